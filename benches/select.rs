@@ -0,0 +1,75 @@
+//! Times filters that select parts of documents of growing size.
+//!
+//! Selecting a subtree borrows it from the input, so `.items` and
+//! `.items[500]` should take about as long on every document, while `clone`
+//! shows what copying the same subtree costs. Variables share the values
+//! bound to them and `reduce` updates its state in place, so the last two
+//! filters should grow linearly with the size of the document. Run with
+//! `cargo bench`.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use jq_clone::Filter;
+use serde_json::{json, Value};
+
+const SIZES: [usize; 3] = [1_000, 10_000, 100_000];
+const FILTERS: [&str; 6] = [
+    ".items",
+    ".items[500]",
+    "first(.items[])",
+    ".meta.name",
+    "[.items[].id] as $ids | .items[] | $ids[0]",
+    "reduce .items[] as $item ([]; . + [$item.id])",
+];
+const WIDTH: usize = 48;
+
+fn document(size: usize) -> Value {
+    let items = (0..size)
+        .map(|i| json!({"id": i, "name": format!("item {i}"), "tags": ["a", "b", "c"]}))
+        .collect::<Vec<_>>();
+    json!({"meta": {"name": "bench", "size": size}, "items": items})
+}
+
+/// Runs `f` until it's taken a while and returns the time of a single run.
+fn time(mut f: impl FnMut()) -> Duration {
+    let mut runs = 0;
+    let start = Instant::now();
+    while runs < 10 || start.elapsed() < Duration::from_millis(200) {
+        f();
+        runs += 1;
+    }
+    start.elapsed() / runs
+}
+
+fn main() {
+    print!("{:<WIDTH$}", "filter");
+    for size in SIZES {
+        print!("{:>14}", format!("{size} items"));
+    }
+    println!();
+
+    let documents = SIZES.map(document);
+    for source in FILTERS {
+        let filter = Filter::compile(source).unwrap();
+        print!("{source:<WIDTH$}");
+        for document in &documents {
+            let elapsed = time(|| {
+                for result in filter.run(black_box(document)) {
+                    black_box(result.unwrap());
+                }
+            });
+            print!("{:>14}", format!("{elapsed:.2?}"));
+        }
+        println!();
+    }
+
+    print!("{:<WIDTH$}", "clone");
+    for document in &documents {
+        let elapsed = time(|| {
+            black_box(black_box(document)["items"].clone());
+        });
+        print!("{:>14}", format!("{elapsed:.2?}"));
+    }
+    println!();
+}
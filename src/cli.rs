@@ -0,0 +1,446 @@
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, ensure};
+use jq_clone::{Indent, InputFormat, Options, Separator};
+use serde_json::Value;
+
+pub const USAGE: &str = "\
+Usage:\tjq-clone [OPTIONS] FILTER [FILES...]
+\tjq-clone [OPTIONS] -f FILTER_FILE [FILES...]
+\tjq-clone [OPTIONS] --args FILTER [ARGUMENTS...]
+\tjq-clone [OPTIONS] --jsonargs FILTER [JSON_VALUES...]
+
+jq-clone is a tool for processing JSON inputs, applying the given filter to
+its JSON text inputs and producing the filter's results as JSON on standard
+output. When no files are given the input is read from standard input.
+
+Options:
+  -n, --null-input          use `null` as the single input value;
+  -R, --raw-input           read each line as string instead of JSON;
+  -s, --slurp               read all inputs into an array and use it as
+                            the single input value;
+  -c, --compact-output      compact instead of pretty-printed output;
+  -r, --raw-output          output strings without escapes and quotes;
+  -j, --join-output         implies -r and output without newline after
+                            each output;
+      --raw-output0         implies -r and output NUL after each output;
+      --seq                 parse input/output as application/json-seq,
+                            skipping invalid input texts;
+  -a, --ascii-output        output strings by only ASCII characters
+                            using escape sequences;
+  -S, --sort-keys           sort keys of each object on output;
+  -C, --color-output        colorize JSON output;
+  -M, --monochrome-output   disable colored output;
+      --tab                 use tabs for indentation;
+      --indent n            use n spaces for indentation (max 7 spaces);
+  -f, --from-file           load the filter from a file;
+  -L, --library-path dir    search modules from the directory, instead
+                            of the default search path;
+  -e, --exit-status         set exit status code based on the output;
+      --arg name value      set $name to the string value;
+      --argjson name value  set $name to the JSON value;
+      --slurpfile name file set $name to an array of JSON values read
+                            from the file;
+      --rawfile name file   set $name to string contents of file;
+      --args                consume remaining arguments as positional
+                            string values;
+      --jsonargs            consume remaining arguments as positional
+                            JSON values;
+  -h, --help                show the help;
+  --                        terminates argument processing;";
+
+pub const HELP_HINT: &str = "Use jq-clone --help for help with command-line options.";
+
+/// Everything the binary needs to know to run, parsed from the arguments.
+#[derive(Debug, Default)]
+pub struct Args {
+    /// The filter itself, or the path of the file containing it with `-f`.
+    pub filter: Option<String>,
+    pub from_file: bool,
+    pub files: Vec<PathBuf>,
+    pub exit_status: bool,
+    /// Variables given with `--arg` and friends, in order.
+    pub named_args: Vec<(String, NamedArg)>,
+    /// Values given after `--args` or `--jsonargs`.
+    pub positional_args: Vec<Value>,
+    /// Directories given with `-L`, in order.
+    pub library_paths: Vec<PathBuf>,
+    /// Whether `-C` or `-M` forced colours on or off.
+    pub color: Option<bool>,
+    pub help: bool,
+    pub options: Options,
+}
+
+/// The value of a named argument, left to be read by the caller when it comes
+/// from a file.
+#[derive(Debug, PartialEq)]
+pub enum NamedArg {
+    Value(Value),
+    /// All the JSON texts in the file, as an array (`--slurpfile`).
+    SlurpFile(PathBuf),
+    /// The contents of the file as a string (`--rawfile`).
+    RawFile(PathBuf),
+}
+
+/// What positional arguments after the filter are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Positional {
+    Files,
+    /// Strings for `$ARGS.positional` (`--args`).
+    Strings,
+    /// JSON texts for `$ARGS.positional` (`--jsonargs`).
+    Json,
+}
+
+pub fn parse_args(args: impl IntoIterator<Item = String>) -> anyhow::Result<Args> {
+    let mut parsed = Args::default();
+    let mut positional = Positional::Files;
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            for arg in args.by_ref() {
+                push_positional(&mut parsed, positional, arg)?;
+            }
+            break;
+        }
+
+        if let Some(long) = arg.strip_prefix("--") {
+            match long {
+                "arg" | "argjson" | "slurpfile" | "rawfile" => {
+                    let (Some(name), Some(value)) = (args.next(), args.next()) else {
+                        bail!("{arg} takes two parameters (e.g. {arg} varname value)");
+                    };
+                    let value = match long {
+                        "arg" => NamedArg::Value(Value::String(value)),
+                        "argjson" => NamedArg::Value(
+                            serde_json::from_str(&value)
+                                .map_err(|_| anyhow!("Invalid JSON text passed to --argjson"))?,
+                        ),
+                        "slurpfile" => NamedArg::SlurpFile(PathBuf::from(value)),
+                        _ => NamedArg::RawFile(PathBuf::from(value)),
+                    };
+                    parsed.named_args.push((name, value));
+                }
+                "args" => positional = Positional::Strings,
+                "jsonargs" => positional = Positional::Json,
+                "library-path" => {
+                    let path = args
+                        .next()
+                        .ok_or_else(|| anyhow!("--library-path takes a parameter"))?;
+                    parsed.library_paths.push(PathBuf::from(path));
+                }
+                "indent" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| anyhow!("--indent takes one parameter"))?;
+                    parsed.options.output.indent = parse_indent(&value)?;
+                }
+                "tab" => parsed.options.output.indent = Indent::Tab,
+                "raw-output0" => {
+                    parsed.options.output.raw = true;
+                    parsed.options.output.separator = Separator::Nul;
+                }
+                "seq" => parsed.options.output.seq = true,
+                _ => {
+                    let short =
+                        long_to_short(long).ok_or_else(|| anyhow!("Unknown option: {arg}"))?;
+                    apply_flag(&mut parsed, short);
+                }
+            }
+        } else if arg.len() > 1 && arg.starts_with('-') {
+            for (i, short) in arg.char_indices().skip(1) {
+                // The directory either follows `-L` directly or is the next
+                // argument.
+                if short == 'L' {
+                    let path = match &arg[i + 1..] {
+                        "" => args.next().ok_or_else(|| {
+                            anyhow!("-L takes a parameter: (e.g. -L /search/path)")
+                        })?,
+                        path => path.to_owned(),
+                    };
+                    parsed.library_paths.push(PathBuf::from(path));
+                    break;
+                }
+                ensure!(is_known(short), "Unknown option: {arg}");
+                apply_flag(&mut parsed, short);
+            }
+        } else {
+            push_positional(&mut parsed, positional, arg)?;
+        }
+    }
+
+    if parsed.from_file && parsed.filter.is_none() && !parsed.help {
+        bail!("-f takes a parameter: (e.g. -f filter.jq)");
+    }
+
+    // Raw input has no texts to separate, whichever flag came first.
+    if parsed.options.output.seq && parsed.options.input_format == InputFormat::Json {
+        parsed.options.input_format = InputFormat::JsonSeq;
+    }
+
+    Ok(parsed)
+}
+
+/// The first positional argument is always the filter, or its file with `-f`.
+fn push_positional(parsed: &mut Args, positional: Positional, arg: String) -> anyhow::Result<()> {
+    if parsed.filter.is_none() {
+        parsed.filter = Some(arg);
+        return Ok(());
+    }
+
+    match positional {
+        Positional::Files => parsed.files.push(PathBuf::from(arg)),
+        Positional::Strings => parsed.positional_args.push(Value::String(arg)),
+        Positional::Json => parsed.positional_args.push(
+            serde_json::from_str(&arg)
+                .map_err(|_| anyhow!("Invalid JSON text passed to --jsonargs"))?,
+        ),
+    }
+    Ok(())
+}
+
+fn parse_indent(value: &str) -> anyhow::Result<Indent> {
+    match value.parse::<usize>() {
+        Ok(0) => Ok(Indent::Compact),
+        Ok(n) if n <= 7 => Ok(Indent::Spaces(n)),
+        Ok(_) => bail!("Cannot indent more than 7 characters"),
+        Err(_) => bail!("--indent takes a number between 0 and 7, got '{value}'"),
+    }
+}
+
+fn long_to_short(long: &str) -> Option<char> {
+    let short = match long {
+        "null-input" => 'n',
+        "raw-input" => 'R',
+        "slurp" => 's',
+        "compact-output" => 'c',
+        "raw-output" => 'r',
+        "join-output" => 'j',
+        "ascii-output" => 'a',
+        "sort-keys" => 'S',
+        "color-output" => 'C',
+        "monochrome-output" => 'M',
+        "from-file" => 'f',
+        "exit-status" => 'e',
+        "help" => 'h',
+        _ => return None,
+    };
+    Some(short)
+}
+
+fn is_known(short: char) -> bool {
+    matches!(
+        short,
+        'n' | 'R' | 's' | 'c' | 'r' | 'j' | 'a' | 'S' | 'C' | 'M' | 'f' | 'e' | 'h'
+    )
+}
+
+fn apply_flag(parsed: &mut Args, short: char) {
+    let options = &mut parsed.options;
+    match short {
+        'n' => options.null_input = true,
+        'R' => options.input_format = InputFormat::Raw,
+        's' => options.slurp = true,
+        'c' => options.output.indent = Indent::Compact,
+        'r' => options.output.raw = true,
+        'j' => {
+            options.output.raw = true;
+            options.output.separator = Separator::Nothing;
+        }
+        'a' => options.output.ascii = true,
+        'S' => options.output.sort_keys = true,
+        'C' => parsed.color = Some(true),
+        'M' => parsed.color = Some(false),
+        'f' => parsed.from_file = true,
+        'e' => parsed.exit_status = true,
+        'h' => parsed.help = true,
+        _ => unreachable!("unknown flags are rejected before being applied"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> anyhow::Result<Args> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn filter_and_files() {
+        let args = parse(&[".foo", "a.json", "b.json"]).unwrap();
+
+        assert_eq!(args.filter.as_deref(), Some(".foo"));
+        assert_eq!(
+            args.files,
+            vec![PathBuf::from("a.json"), PathBuf::from("b.json")]
+        );
+    }
+
+    #[test]
+    fn short_flags_can_be_combined() {
+        let args = parse(&["-nrS", "-c", "."]).unwrap();
+
+        assert!(args.options.null_input);
+        assert!(args.options.output.raw);
+        assert!(args.options.output.sort_keys);
+        assert_eq!(args.options.output.indent, Indent::Compact);
+    }
+
+    #[test]
+    fn long_flags() {
+        let args = parse(&["--slurp", "--raw-input", "--join-output", "."]).unwrap();
+
+        assert!(args.options.slurp);
+        assert_eq!(args.options.input_format, InputFormat::Raw);
+        assert!(args.options.output.raw);
+        assert_eq!(args.options.output.separator, Separator::Nothing);
+
+        let args = parse(&["--raw-output0", "."]).unwrap();
+        assert!(args.options.output.raw);
+        assert_eq!(args.options.output.separator, Separator::Nul);
+
+        let args = parse(&["--seq", "."]).unwrap();
+        assert!(args.options.output.seq);
+        assert_eq!(args.options.input_format, InputFormat::JsonSeq);
+        let args = parse(&["--seq", "-R", "."]).unwrap();
+        assert_eq!(args.options.input_format, InputFormat::Raw);
+    }
+
+    #[test]
+    fn color_flags() {
+        assert_eq!(parse(&["."]).unwrap().color, None);
+        assert_eq!(parse(&["-C", "."]).unwrap().color, Some(true));
+        assert_eq!(parse(&["-CM", "."]).unwrap().color, Some(false));
+        assert_eq!(
+            parse(&["--monochrome-output", "--color-output", "."])
+                .unwrap()
+                .color,
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn indentation() {
+        let args = parse(&["--indent", "4", "."]).unwrap();
+        assert_eq!(args.options.output.indent, Indent::Spaces(4));
+
+        let args = parse(&["--indent", "0", "."]).unwrap();
+        assert_eq!(args.options.output.indent, Indent::Compact);
+
+        let args = parse(&["--tab", "."]).unwrap();
+        assert_eq!(args.options.output.indent, Indent::Tab);
+
+        assert!(parse(&["--indent", "8", "."]).is_err());
+        assert!(parse(&["--indent", "two", "."]).is_err());
+        assert!(parse(&["--indent"]).is_err());
+    }
+
+    #[test]
+    fn double_dash_ends_options() {
+        let args = parse(&["-r", "--", "-n", "-file.json"]).unwrap();
+
+        assert!(args.options.output.raw);
+        assert!(!args.options.null_input);
+        assert_eq!(args.filter.as_deref(), Some("-n"));
+        assert_eq!(args.files, vec![PathBuf::from("-file.json")]);
+    }
+
+    #[test]
+    fn from_file_uses_first_positional() {
+        let args = parse(&["-f", "filter.jq", "input.json"]).unwrap();
+
+        assert!(args.from_file);
+        assert_eq!(args.filter.as_deref(), Some("filter.jq"));
+        assert_eq!(args.files, vec![PathBuf::from("input.json")]);
+
+        assert!(parse(&["-f"]).is_err());
+    }
+
+    #[test]
+    fn library_paths() {
+        let args = parse(&[
+            "-L",
+            "lib",
+            "-Lmodules",
+            "-nL",
+            "a",
+            "--library-path",
+            "b",
+            ".",
+        ])
+        .unwrap();
+
+        assert!(args.options.null_input);
+        assert_eq!(
+            args.library_paths,
+            ["lib", "modules", "a", "b"].map(PathBuf::from)
+        );
+        assert_eq!(args.filter.as_deref(), Some("."));
+
+        assert!(parse(&["-L"]).is_err());
+    }
+
+    #[test]
+    fn unknown_options_are_errors() {
+        assert!(parse(&["-x", "."]).is_err());
+        assert!(parse(&["--unknown", "."]).is_err());
+    }
+
+    #[test]
+    fn named_arguments() {
+        let args = parse(&[
+            "--arg",
+            "name",
+            "value",
+            "--argjson",
+            "n",
+            "{\"a\": 1}",
+            "--slurpfile",
+            "all",
+            "a.json",
+            "--rawfile",
+            "text",
+            "a.txt",
+            ".",
+        ])
+        .unwrap();
+
+        assert_eq!(
+            args.named_args,
+            vec![
+                ("name".to_owned(), NamedArg::Value(Value::from("value"))),
+                ("n".to_owned(), NamedArg::Value(serde_json::json!({"a": 1}))),
+                (
+                    "all".to_owned(),
+                    NamedArg::SlurpFile(PathBuf::from("a.json"))
+                ),
+                ("text".to_owned(), NamedArg::RawFile(PathBuf::from("a.txt"))),
+            ]
+        );
+        assert_eq!(args.filter.as_deref(), Some("."));
+
+        assert!(parse(&["--arg", "name"]).is_err());
+        assert!(parse(&["--argjson", "n", "{", "."]).is_err());
+    }
+
+    #[test]
+    fn positional_arguments() {
+        let args = parse(&[".", "in.json", "--args", "a", "-r", "b"]).unwrap();
+        assert_eq!(args.files, vec![PathBuf::from("in.json")]);
+        assert_eq!(
+            args.positional_args,
+            vec![Value::from("a"), Value::from("b")]
+        );
+        assert!(args.options.output.raw);
+
+        let args = parse(&["--jsonargs", ".", "1", "--", "-2", "[3]"]).unwrap();
+        assert_eq!(args.filter.as_deref(), Some("."));
+        assert_eq!(
+            args.positional_args,
+            vec![Value::from(1), Value::from(-2), serde_json::json!([3])]
+        );
+        assert!(parse(&["--jsonargs", ".", "nope"]).is_err());
+    }
+}
@@ -0,0 +1,225 @@
+use anyhow::{bail, ensure};
+use serde_json::Value;
+
+use crate::format::{self, Indent, OutputOptions};
+
+const RESET: &str = "\x1b[0m";
+
+/// jq's default palette, in the same order as the fields of `JQ_COLORS`:
+/// null, false, true, numbers, strings, arrays, objects and object keys.
+const DEFAULT_COLORS: [&str; 8] = [
+    "0;90", "0;39", "0;39", "0;39", "0;32", "1;39", "1;39", "34;1",
+];
+
+/// Escape sequences used to colour each kind of value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Colors([String; 8]);
+
+impl Default for Colors {
+    fn default() -> Self {
+        Self(DEFAULT_COLORS.map(escape_sequence))
+    }
+}
+
+impl Colors {
+    /// Parses a `JQ_COLORS` specification, a colon-separated list of SGR
+    /// parameters like `1;31`. Only the kinds it lists are changed, the rest
+    /// keep their default colour.
+    pub fn from_spec(spec: &str) -> anyhow::Result<Self> {
+        let mut colors = Self::default();
+
+        let fields = spec.split(':').collect::<Vec<_>>();
+        ensure!(
+            fields.len() <= colors.0.len(),
+            "too many colors in {spec:?}"
+        );
+
+        for (color, field) in colors.0.iter_mut().zip(fields) {
+            if field.len() > 12 || !field.chars().all(|c| c.is_ascii_digit() || c == ';') {
+                bail!("invalid color {field:?}");
+            }
+            *color = escape_sequence(field);
+        }
+
+        Ok(colors)
+    }
+
+    fn for_value(&self, value: &Value) -> &str {
+        let index = match value {
+            Value::Null => 0,
+            Value::Bool(false) => 1,
+            Value::Bool(true) => 2,
+            Value::Number(_) => 3,
+            Value::String(_) => 4,
+            Value::Array(_) => 5,
+            Value::Object(_) => 6,
+        };
+        &self.0[index]
+    }
+
+    fn object_key(&self) -> &str {
+        &self.0[7]
+    }
+}
+
+fn escape_sequence(parameters: &str) -> String {
+    format!("\x1b[{parameters}m")
+}
+
+/// Prints the value with every token wrapped in its colour, writing exactly
+/// the same escape sequences as jq so the output can be compared byte for
+/// byte.
+pub fn to_colored_string(value: &Value, colors: &Colors, options: &OutputOptions) -> String {
+    let indent = match options.indent {
+        Indent::Compact | Indent::Spaces(0) => None,
+        Indent::Spaces(n) => Some(" ".repeat(n.min(7))),
+        Indent::Tab => Some("\t".to_owned()),
+    };
+
+    let mut printer = Printer {
+        out: String::new(),
+        colors,
+        indent,
+        ascii: options.ascii,
+    };
+    printer.write_value(value, 0);
+    printer.out
+}
+
+struct Printer<'c> {
+    out: String,
+    colors: &'c Colors,
+    /// What a single level of indentation looks like, `None` when compact.
+    indent: Option<String>,
+    ascii: bool,
+}
+
+impl Printer<'_> {
+    fn write_value(&mut self, value: &Value, level: usize) {
+        let color = self.colors.for_value(value);
+        self.out.push_str(color);
+
+        match value {
+            Value::Array(array) if array.is_empty() => self.out.push_str("[]"),
+            Value::Object(map) if map.is_empty() => self.out.push_str("{}"),
+            Value::Array(array) => {
+                self.out.push('[');
+                for (i, element) in array.iter().enumerate() {
+                    self.write_separator(i == 0, level + 1);
+                    self.write_value(element, level + 1);
+                    self.out.push_str(color);
+                }
+                self.write_newline(level);
+                self.out.push_str(color);
+                self.out.push(']');
+            }
+            Value::Object(map) => {
+                self.out.push('{');
+                for (i, (key, value)) in map.iter().enumerate() {
+                    self.write_separator(i == 0, level + 1);
+                    self.out.push_str(RESET);
+
+                    self.out.push_str(self.colors.object_key());
+                    self.write_scalar(&Value::from(key.as_str()));
+                    self.out.push_str(RESET);
+
+                    self.out.push_str(color);
+                    self.out.push(':');
+                    if self.indent.is_some() {
+                        self.out.push(' ');
+                    }
+                    self.out.push_str(RESET);
+
+                    self.write_value(value, level + 1);
+                    self.out.push_str(color);
+                }
+                self.write_newline(level);
+                self.out.push_str(color);
+                self.out.push('}');
+            }
+            scalar => self.write_scalar(scalar),
+        }
+
+        self.out.push_str(RESET);
+    }
+
+    fn write_scalar(&mut self, value: &Value) {
+        let options = OutputOptions {
+            ascii: self.ascii,
+            indent: Indent::Compact,
+            ..Default::default()
+        };
+        self.out.push_str(&format::format_value(value, &options));
+    }
+
+    /// Writes what comes before an element of an array or object.
+    fn write_separator(&mut self, first: bool, level: usize) {
+        if !first {
+            self.out.push(',');
+        }
+        self.write_newline(level);
+    }
+
+    fn write_newline(&mut self, level: usize) {
+        if let Some(indent) = &self.indent {
+            self.out.push('\n');
+            self.out.push_str(&indent.repeat(level));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn colored_compact_output() {
+        let options = OutputOptions {
+            indent: Indent::Compact,
+            ..Default::default()
+        };
+        let colored = to_colored_string(&json!({"a": [1, "x"]}), &Colors::default(), &options);
+
+        assert_eq!(
+            colored,
+            "\x1b[1;39m{\x1b[0m\x1b[34;1m\"a\"\x1b[0m\x1b[1;39m:\x1b[0m\
+             \x1b[1;39m[\x1b[0;39m1\x1b[0m\x1b[1;39m,\x1b[0;32m\"x\"\x1b[0m\x1b[1;39m\
+             \x1b[1;39m]\x1b[0m\x1b[1;39m\x1b[1;39m}\x1b[0m"
+        );
+    }
+
+    #[test]
+    fn colored_pretty_output() {
+        let colored = to_colored_string(
+            &json!({"a": [null, {}], "b": false}),
+            &Colors::default(),
+            &OutputOptions::default(),
+        );
+
+        assert_eq!(
+            colored,
+            "\x1b[1;39m{\n  \x1b[0m\x1b[34;1m\"a\"\x1b[0m\x1b[1;39m: \x1b[0m\
+             \x1b[1;39m[\n    \x1b[0;90mnull\x1b[0m\x1b[1;39m,\n    \
+             \x1b[1;39m{}\x1b[0m\x1b[1;39m\n  \x1b[1;39m]\x1b[0m\x1b[1;39m,\n  \
+             \x1b[0m\x1b[34;1m\"b\"\x1b[0m\x1b[1;39m: \x1b[0m\x1b[0;39mfalse\x1b[0m\
+             \x1b[1;39m\n\x1b[1;39m}\x1b[0m"
+        );
+    }
+
+    #[test]
+    fn parse_jq_colors() {
+        let colors = Colors::from_spec("1;31::0;33").unwrap();
+        assert_eq!(colors.for_value(&Value::Null), "\x1b[1;31m");
+        assert_eq!(colors.for_value(&json!(false)), "\x1b[m");
+        assert_eq!(colors.for_value(&json!(true)), "\x1b[0;33m");
+        assert_eq!(colors.for_value(&json!(1)), "\x1b[0;39m");
+
+        let colors = Colors::from_spec("0;90:0;39:0;39:0;39:0;32:1;39:1;39:4;35").unwrap();
+        assert_eq!(colors.object_key(), "\x1b[4;35m");
+
+        assert!(Colors::from_spec("red").is_err());
+        assert!(Colors::from_spec("1:2:3:4:5:6:7:8:9").is_err());
+    }
+}
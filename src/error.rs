@@ -0,0 +1,525 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+use winnow::error::{ContextError, ParseError, StrContext, StrContextValue};
+
+use crate::parser::{KEYWORDS, NOT_IN_KEYS};
+use crate::token::Halt;
+
+/// Why running a filter failed, told apart by the stage it failed at.
+#[derive(Debug)]
+pub enum Error {
+    /// The filter, or a module it imports, isn't valid.
+    Syntax(Box<SyntaxError>),
+    /// A module the filter imports couldn't be found or loaded.
+    Module(String),
+    /// The filter, or a module it imports, refers to a function, a variable
+    /// or a format that isn't defined.
+    Undefined(Undefined),
+    /// An input couldn't be read or isn't valid JSON.
+    Input(InputError),
+    /// The filter failed while running on an input.
+    Runtime(RuntimeError),
+    /// The filter stopped everything with `halt` or `halt_error`.
+    Halt(Halt),
+}
+
+impl Error {
+    /// Classifies an error met while reading the filter and its modules.
+    pub(crate) fn compile(error: anyhow::Error) -> Self {
+        let error = match error.downcast::<SyntaxError>() {
+            Ok(error) => return Error::Syntax(Box::new(error)),
+            Err(error) => error,
+        };
+        match error.downcast::<Undefined>() {
+            Ok(error) => Error::Undefined(error),
+            Err(error) => Error::Module(format!("{error:#}")),
+        }
+    }
+
+    /// Classifies an error met while running the filter, which can also come
+    /// from an input it pulled with `input` or from `halt`.
+    pub(crate) fn runtime(error: anyhow::Error) -> Self {
+        let error = match error.downcast::<Halt>() {
+            Ok(halt) => return Error::Halt(halt),
+            Err(error) => error,
+        };
+        let error = match error.downcast::<InputError>() {
+            Ok(error) => return Error::Input(error),
+            Err(error) => error,
+        };
+        match error.downcast::<RuntimeError>() {
+            Ok(error) => Error::Runtime(error),
+            Err(error) => Error::Runtime(RuntimeError {
+                message: format!("{error:#}"),
+                value: None,
+                path: None,
+            }),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Syntax(error) => error.fmt(f),
+            Error::Module(message) => f.write_str(message),
+            Error::Undefined(error) => error.fmt(f),
+            Error::Input(error) => error.fmt(f),
+            Error::Runtime(error) => error.fmt(f),
+            Error::Halt(halt) => halt.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// An input that couldn't be read, or that isn't valid JSON. Invalid JSON is
+/// displayed with the part of the line it's on:
+///
+/// ```text
+/// Failed to parse JSON in bad.json (document 2): expected `:` at line 2, column 6
+///  --> bad.json:2:6
+///   |
+/// 2 | {"a" 2}
+///   |      ^
+/// ```
+#[derive(Debug)]
+pub struct InputError {
+    /// The source the input was read from, `None` for standard input.
+    pub filename: Option<String>,
+    /// Position of the invalid document in its source, starting from 1.
+    pub document: usize,
+    /// Line of the error in its source, starting from 1.
+    pub line: usize,
+    /// Column of the error in bytes, starting from 1.
+    pub column: usize,
+    /// The text around the error on its line, cut short with `...` when the
+    /// line is long.
+    pub excerpt: String,
+    /// Column of the error in `excerpt`, in characters starting from 1.
+    pub excerpt_column: usize,
+    /// What went wrong, an I/O error if the input couldn't be read at all.
+    pub error: serde_json::Error,
+}
+
+impl InputError {
+    pub(crate) fn read(filename: Option<String>, error: std::io::Error) -> Self {
+        Self {
+            filename,
+            document: 0,
+            line: 0,
+            column: 0,
+            excerpt: String::new(),
+            excerpt_column: 0,
+            error: serde_json::Error::io(error),
+        }
+    }
+
+    /// Whether the input couldn't be read at all, rather than being invalid.
+    pub fn is_io(&self) -> bool {
+        self.error.is_io()
+    }
+}
+
+impl fmt::Display for InputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = self.filename.as_deref();
+        if self.is_io() {
+            return match name {
+                Some(name) => write!(f, "Failed to read {name}: {}", self.error),
+                None => write!(f, "Failed to read input: {}", self.error),
+            };
+        }
+
+        match name {
+            Some(name) => write!(f, "Failed to parse JSON in {name}")?,
+            None => f.write_str("Failed to parse JSON")?,
+        }
+        // The position is shown in our own terms, as it's relative to the
+        // document for `--seq` inputs.
+        let message = self.error.to_string();
+        let position = format!(
+            " at line {} column {}",
+            self.error.line(),
+            self.error.column()
+        );
+        let message = message.strip_suffix(&position).unwrap_or(&message);
+        write!(
+            f,
+            " (document {}): {message} at line {}, column {}",
+            self.document, self.line, self.column
+        )?;
+
+        let location = Location {
+            file: name.unwrap_or("<stdin>"),
+            line: self.line,
+            column: self.column,
+        };
+        write_excerpt(f, &location, &self.excerpt, self.excerpt_column, 1)
+    }
+}
+
+impl std::error::Error for InputError {}
+
+/// An error raised by the filter itself, like indexing a number.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub message: String,
+    /// The value the filter failed on, when there's one to blame.
+    pub value: Option<Value>,
+    /// Keys and indices leading from the input to `value`, when it was
+    /// reached by following a path like `.a[0]` rather than computed.
+    pub path: Option<Vec<Value>>,
+}
+
+impl RuntimeError {
+    /// An error about `value`, found at `path` in the value being filtered.
+    pub(crate) fn new(message: impl Into<String>, value: &Value, path: &[Value]) -> Self {
+        Self {
+            message: message.into(),
+            value: Some(value.clone()),
+            path: Some(path.to_vec()),
+        }
+    }
+
+    /// Moves the error to where `prefix` leads, or forgets where it is when
+    /// there's no path to the value it was found in.
+    pub(crate) fn prefix_path(&mut self, prefix: Option<&[Value]>) {
+        self.path = match (prefix, self.path.take()) {
+            (Some(prefix), Some(path)) => Some(prefix.iter().cloned().chain(path).collect()),
+            _ => None,
+        };
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// A filter, or a module it imports, that isn't valid. It's displayed like
+/// compiler diagnostics, with the offending part of the line underlined:
+///
+/// ```text
+/// unexpected `]`, expected a filter
+///  --> <filter>:1:6
+///   |
+/// 1 | .a | ]
+///   |      ^
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntaxError {
+    /// The module the error is in, `None` for the filter itself.
+    pub file: Option<PathBuf>,
+    /// Line of the error, starting from 1.
+    pub line: usize,
+    /// Column of the error in characters, starting from 1.
+    pub column: usize,
+    /// The whole line the error is on.
+    pub source_line: String,
+    /// How many characters are underlined, starting from `column`.
+    pub length: usize,
+    /// What was found instead of valid syntax, like "unexpected `]`".
+    pub found: String,
+    /// Every token or construct that would have been valid there.
+    pub expected: Vec<String>,
+    /// A suggestion on how to fix the filter.
+    pub help: Option<String>,
+}
+
+impl SyntaxError {
+    pub(crate) fn new(
+        text: &str,
+        error: &ParseError<&str, ContextError>,
+        file: Option<&Path>,
+    ) -> Self {
+        let offset = error.offset();
+        let before = &text[..offset];
+        let rest = &text[offset..];
+
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        let line_end = rest.find('\n').map_or(text.len(), |i| offset + i);
+        let source_line = text[line_start..line_end].trim_end_matches('\r');
+
+        let token = next_token(rest);
+        let found = match token {
+            "" => "unexpected end of filter".to_owned(),
+            token => format!("unexpected `{token}`"),
+        };
+
+        let mut expected = Vec::new();
+        for context in error.inner().context() {
+            let StrContext::Expected(value) = context else {
+                continue;
+            };
+            let value = match value {
+                StrContextValue::CharLiteral(c) => format!("`{c}`"),
+                StrContextValue::StringLiteral(s) => format!("`{s}`"),
+                value => value.to_string(),
+            };
+            if !expected.contains(&value) {
+                expected.push(value);
+            }
+        }
+
+        Self {
+            file: file.map(Path::to_owned),
+            line: before.matches('\n').count() + 1,
+            column: text[line_start..offset].chars().count() + 1,
+            source_line: source_line.to_owned(),
+            length: token.chars().count().max(1),
+            found,
+            expected,
+            help: help(before, rest, token),
+        }
+    }
+}
+
+/// The part of the text a syntax error is about: a whole word, or a single
+/// character otherwise.
+fn next_token(rest: &str) -> &str {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    match rest.chars().next() {
+        Some(c) if is_word(c) => {
+            let end = rest.find(|c| !is_word(c)).unwrap_or(rest.len());
+            &rest[..end]
+        }
+        Some(c) => &rest[..c.len_utf8()],
+        None => "",
+    }
+}
+
+/// Suggests a fix for common mistakes.
+fn help(before: &str, rest: &str, token: &str) -> Option<String> {
+    if token == "'" {
+        let quoted = rest[1..].split(['\'', '\n']).next().unwrap_or_default();
+        let suggestion = if before.trim_end().ends_with(['[', '.']) {
+            format!(".[\"{quoted}\"]")
+        } else {
+            format!("\"{quoted}\"")
+        };
+        return Some(format!(
+            "strings are written with double quotes, did you mean `{suggestion}`?"
+        ));
+    }
+
+    // A key followed by characters it can't contain, like `.foo:bar`.
+    if let Some((_, key)) = before.rsplit_once('.') {
+        let is_key = key.chars().all(|c| c.is_alphanumeric() || c == '_');
+        if is_key && token.chars().all(|c| NOT_IN_KEYS.contains(c)) {
+            let end = rest
+                .find(|c: char| c.is_whitespace() || "|,;)]".contains(c))
+                .unwrap_or(rest.len());
+            return Some(format!(
+                "keys with special characters must be quoted, did you mean `.[\"{key}{}\"]`?",
+                &rest[..end]
+            ));
+        }
+    }
+
+    if KEYWORDS.contains(&token) {
+        return Some(format!("`{token}` is a reserved keyword"));
+    }
+
+    None
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.found)?;
+        if let Some((last, others)) = self.expected.split_last() {
+            f.write_str(", expected ")?;
+            match others.len() {
+                0 => {}
+                1 => write!(f, "{} or ", others[0])?,
+                _ => write!(f, "{}, or ", others.join(", "))?,
+            }
+            f.write_str(last)?;
+        }
+
+        let file = self
+            .file
+            .as_deref()
+            .map_or("<filter>".into(), Path::to_string_lossy);
+        let location = Location {
+            file: &file,
+            line: self.line,
+            column: self.column,
+        };
+        write_excerpt(f, &location, &self.source_line, self.column, self.length)?;
+        if let Some(help) = &self.help {
+            let gutter = " ".repeat(self.line.to_string().len());
+            write!(f, "\n{gutter} |\n{gutter} = help: {help}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Where an error is, as shown above the text it's in.
+struct Location<'a> {
+    file: &'a str,
+    line: usize,
+    column: usize,
+}
+
+/// Writes the location of an error followed by the text it's in, with
+/// `length` characters underlined from `column`, like compiler diagnostics.
+fn write_excerpt(
+    f: &mut fmt::Formatter<'_>,
+    location: &Location,
+    text: &str,
+    column: usize,
+    length: usize,
+) -> fmt::Result {
+    let Location { file, line, .. } = location;
+    let number = line.to_string();
+    let gutter = " ".repeat(number.len());
+    // Tabs are kept so that the caret lines up with the text above it.
+    let indent: String = text
+        .chars()
+        .take(column.saturating_sub(1))
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+
+    write!(f, "\n{gutter}--> {file}:{line}:{}", location.column)?;
+    write!(f, "\n{gutter} |")?;
+    write!(f, "\n{number} | {text}")?;
+    write!(f, "\n{gutter} | {indent}{}", "^".repeat(length))
+}
+
+impl std::error::Error for SyntaxError {}
+
+/// Something a filter refers to that isn't defined where it's used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Undefined {
+    /// A call to a function that no definition, import or builtin provides
+    /// with that many arguments.
+    Function { name: String, arity: usize },
+    /// A variable that nothing binds, like `$x` outside of `. as $x | ...`.
+    Variable(String),
+    /// A format that isn't one of the builtin ones, like `@nope`.
+    Format(String),
+}
+
+impl fmt::Display for Undefined {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Undefined::Function { name, arity } => write!(f, "{name}/{arity} is not defined"),
+            Undefined::Variable(name) => write!(f, "${name} is not defined"),
+            Undefined::Format(name) => write!(f, "{name} is not a valid format"),
+        }
+    }
+}
+
+impl std::error::Error for Undefined {}
+
+#[cfg(test)]
+mod tests {
+    use winnow::Parser;
+
+    use super::*;
+    use crate::parser::parse_program;
+
+    fn syntax_error(filter: &str) -> SyntaxError {
+        let error = parse_program.parse(filter).unwrap_err();
+        SyntaxError::new(filter, &error, None)
+    }
+
+    #[test]
+    fn unexpected_token_with_expected_ones() {
+        let error = syntax_error(".a | ]");
+        assert_eq!((error.line, error.column, error.length), (1, 6, 1));
+        assert_eq!(
+            error.to_string(),
+            "unexpected `]`, expected a filter\n \
+             --> <filter>:1:6\n  \
+             |\n\
+             1 | .a | ]\n  \
+             |      ^"
+        );
+
+        let error = syntax_error("map(.a; .b");
+        assert_eq!(error.found, "unexpected end of filter");
+        assert_eq!(error.expected, vec!["`)`", "`;`"]);
+        assert!(error
+            .to_string()
+            .starts_with("unexpected end of filter, expected `)` or `;`"));
+    }
+
+    #[test]
+    fn missing_variable_name() {
+        let error = syntax_error(".a as x | .");
+        assert_eq!(error.found, "unexpected `x`");
+        assert_eq!(error.expected, vec!["`$`"]);
+
+        let error = syntax_error("reduce .[] as $x (0, .)");
+        assert_eq!(error.found, "unexpected `)`");
+        assert_eq!(error.expected, vec!["`;`"]);
+    }
+
+    #[test]
+    fn position_on_later_lines() {
+        let error = syntax_error("# comment\n.a |\n\t.b ]");
+        assert_eq!((error.line, error.column), (3, 5));
+        assert_eq!(error.source_line, "\t.b ]");
+        assert!(error.to_string().ends_with("3 | \t.b ]\n  | \t   ^"));
+    }
+
+    #[test]
+    fn whole_words_are_underlined() {
+        let error = syntax_error(".a | then");
+        assert_eq!((error.column, error.length), (6, 4));
+        assert!(error.to_string().ends_with(
+            "1 | .a | then\n  |      ^^^^\n  |\n  = help: `then` is a reserved keyword"
+        ));
+
+        let error = syntax_error(".a | if . then 1 end");
+        assert_eq!(error.found, "unexpected `if`");
+        assert_eq!(error.length, 2);
+        assert_eq!(error.help.as_deref(), Some("`if` is a reserved keyword"));
+    }
+
+    #[test]
+    fn single_quotes_suggest_double_quotes() {
+        let error = syntax_error(".['key']");
+        assert_eq!(
+            error.help.as_deref(),
+            Some("strings are written with double quotes, did you mean `.[\"key\"]`?")
+        );
+        assert!(error.to_string().ends_with(
+            "  |\n  = help: strings are written with double quotes, did you mean `.[\"key\"]`?"
+        ));
+
+        let error = syntax_error(".'key'");
+        assert_eq!(
+            error.help.as_deref(),
+            Some("strings are written with double quotes, did you mean `.[\"key\"]`?")
+        );
+
+        let error = syntax_error(".a | .foo:bar | .c");
+        assert_eq!(error.column, 10);
+        assert_eq!(
+            error.help.as_deref(),
+            Some("keys with special characters must be quoted, did you mean `.[\"foo:bar\"]`?")
+        );
+
+        let error = syntax_error("'text'");
+        assert_eq!(
+            error.help.as_deref(),
+            Some("strings are written with double quotes, did you mean `\"text\"`?")
+        );
+    }
+
+    #[test]
+    fn module_errors_name_the_file() {
+        let error = parse_program.parse("import \"lib\" as ;").unwrap_err();
+        let error = SyntaxError::new("import \"lib\" as ;", &error, Some(Path::new("lib.jq")));
+        assert_eq!(error.expected, vec!["a name"]);
+        assert!(error.to_string().contains(" --> lib.jq:1:17\n"));
+    }
+}
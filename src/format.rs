@@ -0,0 +1,414 @@
+use std::io;
+
+use anyhow::bail;
+use serde::Serialize;
+use serde_json::ser::{CompactFormatter, Formatter, PrettyFormatter};
+use serde_json::Value;
+
+use crate::color::{self, Colors};
+
+const MAX_INDENT: &[u8] = b"       ";
+
+/// How nested values are laid out when printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Indent {
+    /// Everything on a single line (`-c`).
+    Compact,
+    /// Each level indented by this many spaces, at most 7 (`--indent n`).
+    Spaces(usize),
+    /// Each level indented by a tab (`--tab`).
+    Tab,
+}
+
+impl Default for Indent {
+    fn default() -> Self {
+        Indent::Spaces(2)
+    }
+}
+
+/// What is written after each result.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Separator {
+    #[default]
+    Newline,
+    /// Results are written back to back (`-j`).
+    Nothing,
+    /// A NUL byte, for `xargs -0` and the like (`--raw-output0`).
+    Nul,
+}
+
+impl Separator {
+    fn as_str(self) -> &'static str {
+        match self {
+            Separator::Newline => "\n",
+            Separator::Nothing => "",
+            Separator::Nul => "\0",
+        }
+    }
+}
+
+/// How results are written out, mirroring jq's output flags.
+#[derive(Debug, Clone, Default)]
+pub struct OutputOptions {
+    /// Write strings without quotes (`-r`).
+    pub raw: bool,
+    pub separator: Separator,
+    /// Escape every non-ASCII character (`-a`). Like in jq, this also keeps
+    /// the quotes around raw strings so the escapes can be read back.
+    pub ascii: bool,
+    /// Recursively sort the keys of objects (`-S`).
+    pub sort_keys: bool,
+    pub indent: Indent,
+    /// Colour every token with these escape sequences (`-C`).
+    pub colors: Option<Colors>,
+    /// Write an ASCII RS before each result, for `application/json-seq`
+    /// (`--seq`).
+    pub seq: bool,
+}
+
+/// Formats a single result according to the output options, without the
+/// newline that separates it from the next one.
+pub fn format_value(value: &Value, options: &OutputOptions) -> String {
+    if let (Value::String(string), true, false) = (value, options.raw, options.ascii) {
+        return string.clone();
+    }
+
+    let sorted;
+    let value = if options.sort_keys {
+        sorted = sort_keys(value);
+        &sorted
+    } else {
+        value
+    };
+
+    if let Some(colors) = &options.colors {
+        return color::to_colored_string(value, colors, options);
+    }
+
+    match options.indent {
+        Indent::Compact | Indent::Spaces(0) => {
+            to_string_with(value, CompactFormatter, options.ascii)
+        }
+        Indent::Spaces(n) => {
+            let indent = &MAX_INDENT[..n.min(MAX_INDENT.len())];
+            to_string_with(value, PrettyFormatter::with_indent(indent), options.ascii)
+        }
+        Indent::Tab => to_string_with(value, PrettyFormatter::with_indent(b"\t"), options.ascii),
+    }
+}
+
+/// Writes a single result followed by its separator.
+pub fn write_value(
+    writer: &mut impl io::Write,
+    value: &Value,
+    options: &OutputOptions,
+) -> anyhow::Result<()> {
+    let formatted = format_value(value, options);
+
+    // The NUL would be indistinguishable from the separator.
+    if options.separator == Separator::Nul && value.is_string() && formatted.contains('\0') {
+        bail!("Cannot dump a string containing NUL with --raw-output0 option");
+    }
+
+    if options.seq {
+        writer.write_all(b"\x1e")?;
+    }
+    writer.write_all(formatted.as_bytes())?;
+    writer.write_all(options.separator.as_str().as_bytes())?;
+    Ok(())
+}
+
+/// Formats every result, each followed by its separator except the last.
+pub fn format_values<'v>(
+    values: impl IntoIterator<Item = &'v Value>,
+    options: &OutputOptions,
+) -> String {
+    values
+        .into_iter()
+        .map(|value| match options.seq {
+            true => format!("\x1e{}", format_value(value, options)),
+            false => format_value(value, options),
+        })
+        .collect::<Vec<_>>()
+        .join(options.separator.as_str())
+}
+
+fn sort_keys(value: &Value) -> Value {
+    match value {
+        Value::Array(array) => array.iter().map(sort_keys).collect(),
+        Value::Object(map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by_key(|(key, _)| *key);
+            entries
+                .into_iter()
+                .map(|(key, value)| (key.clone(), sort_keys(value)))
+                .collect()
+        }
+        value => value.clone(),
+    }
+}
+
+/// Serializes the value on a single line, which is what `tojson` outputs.
+pub fn to_string_compact(value: &Value) -> String {
+    to_string_with(value, CompactFormatter, false)
+}
+
+fn to_string_with(value: &Value, formatter: impl Formatter, ascii: bool) -> String {
+    let mut buf = Vec::new();
+    let mut serializer =
+        serde_json::Serializer::with_formatter(&mut buf, JqFormatter::new(formatter, ascii));
+    value
+        .serialize(&mut serializer)
+        .expect("serializing a value into memory can't fail");
+
+    // Serializing a `Value` only ever writes valid UTF-8
+    String::from_utf8(buf).expect("serialized JSON is valid UTF-8")
+}
+
+/// Wraps another formatter to print numbers and strings the way jq does.
+///
+/// Older versions of `serde_json` write exponents without a sign (`1e300`)
+/// while jq always includes it (`1e+300`), and jq also escapes the DEL
+/// control character.
+/// When `ascii` is set every non-ASCII character is escaped as well.
+/// Everything else is left to the inner formatter.
+pub struct JqFormatter<F> {
+    inner: F,
+    ascii: bool,
+}
+
+impl<F: Formatter> JqFormatter<F> {
+    fn new(inner: F, ascii: bool) -> Self {
+        Self { inner, ascii }
+    }
+}
+
+impl<F: Formatter> Formatter for JqFormatter<F> {
+    fn write_f64<W: ?Sized + io::Write>(&mut self, writer: &mut W, value: f64) -> io::Result<()> {
+        let mut number = Vec::new();
+        self.inner.write_f64(&mut number, value)?;
+
+        match number.iter().position(|&b| b == b'e') {
+            Some(e) if !matches!(number.get(e + 1), Some(b'+' | b'-')) => {
+                writer.write_all(&number[..=e])?;
+                writer.write_all(b"+")?;
+                writer.write_all(&number[e + 1..])
+            }
+            _ => writer.write_all(&number),
+        }
+    }
+
+    fn write_string_fragment<W: ?Sized + io::Write>(
+        &mut self,
+        writer: &mut W,
+        fragment: &str,
+    ) -> io::Result<()> {
+        let ascii = self.ascii;
+        let escaped = |c: char| c == '\x7f' || (ascii && !c.is_ascii());
+
+        let mut rest = fragment;
+        while let Some(position) = rest.find(escaped) {
+            self.inner
+                .write_string_fragment(writer, &rest[..position])?;
+
+            let c = rest[position..].chars().next().unwrap_or_default();
+            let mut units = [0; 2];
+            for unit in c.encode_utf16(&mut units) {
+                write!(writer, "\\u{unit:04x}")?;
+            }
+            rest = &rest[position + c.len_utf8()..];
+        }
+
+        self.inner.write_string_fragment(writer, rest)
+    }
+
+    fn begin_array<W: ?Sized + io::Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.inner.begin_array(writer)
+    }
+
+    fn end_array<W: ?Sized + io::Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.inner.end_array(writer)
+    }
+
+    fn begin_array_value<W: ?Sized + io::Write>(
+        &mut self,
+        writer: &mut W,
+        first: bool,
+    ) -> io::Result<()> {
+        self.inner.begin_array_value(writer, first)
+    }
+
+    fn end_array_value<W: ?Sized + io::Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.inner.end_array_value(writer)
+    }
+
+    fn begin_object<W: ?Sized + io::Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.inner.begin_object(writer)
+    }
+
+    fn end_object<W: ?Sized + io::Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.inner.end_object(writer)
+    }
+
+    fn begin_object_key<W: ?Sized + io::Write>(
+        &mut self,
+        writer: &mut W,
+        first: bool,
+    ) -> io::Result<()> {
+        self.inner.begin_object_key(writer, first)
+    }
+
+    fn begin_object_value<W: ?Sized + io::Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.inner.begin_object_value(writer)
+    }
+
+    fn end_object_value<W: ?Sized + io::Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.inner.end_object_value(writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn written(values: &[Value], options: &OutputOptions) -> anyhow::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        for value in values {
+            write_value(&mut buf, value, options)?;
+        }
+        Ok(buf)
+    }
+
+    #[test]
+    fn raw_and_ascii_strings() {
+        let value = json!("caf\u{e9} \u{1f600}");
+
+        let raw = OutputOptions {
+            raw: true,
+            ..Default::default()
+        };
+        assert_eq!(format_value(&value, &raw), "caf\u{e9} \u{1f600}");
+
+        let ascii = OutputOptions {
+            ascii: true,
+            ..Default::default()
+        };
+        assert_eq!(format_value(&value, &ascii), r#""caf\u00e9 \ud83d\ude00""#);
+
+        let raw_ascii = OutputOptions {
+            raw: true,
+            ascii: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_value(&value, &raw_ascii),
+            r#""caf\u00e9 \ud83d\ude00""#
+        );
+    }
+
+    #[test]
+    fn separators() {
+        let values = [json!("a"), json!([1])];
+
+        let mut options = OutputOptions {
+            raw: true,
+            indent: Indent::Compact,
+            ..Default::default()
+        };
+        assert_eq!(written(&values, &options).unwrap(), b"a\n[1]\n");
+
+        options.separator = Separator::Nothing;
+        assert_eq!(written(&values, &options).unwrap(), b"a[1]");
+
+        options.separator = Separator::Nul;
+        assert_eq!(written(&values, &options).unwrap(), b"a\0[1]\0");
+        assert!(written(&[json!("a\u{0}b")], &options).is_err());
+        assert_eq!(format_values(&values, &options), "a\0[1]");
+
+        options.separator = Separator::Newline;
+        options.seq = true;
+        assert_eq!(written(&values, &options).unwrap(), b"\x1ea\n\x1e[1]\n");
+        assert_eq!(format_values(&values, &options), "\x1ea\n\x1e[1]");
+    }
+
+    #[test]
+    fn indentation() {
+        let value = json!({"a": [1, {}], "b": []});
+        let with_indent = |indent| OutputOptions {
+            indent,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            format_value(&value, &with_indent(Indent::Compact)),
+            r#"{"a":[1,{}],"b":[]}"#
+        );
+        assert_eq!(
+            format_value(&value, &with_indent(Indent::Spaces(0))),
+            r#"{"a":[1,{}],"b":[]}"#
+        );
+        assert_eq!(
+            format_value(&value, &with_indent(Indent::default())),
+            "{\n  \"a\": [\n    1,\n    {}\n  ],\n  \"b\": []\n}"
+        );
+        assert_eq!(
+            format_value(&value, &with_indent(Indent::Spaces(7))),
+            "{\n       \"a\": [\n              1,\n              {}\n       ],\n       \"b\": []\n}"
+        );
+        assert_eq!(
+            format_value(&value, &with_indent(Indent::Tab)),
+            "{\n\t\"a\": [\n\t\t1,\n\t\t{}\n\t],\n\t\"b\": []\n}"
+        );
+    }
+
+    #[test]
+    fn keys_keep_input_order_unless_sorted() {
+        let value: Value =
+            serde_json::from_str(r#"{"b": 1, "a": [{"d": 2, "c": 3}], "é": 4, "Z": 5}"#).unwrap();
+        let mut options = OutputOptions {
+            indent: Indent::Compact,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            format_value(&value, &options),
+            r#"{"b":1,"a":[{"d":2,"c":3}],"é":4,"Z":5}"#
+        );
+
+        options.sort_keys = true;
+        assert_eq!(
+            format_value(&value, &options),
+            r#"{"Z":5,"a":[{"c":3,"d":2}],"b":1,"é":4}"#
+        );
+    }
+
+    #[test]
+    fn exponents_get_a_single_sign() {
+        /// Writes exponents with their sign, like newer versions of `serde_json`.
+        struct Signed;
+
+        impl Formatter for Signed {
+            fn write_f64<W: ?Sized + io::Write>(
+                &mut self,
+                writer: &mut W,
+                value: f64,
+            ) -> io::Result<()> {
+                let number = format!("{value:e}").replacen('e', "e+", 1);
+                writer.write_all(number.replace("+-", "-").as_bytes())
+            }
+        }
+
+        fn written_f64(inner: impl Formatter, value: f64) -> String {
+            let mut buf = Vec::new();
+            let mut formatter = JqFormatter::new(inner, false);
+            formatter.write_f64(&mut buf, value).unwrap();
+            String::from_utf8(buf).unwrap()
+        }
+
+        assert_eq!(written_f64(CompactFormatter, 1e300), "1e+300");
+        assert_eq!(written_f64(Signed, 1e300), "1e+300");
+        assert_eq!(written_f64(Signed, 1.5e-7), "1.5e-7");
+    }
+}
@@ -0,0 +1,497 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, BufRead, Read};
+use std::iter;
+use std::rc::Rc;
+
+use serde_json::Value;
+
+use crate::error::InputError;
+
+type Values = Box<dyn Iterator<Item = Result<Value, InputError>>>;
+
+/// How many bytes of the line around invalid JSON are shown on each side.
+const EXCERPT_CONTEXT: usize = 40;
+
+/// Separates the texts of `--seq` inputs, the ASCII record separator.
+const RECORD_SEPARATOR: u8 = 0x1e;
+
+/// A stream of input text, such as standard input or a file given on the
+/// command line.
+pub struct InputSource {
+    name: Option<String>,
+    reader: Box<dyn BufRead>,
+}
+
+impl InputSource {
+    /// Creates a source whose inputs will report `name` from `input_filename`.
+    pub fn new(name: Option<String>, reader: impl BufRead + 'static) -> Self {
+        Self {
+            name,
+            reader: Box::new(reader),
+        }
+    }
+}
+
+/// How the text of each source is turned into input values.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InputFormat {
+    /// A stream of whitespace-separated JSON texts.
+    #[default]
+    Json,
+    /// Every line is a string, or with `slurp` the whole text is one (`-R`).
+    Raw,
+    /// JSON texts each preceded by an ASCII RS, where a text that isn't valid
+    /// is skipped up to the next RS instead of ending the source (`--seq`).
+    JsonSeq,
+}
+
+impl InputFormat {
+    fn read(self, source: InputSource) -> Values {
+        match self {
+            InputFormat::Json => read_json(source),
+            InputFormat::Raw => read_lines(source),
+            InputFormat::JsonSeq => read_json_seq(source),
+        }
+    }
+
+    /// Reads every remaining source into a single value: an array of their
+    /// JSON texts, or the whole raw text as one string.
+    fn slurp(self, inputs: &mut Inputs) -> Result<Value, InputError> {
+        if self != InputFormat::Raw {
+            return inputs.collect::<Result<_, _>>().map(Value::Array);
+        }
+
+        let mut text = Vec::new();
+        while let Some(mut source) = inputs.sources.pop_front() {
+            if let Err(e) = source.reader.read_to_end(&mut text) {
+                return Err(InputError::read(source.name, e));
+            }
+            inputs.filename = source.name;
+        }
+        Ok(Value::String(String::from_utf8_lossy(&text).into_owned()))
+    }
+}
+
+/// The inputs of every source, read one after the other as a single stream.
+///
+/// The filter pulls from the same stream through `input` and `inputs`, so
+/// whatever it consumes is skipped by the main loop.
+#[derive(Default)]
+pub struct Inputs {
+    sources: VecDeque<InputSource>,
+    current: Option<Values>,
+    filename: Option<String>,
+    pub(crate) format: InputFormat,
+    /// Whether every input should be read into one value the next time one
+    /// is requested.
+    pub(crate) slurp: bool,
+}
+
+impl Inputs {
+    pub fn new(sources: impl IntoIterator<Item = InputSource>) -> Self {
+        Self {
+            sources: sources.into_iter().collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Name of the source the last input was read from.
+    pub fn filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+}
+
+impl fmt::Debug for Inputs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Inputs")
+            .field("sources", &self.sources.len())
+            .field("filename", &self.filename)
+            .field("format", &self.format)
+            .field("slurp", &self.slurp)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Iterator for Inputs {
+    type Item = Result<Value, InputError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.slurp {
+            self.slurp = false;
+            return Some(self.format.slurp(self));
+        }
+
+        loop {
+            if let Some(value) = self.current.as_mut().and_then(Iterator::next) {
+                return Some(value);
+            }
+
+            let source = self.sources.pop_front()?;
+            self.filename.clone_from(&source.name);
+            self.current = Some(self.format.read(source));
+        }
+    }
+}
+
+fn read_json(source: InputSource) -> Values {
+    let InputSource { name, reader } = source;
+    let recorder = Rc::new(RefCell::new(Recorder {
+        reader,
+        line: LineTail::default(),
+    }));
+    let mut values =
+        serde_json::Deserializer::from_reader(Recording(recorder.clone())).into_iter::<Value>();
+
+    let mut document = 0;
+    let mut failed = false;
+    Box::new(iter::from_fn(move || {
+        // A document that fails to parse leaves the reader in an unknown
+        // state, so give up on the rest of this source after the first error.
+        if failed {
+            return None;
+        }
+        document += 1;
+        let error = match values.next()? {
+            Ok(value) => return Some(Ok(value)),
+            Err(error) => error,
+        };
+        failed = true;
+        if error.is_io() {
+            return Some(Err(InputError::read(name.clone(), error.into())));
+        }
+
+        let (excerpt, excerpt_column) = recorder.borrow_mut().excerpt(error.column());
+        Some(Err(InputError {
+            filename: name.clone(),
+            document,
+            line: error.line(),
+            column: error.column(),
+            excerpt,
+            excerpt_column,
+            error,
+        }))
+    }))
+}
+
+/// The end of the line being read, to show where a text stops being valid.
+#[derive(Debug, Clone, Default)]
+struct LineTail {
+    /// The last bytes read from the line.
+    bytes: Vec<u8>,
+    /// How many bytes of the line have been read.
+    length: usize,
+}
+
+impl LineTail {
+    fn push(&mut self, bytes: &[u8]) {
+        let bytes = match bytes.iter().rposition(|&b| b == b'\n') {
+            Some(newline) => {
+                self.bytes.clear();
+                self.length = 0;
+                &bytes[newline + 1..]
+            }
+            None => bytes,
+        };
+        self.bytes.extend_from_slice(bytes);
+        self.length += bytes.len();
+
+        // Only the text right before the error is shown.
+        if self.bytes.len() > 2 * EXCERPT_CONTEXT {
+            self.bytes.drain(..self.bytes.len() - EXCERPT_CONTEXT);
+        }
+    }
+
+    /// Where the kept bytes start in the line.
+    fn offset(&self) -> usize {
+        self.length - self.bytes.len()
+    }
+}
+
+/// What has been read of a source parsed as JSON.
+struct Recorder {
+    reader: Box<dyn BufRead>,
+    line: LineTail,
+}
+
+impl Recorder {
+    /// Cuts the text around `column` of the current line, reading a little
+    /// further to show what follows the error.
+    fn excerpt(&mut self, column: usize) -> (String, usize) {
+        let mut text = self.line.bytes.clone();
+        // The error is reported anyway if the rest can't be read.
+        let _ = (&mut self.reader)
+            .take(2 * EXCERPT_CONTEXT as u64)
+            .read_until(b'\n', &mut text);
+        excerpt(&text, self.line.offset(), column)
+    }
+}
+
+/// Hands the text of a source to the JSON parser through its `Recorder`.
+struct Recording(Rc<RefCell<Recorder>>);
+
+impl Read for Recording {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut recorder = self.0.borrow_mut();
+        let read = recorder.reader.read(buf)?;
+        recorder.line.push(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// Reads texts separated by ASCII RS like jq's `--seq`. A text that fails to
+/// parse only loses the rest of its record, and the next ones are read.
+fn read_json_seq(source: InputSource) -> Values {
+    let InputSource { name, mut reader } = source;
+    let mut pending = VecDeque::new();
+    let mut document = 0;
+    // Where the next record starts in the source.
+    let mut line = 1;
+    let mut line_tail = LineTail::default();
+
+    Box::new(iter::from_fn(move || loop {
+        if let Some(value) = pending.pop_front() {
+            return Some(value);
+        }
+
+        let mut record = Vec::new();
+        match reader.read_until(RECORD_SEPARATOR, &mut record) {
+            Ok(0) => return None,
+            Ok(_) => {}
+            Err(e) => return Some(Err(InputError::read(name.clone(), e))),
+        }
+        let text = record.strip_suffix(&[RECORD_SEPARATOR]).unwrap_or(&record);
+
+        for value in serde_json::Deserializer::from_slice(text).into_iter::<Value>() {
+            document += 1;
+            let error = match value {
+                Ok(value) => {
+                    pending.push_back(Ok(value));
+                    continue;
+                }
+                Err(error) => error,
+            };
+
+            // Positions in the record are moved to where it is in the source,
+            // whose first line may have started before it.
+            let text_line = text.split(|&b| b == b'\n').nth(error.line() - 1);
+            let text_line = text_line.unwrap_or_default();
+            let (error_line, error_column, (excerpt, excerpt_column)) = match error.line() {
+                1 => {
+                    let column = line_tail.length + error.column();
+                    let text = [&line_tail.bytes, text_line].concat();
+                    (line, column, excerpt(&text, line_tail.offset(), column))
+                }
+                n => (
+                    line + n - 1,
+                    error.column(),
+                    excerpt(text_line, 0, error.column()),
+                ),
+            };
+            pending.push_back(Err(InputError {
+                filename: name.clone(),
+                document,
+                line: error_line,
+                column: error_column,
+                excerpt,
+                excerpt_column,
+                error,
+            }));
+            break;
+        }
+
+        line += record.iter().filter(|&&b| b == b'\n').count();
+        line_tail.push(&record);
+    }))
+}
+
+/// Cuts the text around the error at byte `column` of a line, of which `text`
+/// is the part from byte `offset` onwards. Returns the excerpt and the column
+/// of the error in it, in characters.
+fn excerpt(text: &[u8], offset: usize, column: usize) -> (String, usize) {
+    let is_continuation = |byte: u8| byte & 0b1100_0000 == 0b1000_0000;
+
+    let text = text.split(|&b| b == b'\n').next().unwrap_or_default();
+    let text = text.strip_suffix(b"\r").unwrap_or(text);
+
+    let mut index = column.saturating_sub(offset + 1).min(text.len());
+    while index > 0 && index < text.len() && is_continuation(text[index]) {
+        index -= 1;
+    }
+    let mut start = index.saturating_sub(EXCERPT_CONTEXT);
+    while start < index && is_continuation(text[start]) {
+        start += 1;
+    }
+    let mut end = (index + EXCERPT_CONTEXT).min(text.len());
+    while end > index && end < text.len() && is_continuation(text[end]) {
+        end -= 1;
+    }
+
+    // Record separators wouldn't be visible and would put the caret off.
+    let visible = |bytes: &[u8]| String::from_utf8_lossy(bytes).replace('\x1e', "");
+
+    let mut excerpt = String::new();
+    if offset + start > 0 {
+        excerpt.push_str("...");
+    }
+    excerpt.push_str(&visible(&text[start..index]));
+    let excerpt_column = excerpt.chars().count() + 1;
+    excerpt.push_str(&visible(&text[index..end]));
+    if end < text.len() {
+        excerpt.push_str("...");
+    }
+
+    (excerpt, excerpt_column)
+}
+
+/// Splits the text on newlines only, so carriage returns are kept like in jq,
+/// and replaces invalid UTF-8 instead of failing.
+fn read_lines(source: InputSource) -> Values {
+    let InputSource { name, mut reader } = source;
+
+    Box::new(iter::from_fn(move || {
+        let mut line = Vec::new();
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) => None,
+            Ok(_) => {
+                if line.ends_with(b"\n") {
+                    line.pop();
+                }
+                Some(Ok(Value::String(
+                    String::from_utf8_lossy(&line).into_owned(),
+                )))
+            }
+            Err(e) => Some(Err(InputError::read(name.clone(), e))),
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(name: &str, text: &'static str) -> InputSource {
+        InputSource::new(Some(name.to_owned()), text.as_bytes())
+    }
+
+    #[test]
+    fn sources_are_read_in_order() {
+        let mut inputs = Inputs::new([source("a.json", "1 2"), source("b.json", "[3]")]);
+
+        assert_eq!(inputs.next().unwrap().unwrap(), Value::from(1));
+        assert_eq!(inputs.filename(), Some("a.json"));
+        assert_eq!(inputs.next().unwrap().unwrap(), Value::from(2));
+        assert_eq!(inputs.next().unwrap().unwrap(), serde_json::json!([3]));
+        assert_eq!(inputs.filename(), Some("b.json"));
+        assert!(inputs.next().is_none());
+    }
+
+    #[test]
+    fn parse_errors_name_the_file() {
+        let mut inputs = Inputs::new([
+            source("good.json", "{}"),
+            source("bad.json", "1\n{\"a\" 2}"),
+        ]);
+
+        assert!(inputs.next().unwrap().is_ok());
+        assert!(inputs.next().unwrap().is_ok());
+
+        let error = inputs.next().unwrap().unwrap_err();
+        assert_eq!(error.filename.as_deref(), Some("bad.json"));
+        assert!(error.error.is_syntax());
+        assert_eq!((error.document, error.line, error.column), (2, 2, 6));
+        assert_eq!(
+            error.to_string(),
+            "Failed to parse JSON in bad.json (document 2): expected `:` at line 2, column 6\n \
+             --> bad.json:2:6\n  \
+             |\n\
+             2 | {\"a\" 2}\n  \
+             |      ^"
+        );
+        assert!(inputs.next().is_none());
+    }
+
+    #[test]
+    fn long_lines_are_cut_around_errors() {
+        let text = format!("[{}x, {}]", "1, ".repeat(30), "2, ".repeat(30));
+        let mut inputs = Inputs::new([InputSource::new(None, io::Cursor::new(text))]);
+
+        let error = inputs.next().unwrap().unwrap_err();
+        assert_eq!((error.line, error.column), (1, 92));
+        assert_eq!(
+            error.excerpt,
+            "... 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, x, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2..."
+        );
+        assert_eq!(error.excerpt_column, 44);
+    }
+
+    #[test]
+    fn excerpts_keep_whole_characters() {
+        assert_eq!(
+            excerpt("{\"é€\": x}".as_bytes(), 0, 11),
+            ("{\"é€\": x}".to_owned(), 8)
+        );
+        assert_eq!(excerpt("x\r\n".as_bytes(), 0, 1), ("x".to_owned(), 1));
+        assert_eq!(excerpt(b"", 0, 0), (String::new(), 1));
+
+        let text = "é".repeat(30);
+        let (excerpt, column) = excerpt(text.as_bytes(), 0, 60);
+        assert_eq!(excerpt, format!("...{}", "é".repeat(21)));
+        assert_eq!(column, 24);
+    }
+
+    #[test]
+    fn seq_inputs_skip_invalid_texts() {
+        let mut inputs = Inputs::new([source(
+            "a.json",
+            "\x1e{\"a\": 1}\n\x1e{\"a\" 2} 3\n\x1e[4]\n\x1e[5",
+        )]);
+        inputs.format = InputFormat::JsonSeq;
+
+        assert_eq!(inputs.next().unwrap().unwrap(), serde_json::json!({"a": 1}));
+        let error = inputs.next().unwrap().unwrap_err();
+        assert_eq!((error.document, error.line, error.column), (2, 2, 7));
+        assert_eq!(
+            (error.excerpt.as_str(), error.excerpt_column),
+            ("{\"a\" 2} 3", 6)
+        );
+        assert_eq!(inputs.next().unwrap().unwrap(), serde_json::json!([4]));
+        let error = inputs.next().unwrap().unwrap_err();
+        assert_eq!((error.document, error.line, error.column), (4, 4, 3));
+        assert!(inputs.next().is_none());
+    }
+
+    #[test]
+    fn slurp_every_source() {
+        let mut inputs = Inputs::new([source("a.json", "1 2"), source("b.json", "3")]);
+        inputs.slurp = true;
+        assert_eq!(
+            inputs.next().unwrap().unwrap(),
+            serde_json::json!([1, 2, 3])
+        );
+        assert!(inputs.next().is_none());
+
+        let mut inputs = Inputs::new([source("a.txt", "a\nb\n"), source("b.txt", "c")]);
+        inputs.format = InputFormat::Raw;
+        inputs.slurp = true;
+        assert_eq!(inputs.next().unwrap().unwrap(), Value::from("a\nb\nc"));
+        assert_eq!(inputs.filename(), Some("b.txt"));
+    }
+
+    #[test]
+    fn raw_lines_keep_carriage_returns() {
+        let mut inputs = Inputs::new([InputSource::new(None, &b"a\r\n\nb\xffc"[..])]);
+        inputs.format = InputFormat::Raw;
+
+        let lines = inputs.collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(
+            lines,
+            vec![
+                Value::from("a\r"),
+                Value::from(""),
+                Value::from("b\u{fffd}c")
+            ]
+        );
+    }
+}
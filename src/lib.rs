@@ -1,28 +1,209 @@
-use anyhow::{ensure, Context};
+use std::borrow::Cow;
+use std::io::{self, BufRead};
+
 use serde_json::Value;
-use winnow::combinator::iterator;
+use winnow::Parser;
+
+use crate::module::{Library, Program};
+use crate::parser::parse_program;
+use crate::token::{apply_tokens_with, check_references, Scope, Shared, Token};
 
-use crate::{parser::parse_token, token::apply_tokens};
+pub use crate::color::Colors;
+pub use crate::error::{Error, InputError, RuntimeError, SyntaxError, Undefined};
+pub use crate::format::{format_value, write_value, Indent, OutputOptions, Separator};
+pub use crate::input::{InputFormat, InputSource, Inputs};
+pub use crate::module::SearchPath;
+pub use crate::time::Clock;
+pub use crate::token::{Context, Environment, Halt};
 
+mod color;
+mod error;
+mod format;
+mod input;
+mod module;
 mod parser;
+mod time;
 mod token;
 
-pub fn apply_filter(input: &str, filter: Option<&str>) -> anyhow::Result<String> {
-    let json: Value = serde_json::from_str(input).context("Failed to parse JSON")?;
+/// Settings for reading inputs and writing outputs, mirroring jq's flags.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Run the filter once with `null` as its input instead of reading any (`-n`).
+    pub null_input: bool,
+    /// Collect every input into an array and run the filter once on it (`-s`).
+    pub slurp: bool,
+    /// How inputs are read, as JSON texts by default.
+    pub input_format: InputFormat,
+    pub output: OutputOptions,
+    pub context: Context,
+}
+
+/// A filter that has been parsed and linked with the modules it imports, ready
+/// to be run on any number of inputs.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    tokens: Vec<Token>,
+    context: Context,
+}
+
+impl Filter {
+    /// Checks the filter and loads its modules from the default search path.
+    pub fn compile(filter: &str) -> Result<Self, Error> {
+        Self::compile_with(filter, Context::default())
+    }
+
+    /// Like `compile`, but running the filter in `context`, which sets its
+    /// variables, clock, environment and module search path.
+    pub fn compile_with(filter: &str, context: Context) -> Result<Self, Error> {
+        let program = parse_filter(filter).map_err(Error::compile)?;
+        let library = Library::load(&program, &context.search_path).map_err(Error::compile)?;
+        let context = library.link(context).map_err(Error::compile)?;
+        check_references(&program.tokens, &context).map_err(Error::compile)?;
+
+        Ok(Self {
+            tokens: program.tokens,
+            context,
+        })
+    }
+
+    /// Runs the filter on `input`, producing each of its results only when
+    /// it's asked for, so that they can be handled as soon as they're ready
+    /// and the filter stops running as soon as the iterator is dropped.
+    ///
+    /// Results that are part of the input, or of the filter itself, are
+    /// borrowed from it rather than copied.
+    ///
+    /// An error, including the one raised by `halt`, is the last item.
+    ///
+    /// There are no further inputs for `input` and `inputs` to read.
+    pub fn run<'a>(
+        &'a self,
+        input: &'a Value,
+    ) -> impl Iterator<Item = Result<Cow<'a, Value>, Error>> + 'a {
+        self.run_in(Scope::new(&self.context), input)
+    }
+
+    /// Like `run`, but `input` and `inputs` read from `inputs`, leaving the
+    /// ones they don't read for the caller.
+    pub fn run_with_inputs<'a>(
+        &'a self,
+        input: &'a Value,
+        inputs: &'a mut Inputs,
+    ) -> impl Iterator<Item = Result<Cow<'a, Value>, Error>> + 'a {
+        self.run_in(Scope::new(&self.context).with_inputs(inputs), input)
+    }
+
+    fn run_in<'a>(
+        &'a self,
+        scope: Scope<'a>,
+        input: &'a Value,
+    ) -> impl Iterator<Item = Result<Cow<'a, Value>, Error>> + 'a {
+        let results = apply_tokens_with(&scope, Shared::Borrowed(input), &self.tokens);
+        let mut failed = false;
+        results.map_while(move |result| {
+            if failed {
+                return None;
+            }
+            failed = result.is_err();
+            Some(result.map(Shared::into_cow).map_err(Error::runtime))
+        })
+    }
+}
+
+pub fn apply_filter(input: &str, filter: Option<&str>) -> Result<String, Error> {
+    apply_filter_with(input, filter, &Options::default())
+}
+
+pub fn apply_filter_with(
+    input: &str,
+    filter: Option<&str>,
+    options: &Options,
+) -> Result<String, Error> {
+    let outputs = filter_values(input, filter, options)?;
+    Ok(format::format_values(&outputs, &options.output))
+}
+
+/// Runs the filter on the inputs and returns every result, leaving it to the
+/// caller to format them with `format_value`.
+pub fn filter_values(
+    input: &str,
+    filter: Option<&str>,
+    options: &Options,
+) -> Result<Vec<Value>, Error> {
+    let mut outputs = Vec::new();
+    let reader = io::Cursor::new(input.to_owned());
+    filter_stream(reader, filter, options, |value| -> Result<(), Error> {
+        outputs.push(value?.into_owned());
+        Ok(())
+    })?;
+
+    Ok(outputs)
+}
+
+/// Runs the filter on each JSON text read from `reader` in turn, handing every
+/// result to `emit` as soon as it's produced rather than waiting for the end
+/// of the stream.
+///
+/// When the filter fails on an input the error is handed to `emit` as well,
+/// and like in jq the next input is processed unless `emit` returns an error
+/// itself. So are inputs that aren't valid JSON with `InputFormat::JsonSeq`.
+/// Errors reading the inputs, in the filter itself and from `halt` stop
+/// everything and are returned instead, converted to whatever error type
+/// `emit` returns, such as `anyhow::Error`.
+pub fn filter_stream<E: From<Error>>(
+    reader: impl BufRead + 'static,
+    filter: Option<&str>,
+    options: &Options,
+    emit: impl FnMut(Result<Cow<'_, Value>, Error>) -> Result<(), E>,
+) -> Result<(), E> {
+    let inputs = Inputs::new([InputSource::new(None, reader)]);
+    filter_inputs(inputs, filter, options, emit)
+}
 
-    let filter = filter.unwrap_or(".");
-    let mut it = iterator(filter, parse_token);
+/// Like `filter_stream`, but reading from several sources one after the other.
+///
+/// The filter itself can pull further inputs with `input` and `inputs`, which
+/// is mostly useful together with `null_input`.
+pub fn filter_inputs<E: From<Error>>(
+    mut inputs: Inputs,
+    filter: Option<&str>,
+    options: &Options,
+    mut emit: impl FnMut(Result<Cow<'_, Value>, Error>) -> Result<(), E>,
+) -> Result<(), E> {
+    let filter = Filter::compile_with(filter.unwrap_or("."), options.context.clone())?;
 
-    let tokens = it.collect::<Vec<_>>();
-    let (remaining, _) = it
-        .finish()
-        .map_err(|e| anyhow::anyhow!("Failed to parse filter: {e:?}"))?;
+    inputs.format = options.input_format;
+    inputs.slurp = options.slurp;
 
-    ensure!(remaining.is_empty(), "Failed to parse the whole filter");
+    let mut run = |json: Result<Value, InputError>, inputs: &mut Inputs| match json {
+        Ok(json) => filter
+            .run_with_inputs(&json, inputs)
+            .try_for_each(|result| match result {
+                Err(Error::Halt(halt)) => Err(Error::Halt(halt).into()),
+                result => emit(result),
+            }),
+        // Invalid texts of `--seq` inputs are skipped like in jq.
+        Err(e) if options.input_format == InputFormat::JsonSeq && !e.is_io() => {
+            emit(Err(Error::Input(e)))
+        }
+        Err(e) => Err(Error::Input(e).into()),
+    };
 
-    let filtered = apply_tokens(&json, &tokens)?;
+    if options.null_input {
+        return run(Ok(Value::Null), &mut inputs);
+    }
 
-    token::token_output_to_string(filtered)
+    while let Some(json) = inputs.next() {
+        run(json, &mut inputs)?;
+    }
+
+    Ok(())
+}
+
+fn parse_filter(filter: &str) -> anyhow::Result<Program> {
+    parse_program
+        .parse(filter)
+        .map_err(|e| SyntaxError::new(filter, &e, None).into())
 }
 
 #[cfg(test)]
@@ -39,4 +220,560 @@ mod tests {
             "1\n2\n3".to_owned()
         )
     }
+
+    #[test]
+    fn brackets_build_arrays_unless_they_follow_a_term() {
+        let cases = [
+            ("[1,2,3] - [2]", "[1,3]"),
+            ("[1,[2],[[3]]]", "[1,[2],[[3]]]"),
+            ("[1,\"1\",[1]] | tostring", "\"[1,\\\"1\\\",[1]]\""),
+            ("[1,2,3][]", "1\n2\n3"),
+            ("[], [[]]", "[]\n[[]]"),
+            ("[] | all", "true"),
+            ("[[1], 2] [0] [0]", "1"),
+        ];
+        let options = Options {
+            output: OutputOptions {
+                indent: Indent::Compact,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        for (filter, expected) in cases {
+            let output = apply_filter_with("null", Some(filter), &options).unwrap();
+            assert_eq!(output, expected, "{filter}");
+        }
+        assert!(matches!(
+            apply_filter("null", Some("[1][nan]")),
+            Err(Error::Syntax(_))
+        ));
+    }
+
+    #[test]
+    fn math_output_to_string() {
+        let input = r#"{"ratio": 0.4567}"#;
+        let filter = ".ratio | pow(.; 2) | sqrt | round";
+
+        assert_eq!(apply_filter(input, Some(filter)).unwrap(), "0".to_owned());
+        assert_eq!(
+            apply_filter(input, Some("infinite")).unwrap(),
+            "1.7976931348623157e+308".to_owned()
+        );
+        assert_eq!(
+            apply_filter("2", Some("pow(.; -1), -. * -3, 1 - -1")).unwrap(),
+            "0.5\n6\n2".to_owned()
+        );
+    }
+
+    #[test]
+    fn multi_line_filter_with_comments() {
+        let input = r#"{"items": [1, 2, 3]}"#;
+        let filter = "# sum the items \\\n  still a comment\n\
+                      reduce .items[] as $x (0;\n    . + $x) # total\n";
+
+        assert_eq!(apply_filter(input, Some(filter)).unwrap(), "6".to_owned());
+    }
+
+    #[test]
+    fn dates_with_fixed_clock() {
+        let options = Options {
+            context: Context {
+                clock: Clock::Fixed(1425599621.0),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        assert_eq!(
+            apply_filter_with("null", Some("now | todate"), &options).unwrap(),
+            "\"2015-03-05T23:53:41Z\"".to_owned()
+        );
+        assert_eq!(
+            apply_filter_with("null", Some(r#"now | strftime("%A, %B %d, %Y")"#), &options)
+                .unwrap(),
+            "\"Thursday, March 05, 2015\"".to_owned()
+        );
+    }
+
+    #[test]
+    fn null_input_ignores_input() {
+        let options = Options {
+            null_input: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            apply_filter_with("not json", Some("."), &options).unwrap(),
+            "null".to_owned()
+        );
+    }
+
+    #[test]
+    fn slurp_inputs_into_array() {
+        let options = Options {
+            slurp: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            filter_values("1 [2]\n{\"a\": 3}", Some("."), &options).unwrap(),
+            vec![serde_json::json!([1, [2], {"a": 3}])]
+        );
+    }
+
+    #[test]
+    fn raw_input_lines() {
+        let options = Options {
+            input_format: InputFormat::Raw,
+            ..Default::default()
+        };
+        assert_eq!(
+            filter_values("a\nb c\n", Some("."), &options).unwrap(),
+            vec![Value::from("a"), Value::from("b c")]
+        );
+
+        let options = Options {
+            input_format: InputFormat::Raw,
+            slurp: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            filter_values("a\nb c\n", Some("."), &options).unwrap(),
+            vec![Value::from("a\nb c\n")]
+        );
+    }
+
+    #[test]
+    fn raw_compact_and_joined_output() {
+        let input = r#"{"b": ["x", {"d": 1, "c": 2}]}"#;
+
+        let options = Options {
+            output: OutputOptions {
+                raw: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(
+            apply_filter_with(input, Some(".b[]"), &options).unwrap(),
+            "x\n{\n  \"d\": 1,\n  \"c\": 2\n}".to_owned()
+        );
+
+        let options = Options {
+            output: OutputOptions {
+                raw: true,
+                separator: Separator::Nothing,
+                indent: Indent::Compact,
+                sort_keys: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(
+            apply_filter_with(input, Some(".b[]"), &options).unwrap(),
+            "x{\"c\":2,\"d\":1}".to_owned()
+        );
+    }
+
+    #[test]
+    fn concatenated_and_newline_delimited_inputs() {
+        assert_eq!(
+            apply_filter(r#"{"a":1}{"a":2}"#, Some(".a")).unwrap(),
+            "1\n2".to_owned()
+        );
+        assert_eq!(
+            apply_filter("{\"a\": [3]}\n{\"a\": [4, 5]}\n", Some(".a[]")).unwrap(),
+            "3\n4\n5".to_owned()
+        );
+        assert_eq!(
+            apply_filter("1 \"two\"\t[3]\n\n", Some(".")).unwrap(),
+            "1\n\"two\"\n[\n  3\n]".to_owned()
+        );
+        assert_eq!(apply_filter("  \n", Some(".")).unwrap(), "".to_owned());
+    }
+
+    #[test]
+    fn results_are_emitted_before_later_inputs_fail() {
+        let mut outputs = Vec::new();
+        let result = filter_stream(
+            r#"{"a":1} {"a":"#.as_bytes(),
+            Some(".a"),
+            &Options::default(),
+            |value| -> Result<(), Error> {
+                outputs.push(value?.into_owned());
+                Ok(())
+            },
+        );
+
+        assert!(result.is_err());
+        assert_eq!(outputs, vec![Value::from(1)]);
+    }
+
+    #[test]
+    fn input_filename_of_each_source() {
+        let inputs = Inputs::new([
+            InputSource::new(Some("a.json".to_owned()), "1 2".as_bytes()),
+            InputSource::new(Some("b.json".to_owned()), "3".as_bytes()),
+        ]);
+
+        let mut outputs = Vec::new();
+        filter_inputs(
+            inputs,
+            Some("[., input_filename]"),
+            &Options::default(),
+            |value| -> Result<(), Error> {
+                outputs.push(value?.into_owned());
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            outputs,
+            vec![
+                serde_json::json!([1, "a.json"]),
+                serde_json::json!([2, "a.json"]),
+                serde_json::json!([3, "b.json"]),
+            ]
+        );
+        assert_eq!(
+            apply_filter("1", Some("input_filename")).unwrap(),
+            "null".to_owned()
+        );
+    }
+
+    #[test]
+    fn pull_inputs_from_the_filter() {
+        let input = r#"{"size": 3} {"size": 4} {"size": 5}"#;
+        let null_input = Options {
+            null_input: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            apply_filter_with(
+                input,
+                Some("reduce inputs as $x (0; . + $x.size)"),
+                &null_input
+            )
+            .unwrap(),
+            "12".to_owned()
+        );
+        assert_eq!(
+            apply_filter_with(input, Some("input.size"), &null_input).unwrap(),
+            "3".to_owned()
+        );
+        assert_eq!(
+            apply_filter(r#"{"size": 3} {"size": 4}"#, Some("[.size, input.size]")).unwrap(),
+            "[\n  3,\n  4\n]".to_owned()
+        );
+        assert!(apply_filter("1", Some("input")).is_err());
+        // Only the inputs that are needed are read.
+        assert_eq!(
+            filter_values(input, Some("first(inputs).size, input.size"), &null_input).unwrap(),
+            vec![Value::from(3), Value::from(4)]
+        );
+    }
+
+    #[test]
+    fn slurp_with_null_input() {
+        let options = Options {
+            null_input: true,
+            slurp: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            filter_values("1 2", Some("., input, [inputs]"), &options).unwrap(),
+            vec![
+                Value::Null,
+                serde_json::json!([1, 2]),
+                serde_json::json!([])
+            ]
+        );
+    }
+
+    #[test]
+    fn failures_are_told_apart() {
+        let failure = |input: &str, filter: &str| {
+            filter_values(input, Some(filter), &Options::default()).unwrap_err()
+        };
+
+        assert!(matches!(failure("1", "[.a"), Error::Syntax(_)));
+        assert!(matches!(
+            failure("1", "import \"nope\" as n; ."),
+            Error::Module(_)
+        ));
+        assert!(matches!(failure("1", "nope"), Error::Undefined(_)));
+        assert!(matches!(failure("{", "."), Error::Input(_)));
+        assert!(matches!(failure("1", ".a"), Error::Runtime(_)));
+        assert!(matches!(failure("1", "halt_error"), Error::Halt(_)));
+    }
+
+    #[test]
+    fn syntax_errors_point_at_the_filter() {
+        let error = filter_values("1", Some(".a |\n  [.b"), &Options::default()).unwrap_err();
+        let Error::Syntax(syntax) = error else {
+            panic!("expected a syntax error, got {error:?}");
+        };
+
+        assert_eq!((syntax.line, syntax.column), (2, 6));
+        assert_eq!(syntax.expected, vec!["`]`"]);
+    }
+
+    #[test]
+    fn runtime_errors_tell_where_they_happened() {
+        let runtime_error = |input: &str, filter: &str| match filter_values(
+            input,
+            Some(filter),
+            &Options::default(),
+        ) {
+            Err(Error::Runtime(error)) => error,
+            result => panic!("expected a runtime error, got {result:?}"),
+        };
+
+        let error = runtime_error(r#"{"a": [{"b": []}, {"b": "x"}]}"#, ".a[].b[]");
+        assert_eq!(error.value, Some(Value::from("x")));
+        assert_eq!(
+            error.path,
+            Some(vec![Value::from("a"), Value::from(1), Value::from("b")])
+        );
+
+        let error = runtime_error(r#"{"a": {"b": 5}}"#, ".a | .b.c");
+        assert_eq!(error.value, Some(Value::from(5)));
+        assert_eq!(error.path, Some(vec![Value::from("a"), Value::from("b")]));
+
+        let error = runtime_error(r#"{"a": 5}"#, "def f: .a[0]; .x, f");
+        assert_eq!(error.value, Some(Value::from(5)));
+        assert_eq!(error.path, Some(vec![Value::from("a")]));
+
+        // Values that are computed aren't anywhere in the input.
+        let error = runtime_error("[1]", "[.[] + 1] | .[0].a");
+        assert_eq!(error.value, Some(Value::from(2)));
+        assert_eq!(error.path, None);
+
+        let error = runtime_error("null", "1 / 0");
+        assert_eq!(error.value, None);
+        assert_eq!(error.path, None);
+    }
+
+    #[test]
+    fn runtime_errors_continue_with_next_input() {
+        let mut outputs = Vec::new();
+        filter_stream(
+            r#"{"a":1} [2] {"a":3}"#.as_bytes(),
+            Some(".a"),
+            &Options::default(),
+            |value| {
+                outputs.push(value.map(Cow::into_owned).map_err(|e| e.to_string()));
+                Ok::<_, Error>(())
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            outputs,
+            vec![
+                Ok(Value::from(1)),
+                Err("Cannot index array with \"a\"".to_owned()),
+                Ok(Value::from(3)),
+            ]
+        );
+    }
+
+    #[test]
+    fn seq_inputs_continue_past_invalid_texts() {
+        let options = Options {
+            input_format: InputFormat::JsonSeq,
+            ..Default::default()
+        };
+        let mut outputs = Vec::new();
+        filter_stream(
+            "\x1e{\"a\":1}\n\x1e{\"a\"\n\x1e{\"a\":3}\n".as_bytes(),
+            Some(".a"),
+            &options,
+            |value| {
+                outputs.push(value.map(Cow::into_owned).map_err(|e| match e {
+                    Error::Input(e) => (e.document, e.line),
+                    e => panic!("expected an input error, got {e:?}"),
+                }));
+                Ok::<_, Error>(())
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            outputs,
+            vec![Ok(Value::from(1)), Err((2, 3)), Ok(Value::from(3))]
+        );
+    }
+
+    #[test]
+    fn halt_stops_every_input() {
+        let mut outputs = Vec::new();
+        let error = filter_stream(
+            r#""bye" 2"#.as_bytes(),
+            Some("halt_error(1)"),
+            &Options::default(),
+            |value| -> Result<(), Error> {
+                outputs.push(value?.into_owned());
+                Ok(())
+            },
+        )
+        .unwrap_err();
+
+        assert!(matches!(
+            error,
+            Error::Halt(Halt {
+                code: 1,
+                message: Some(ref message),
+            }) if message == "bye"
+        ));
+        assert!(outputs.is_empty());
+    }
+
+    #[test]
+    fn compiled_filters_run_on_many_inputs() {
+        let filter = Filter::compile("def double: . * 2; .[] | double").unwrap();
+        let run = |input: Value| {
+            filter
+                .run(&input)
+                .map(|value| value.map(Cow::into_owned))
+                .collect::<Result<Vec<_>, _>>()
+        };
+
+        assert_eq!(
+            run(serde_json::json!([1, 2])).unwrap(),
+            vec![serde_json::json!(2), serde_json::json!(4)]
+        );
+        assert_eq!(
+            run(serde_json::json!({"a": 3})).unwrap(),
+            vec![serde_json::json!(6)]
+        );
+        assert!(matches!(
+            run(serde_json::json!(true)),
+            Err(Error::Runtime(_))
+        ));
+        assert_eq!(run(serde_json::json!([])).unwrap(), Vec::<Value>::new());
+    }
+
+    #[test]
+    fn compiled_filters_are_shared_between_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Filter>();
+
+        let filter = Filter::compile("def double: . * 2; [.[] | double]").unwrap();
+        std::thread::scope(|threads| {
+            for n in 0..4 {
+                let filter = &filter;
+                threads.spawn(move || {
+                    let input = serde_json::json!([n, 1]);
+                    let output = filter.run(&input).next().unwrap().unwrap();
+                    assert_eq!(*output, serde_json::json!([n * 2, 2]));
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn inputs_are_passed_to_each_run() {
+        let filter = Filter::compile("[., input]").unwrap();
+        let reader = io::Cursor::new("1 2 3 4");
+        let mut inputs = Inputs::new([InputSource::new(None, reader)]);
+
+        let first = inputs.next().unwrap().unwrap();
+        let output = filter.run_with_inputs(&first, &mut inputs).next().unwrap();
+        assert_eq!(*output.unwrap(), serde_json::json!([1, 2]));
+        let third = inputs.next().unwrap().unwrap();
+        let output = filter.run_with_inputs(&third, &mut inputs).next().unwrap();
+        assert_eq!(*output.unwrap(), serde_json::json!([3, 4]));
+
+        // Neither the filter nor its clones keep any inputs of their own.
+        let error = filter
+            .clone()
+            .run(&Value::Null)
+            .next()
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(error.to_string(), "No more inputs");
+    }
+
+    #[test]
+    fn results_are_produced_as_they_are_asked_for() {
+        let filter = Filter::compile(".[] | . + 1").unwrap();
+        let input = serde_json::json!([1, "a", 2]);
+        let mut results = filter.run(&input);
+
+        assert!(matches!(results.next(), Some(Ok(value)) if *value == 2));
+        assert!(matches!(results.next(), Some(Err(Error::Runtime(_)))));
+        // Like in jq, the filter stops at its first error.
+        assert!(results.next().is_none());
+    }
+
+    #[test]
+    fn selected_values_are_borrowed_from_the_input() {
+        let input = serde_json::json!({"a": [1, {"b": 2}], "c": "d"});
+        let filter = Filter::compile(".a[1], .c, .a[], .a[0] + 1").unwrap();
+        let results = filter.run(&input).collect::<Result<Vec<_>, _>>().unwrap();
+
+        assert!(matches!(results[0], Cow::Borrowed(value) if std::ptr::eq(value, &input["a"][1])));
+        assert!(matches!(results[1], Cow::Borrowed(value) if std::ptr::eq(value, &input["c"])));
+        assert!(matches!(results[2], Cow::Borrowed(value) if std::ptr::eq(value, &input["a"][0])));
+        assert!(matches!(results[3], Cow::Borrowed(value) if std::ptr::eq(value, &input["a"][1])));
+        assert!(matches!(results[4], Cow::Owned(ref value) if *value == 2));
+    }
+
+    #[test]
+    fn filters_are_checked_when_compiled() {
+        assert!(matches!(Filter::compile(".a |"), Err(Error::Syntax(_))));
+        assert!(matches!(
+            Filter::compile("include \"nope\"; ."),
+            Err(Error::Module(_))
+        ));
+        assert!(matches!(
+            Filter::compile(".[] | foo(1)"),
+            Err(Error::Undefined(Undefined::Function { ref name, arity: 1 })) if name == "foo"
+        ));
+        assert!(matches!(
+            Filter::compile("$undefined"),
+            Err(Error::Undefined(Undefined::Variable(ref name))) if name == "undefined"
+        ));
+        assert!(matches!(
+            Filter::compile("@base64"),
+            Err(Error::Undefined(Undefined::Format(ref name))) if name == "base64"
+        ));
+        // Variables are only bound for what follows them, and `$ENV` and
+        // `$ARGS` always are.
+        assert!(Filter::compile("$ENV, $ARGS, [1 as $x | $x], reduce 1 as $y (0; $y)").is_ok());
+        assert!(Filter::compile("def f($x): $x; f(1), @text, @json").is_ok());
+        assert!(matches!(
+            Filter::compile("[1 as $x | $x], $x"),
+            Err(Error::Undefined(Undefined::Variable(_)))
+        ));
+        assert!(matches!(
+            Filter::compile("reduce $y as $y (0; 1)"),
+            Err(Error::Undefined(Undefined::Variable(_)))
+        ));
+        assert!(matches!(
+            Filter::compile("def f: 1; f(2)"),
+            Err(Error::Undefined(_))
+        ));
+        // Definitions see themselves and their parameters, but only in the
+        // rest of the pipeline.
+        assert!(Filter::compile("def f(g; $x): g, x, $x, f(g; $x); f(floor; pow(2; 3))").is_ok());
+        assert!(matches!(
+            Filter::compile("[def f: 1; f] | f"),
+            Err(Error::Undefined(_))
+        ));
+
+        let context = Context::default().with_variable("x", serde_json::json!(1));
+        let filter = Filter::compile_with("$x + .", context).unwrap();
+        let input = serde_json::json!(2);
+        let results = filter.run(&input).collect::<Vec<_>>();
+        assert!(matches!(&results[..], [Ok(value)] if **value == serde_json::json!(3)));
+        // Without inputs of its own, `input` has nothing to read.
+        let filter = Filter::compile("input").unwrap();
+        let mut results = filter.run(&Value::Null);
+        assert!(matches!(results.next(), Some(Err(Error::Runtime(_)))));
+    }
 }
@@ -1,18 +1,220 @@
-use std::io::{stdin, Read};
+use std::env;
+use std::fs::{self, File};
+use std::io::{stdin, stdout, BufReader, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 
-use jq_clone::apply_filter;
+use anyhow::Context as _;
+use jq_clone::{
+    filter_inputs, format_value, write_value, Colors, Error, Indent, InputSource, Inputs,
+    OutputOptions, SearchPath,
+};
+use serde_json::{Map, Value};
 
-fn main() {
-    let filter = std::env::args().nth(1);
+use crate::cli::{parse_args, Args, NamedArg, HELP_HINT, USAGE};
 
-    let mut buf = String::new();
-    let _ = stdin()
-        .lock()
-        .read_to_string(&mut buf)
-        .expect("Could not read stdin");
+mod cli;
 
-    match apply_filter(&buf, filter.as_deref()) {
-        Ok(string) => println!("{string}"),
-        Err(e) => eprintln!("Failed to apply filters: {e:?}"),
+fn main() -> ExitCode {
+    let mut args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("jq-clone: error: {e}\n{HELP_HINT}");
+            return ExitCode::from(2);
+        }
+    };
+
+    if args.help {
+        println!("{USAGE}");
+        return ExitCode::SUCCESS;
+    }
+
+    args.options.output.colors = output_colors(&args);
+
+    match run(&args) {
+        Ok(code) => code,
+        Err(e) => report_error(&e),
     }
 }
+
+/// Colours are used when forced with `-C`, or by default when writing to a
+/// terminal unless `NO_COLOR` is set.
+fn output_colors(args: &Args) -> Option<Colors> {
+    let no_color = env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    let colorize = args
+        .color
+        .unwrap_or_else(|| stdout().is_terminal() && !no_color);
+    if !colorize {
+        return None;
+    }
+
+    let Some(spec) = env::var_os("JQ_COLORS") else {
+        return Some(Colors::default());
+    };
+    match Colors::from_spec(&spec.to_string_lossy()) {
+        Ok(colors) => Some(colors),
+        Err(e) => {
+            eprintln!("Failed to set $JQ_COLORS: {e}");
+            Some(Colors::default())
+        }
+    }
+}
+
+fn run(args: &Args) -> anyhow::Result<ExitCode> {
+    let filter = match (&args.filter, args.from_file) {
+        (Some(path), true) => {
+            Some(fs::read_to_string(path).with_context(|| format!("Could not open {path}"))?)
+        }
+        (filter, false) => filter.clone(),
+        (None, true) => None,
+    };
+
+    let mut options = args.options.clone();
+    options.context = options
+        .context
+        .with_arguments(named_arguments(args)?, args.positional_args.clone());
+    set_search_path(args, &mut options.context.search_path);
+
+    let (inputs, all_opened) = open_inputs(args);
+
+    let mut out = stdout().lock();
+    let mut last = None;
+    let mut failed = false;
+    filter_inputs(
+        inputs,
+        filter.as_deref(),
+        &options,
+        |value| -> anyhow::Result<()> {
+            match value {
+                Ok(value) => {
+                    write_value(&mut out, &value, &args.options.output)?;
+                    out.flush()?;
+                    last = Some(!matches!(*value, Value::Null | Value::Bool(false)));
+                }
+                // Only `--seq` inputs carry on past invalid texts.
+                Err(Error::Input(e)) => eprintln!("jq-clone: ignoring parse error: {e}"),
+                Err(e) => {
+                    eprintln!("jq-clone: error: {e}");
+                    failed = true;
+                }
+            }
+            Ok(())
+        },
+    )?;
+
+    if failed {
+        return Ok(ExitCode::from(5));
+    }
+    if !all_opened {
+        return Ok(ExitCode::from(2));
+    }
+    if !args.exit_status {
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    Ok(match last {
+        None => ExitCode::from(4),
+        Some(false) => ExitCode::from(1),
+        Some(true) => ExitCode::SUCCESS,
+    })
+}
+
+/// Like jq, `-L` replaces the default search path, imports of a filter read
+/// with `-f` are relative to its file, and `~/.jq` is included in every
+/// program when it's a file.
+fn set_search_path(args: &Args, search_path: &mut SearchPath) {
+    if !args.library_paths.is_empty() {
+        search_path.directories.clone_from(&args.library_paths);
+    }
+
+    if let (Some(path), true) = (&args.filter, args.from_file) {
+        search_path.program_directory = Path::new(path).parent().map(Path::to_owned);
+    }
+
+    search_path.prelude = env::var_os("HOME")
+        .map(|home| PathBuf::from(home).join(".jq"))
+        .filter(|prelude| prelude.is_file());
+}
+
+/// Reads the values of `--arg` and friends, loading the files they refer to.
+fn named_arguments(args: &Args) -> anyhow::Result<Map<String, Value>> {
+    let mut named = Map::new();
+
+    for (name, arg) in &args.named_args {
+        let value = match arg {
+            NamedArg::Value(value) => value.clone(),
+            NamedArg::SlurpFile(path) => {
+                let text = fs::read_to_string(path)
+                    .with_context(|| format!("Could not open {}", path.display()))?;
+                serde_json::Deserializer::from_str(&text)
+                    .into_iter()
+                    .collect::<Result<Vec<Value>, _>>()
+                    .map(Value::Array)
+                    .with_context(|| format!("Bad JSON in --slurpfile {name} {}", path.display()))?
+            }
+            NamedArg::RawFile(path) => fs::read_to_string(path)
+                .map(Value::String)
+                .with_context(|| format!("Could not open {}", path.display()))?,
+        };
+        named.insert(name.clone(), value);
+    }
+
+    Ok(named)
+}
+
+/// Reports an error that stopped the program and picks the exit status jq
+/// would use for it: 3 for invalid filters, 5 for errors while running them,
+/// whatever `halt_error` was given, and 2 for everything else.
+fn report_error(e: &anyhow::Error) -> ExitCode {
+    let error = e.downcast_ref::<Error>();
+    if let Some(Error::Halt(halt)) = error {
+        match &halt.message {
+            Some(Value::String(message)) => eprint!("{message}"),
+            Some(message) => {
+                let options = OutputOptions {
+                    indent: Indent::Compact,
+                    ..Default::default()
+                };
+                eprintln!("{}", format_value(message, &options));
+            }
+            None => {}
+        }
+        return ExitCode::from(halt.code as u8);
+    }
+
+    eprintln!("jq-clone: error: {e:#}");
+    match error {
+        Some(Error::Syntax(_) | Error::Module(_) | Error::Undefined(_)) => {
+            eprintln!("jq-clone: 1 compile error");
+            ExitCode::from(3)
+        }
+        Some(Error::Runtime(_)) => ExitCode::from(5),
+        Some(Error::Input(_) | Error::Halt(_)) | None => ExitCode::from(2),
+    }
+}
+
+/// Opens every file given on the command line, or stdin when there are none.
+/// Like jq, files that can't be opened are reported and skipped, which is
+/// signalled by returning `false` alongside the inputs.
+fn open_inputs(args: &Args) -> (Inputs, bool) {
+    if args.files.is_empty() {
+        return (Inputs::new([InputSource::new(None, stdin().lock())]), true);
+    }
+
+    let mut all_opened = true;
+    let mut sources = Vec::new();
+    for path in &args.files {
+        match File::open(path) {
+            Ok(file) => {
+                let name = path.to_string_lossy().into_owned();
+                sources.push(InputSource::new(Some(name), BufReader::new(file)));
+            }
+            Err(e) => {
+                eprintln!("jq-clone: error: Could not open {}: {e}", path.display());
+                all_opened = false;
+            }
+        }
+    }
+
+    (Inputs::new(sources), all_opened)
+}
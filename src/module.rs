@@ -0,0 +1,578 @@
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context as _};
+use serde_json::{Map, Value};
+use winnow::Parser;
+
+use crate::error::SyntaxError;
+use crate::parser::parse_module;
+use crate::token::{self, Context, Definition, Function, Token};
+
+/// Where `import` and `include` look for modules.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchPath {
+    /// Directories searched after the one of the importing file, like the
+    /// ones given with `-L`. A leading `~` stands for the home directory,
+    /// `$ORIGIN` for the directory of the executable and `.` for the
+    /// directory of the importing file.
+    pub directories: Vec<PathBuf>,
+    /// Directory of the main program, the current directory when unset.
+    pub program_directory: Option<PathBuf>,
+    /// File whose functions are defined for every program, like jq's `~/.jq`.
+    pub prelude: Option<PathBuf>,
+}
+
+impl Default for SearchPath {
+    fn default() -> Self {
+        Self {
+            directories: ["~/.jq", "$ORIGIN/../lib/jq", "$ORIGIN/../lib"]
+                .map(PathBuf::from)
+                .to_vec(),
+            program_directory: None,
+            prelude: None,
+        }
+    }
+}
+
+/// An `import` or `include` directive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Import {
+    /// Path of the file relative to the search path, without its extension.
+    pub path: String,
+    pub kind: ImportKind,
+    /// The object following the path, like `{search: "./lib"}`.
+    pub metadata: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImportKind {
+    /// `include "path";` defines the functions of `path.jq` as they are.
+    Include,
+    /// `import "path" as name;` defines them as `name::function`.
+    Module(String),
+    /// `import "path" as $name;` binds the JSON texts in `path.json`, as an
+    /// array, to `$name` and `$name::name`.
+    Data(String),
+}
+
+/// The main program: the modules it imports and the filter itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    pub imports: Vec<Import>,
+    pub tokens: Vec<Token>,
+}
+
+/// A file of function definitions that can be imported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Module {
+    /// The object given with the `module` directive.
+    pub metadata: Map<String, Value>,
+    pub imports: Vec<Import>,
+    pub definitions: Vec<Arc<Definition>>,
+}
+
+/// An import along with the file it was found in.
+type Resolved = (Import, PathBuf);
+
+/// Every file a program depends on, read and parsed up front so that
+/// linking it can't fail on a missing or invalid file.
+#[derive(Debug, Default)]
+pub struct Library {
+    /// Each module, with the files of its own imports.
+    modules: HashMap<PathBuf, (Module, Vec<Resolved>)>,
+    /// Contents of each data file.
+    data: HashMap<PathBuf, Value>,
+    /// What the main program imports, starting with the prelude.
+    imports: Vec<Resolved>,
+}
+
+impl Library {
+    /// Reads every module the program imports, directly or not.
+    pub fn load(program: &Program, search_path: &SearchPath) -> anyhow::Result<Self> {
+        let mut library = Self::default();
+
+        if let Some(prelude) = &search_path.prelude {
+            library.load_module(prelude, search_path)?;
+            let import = Import {
+                path: prelude.to_string_lossy().into_owned(),
+                kind: ImportKind::Include,
+                metadata: Map::new(),
+            };
+            library.imports.push((import, prelude.clone()));
+        }
+
+        let directory = program_directory(search_path);
+        for import in &program.imports {
+            let path = library.load_import(import, &directory, search_path)?;
+            library.imports.push((import.clone(), path));
+        }
+
+        Ok(library)
+    }
+
+    fn load_import(
+        &mut self,
+        import: &Import,
+        directory: &Path,
+        search_path: &SearchPath,
+    ) -> anyhow::Result<PathBuf> {
+        let path = resolve(import, directory, search_path)?;
+
+        match import.kind {
+            ImportKind::Data(_) if !self.data.contains_key(&path) => {
+                let data = read_data(&path)?;
+                self.data.insert(path.clone(), data);
+            }
+            ImportKind::Data(_) => {}
+            ImportKind::Include | ImportKind::Module(_) => self.load_module(&path, search_path)?,
+        }
+
+        Ok(path)
+    }
+
+    fn load_module(&mut self, path: &Path, search_path: &SearchPath) -> anyhow::Result<()> {
+        if self.modules.contains_key(path) {
+            return Ok(());
+        }
+
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Could not open {}", path.display()))?;
+        let module = parse_module_text(&text, path)?;
+        let imports = module.imports.clone();
+
+        // Added before its imports are loaded so that circular ones end here.
+        self.modules.insert(path.to_owned(), (module, Vec::new()));
+
+        let directory = path.parent().unwrap_or(Path::new("."));
+        let mut resolved = Vec::new();
+        for import in imports {
+            let path = self.load_import(&import, directory, search_path)?;
+            resolved.push((import, path));
+        }
+        if let Some((_, imports)) = self.modules.get_mut(path) {
+            *imports = resolved;
+        }
+
+        Ok(())
+    }
+
+    /// Defines what the program imports in the context it runs in.
+    pub fn link(&self, context: Context) -> anyhow::Result<Context> {
+        let mut linker = Linker {
+            library: self,
+            base: context.clone(),
+            exports: HashMap::new(),
+        };
+        linker.import(context, &self.imports)
+    }
+}
+
+struct Linker<'a> {
+    library: &'a Library,
+    /// The context modules are defined in, which doesn't see anything the
+    /// program defines.
+    base: Context,
+    /// Functions defined by each module, `None` while they're being defined.
+    exports: HashMap<&'a Path, Option<Rc<Vec<Function>>>>,
+}
+
+impl<'a> Linker<'a> {
+    fn import(&mut self, mut context: Context, imports: &'a [Resolved]) -> anyhow::Result<Context> {
+        for (import, path) in imports {
+            context = match &import.kind {
+                ImportKind::Data(name) => {
+                    let data = &self.library.data[path];
+                    context
+                        .with_variable(&format!("{name}::{name}"), data.clone())
+                        .with_variable(name, data.clone())
+                }
+                ImportKind::Include => {
+                    self.exports(path)?
+                        .iter()
+                        .fold(context, |context, function| {
+                            context.with_function(function.name().to_owned(), function.clone())
+                        })
+                }
+                ImportKind::Module(alias) => {
+                    self.exports(path)?
+                        .iter()
+                        .fold(context, |context, function| {
+                            let name = format!("{alias}::{}", function.name());
+                            context.with_function(name, function.clone())
+                        })
+                }
+            };
+        }
+
+        Ok(context)
+    }
+
+    fn exports(&mut self, path: &'a Path) -> anyhow::Result<Rc<Vec<Function>>> {
+        match self.exports.get(path) {
+            Some(Some(functions)) => return Ok(functions.clone()),
+            Some(None) => bail!("{} imports itself", path.display()),
+            None => {}
+        }
+        self.exports.insert(path, None);
+
+        let (module, imports) = &self.library.modules[path];
+        let mut context = self.import(self.base.clone(), imports)?;
+
+        // Like in a program, each definition sees the ones before it.
+        let mut functions = Vec::new();
+        for definition in &module.definitions {
+            token::check_definition(definition, &context)?;
+            let function = Function::new(definition.clone(), context.clone());
+            context = context.with_function(function.name().to_owned(), function.clone());
+            functions.push(function);
+        }
+
+        let functions = Rc::new(functions);
+        self.exports.insert(path, Some(functions.clone()));
+        Ok(functions)
+    }
+}
+
+fn parse_module_text(text: &str, path: &Path) -> anyhow::Result<Module> {
+    parse_module
+        .parse(text)
+        .map_err(|e| SyntaxError::new(text, &e, Some(path)).into())
+}
+
+/// Reads every JSON text in a data file into an array.
+fn read_data(path: &Path) -> anyhow::Result<Value> {
+    let text =
+        fs::read_to_string(path).with_context(|| format!("Could not open {}", path.display()))?;
+    serde_json::Deserializer::from_str(&text)
+        .into_iter()
+        .collect::<Result<Vec<Value>, _>>()
+        .map(Value::Array)
+        .with_context(|| format!("Failed to parse JSON in {}", path.display()))
+}
+
+fn program_directory(search_path: &SearchPath) -> PathBuf {
+    search_path
+        .program_directory
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("."))
+}
+
+/// Finds the file an import refers to, `path.jq` or `path/name.jq` where
+/// `name` is the last component of the path (`.json` for data).
+///
+/// It's looked for in the directories of the import's `search` metadata,
+/// relative to `directory` which is the one of the importing file, then in
+/// `directory` itself and finally in the search path.
+fn resolve(import: &Import, directory: &Path, search_path: &SearchPath) -> anyhow::Result<PathBuf> {
+    let not_found = || anyhow!("module not found: {}", import.path);
+
+    let relative = Path::new(&import.path);
+    let name = relative.file_name().ok_or_else(not_found)?;
+    let extension = match import.kind {
+        ImportKind::Data(_) => ".json",
+        ImportKind::Include | ImportKind::Module(_) => ".jq",
+    };
+
+    let searched = match import.metadata.get("search") {
+        Some(Value::String(path)) => vec![path.as_str()],
+        Some(Value::Array(paths)) => paths.iter().filter_map(Value::as_str).collect(),
+        _ => Vec::new(),
+    };
+    let directories = searched
+        .into_iter()
+        .filter_map(|path| expand(Path::new(path), directory))
+        .map(|path| directory.join(path))
+        .chain([directory.to_owned()])
+        .chain(
+            search_path
+                .directories
+                .iter()
+                .filter_map(|path| expand(path, directory)),
+        );
+
+    for searched in directories {
+        let base = searched.join(relative);
+        for candidate in [base.clone(), base.join(name)] {
+            let mut file = OsString::from(candidate);
+            file.push(extension);
+            let file = PathBuf::from(file);
+            if file.is_file() {
+                return Ok(file);
+            }
+        }
+    }
+
+    Err(not_found())
+}
+
+/// Replaces a leading `~` with the home directory, `$ORIGIN` with the
+/// directory of the executable and `.` with `directory`.
+fn expand(path: &Path, directory: &Path) -> Option<PathBuf> {
+    let mut components = path.components();
+    let base = match components.next()? {
+        Component::CurDir => directory.to_owned(),
+        Component::Normal(first) if first == "~" => PathBuf::from(env::var_os("HOME")?),
+        Component::Normal(first) if first == "$ORIGIN" => {
+            env::current_exe().ok()?.parent()?.to_owned()
+        }
+        _ => return Some(path.to_owned()),
+    };
+    Some(base.join(components.as_path()))
+}
+
+/// Describes a module for `modulemeta`: its metadata along with what it
+/// imports as `deps` and the functions it defines as `defs`.
+pub fn metadata(input: &Value, search_path: &SearchPath) -> anyhow::Result<Value> {
+    let Value::String(name) = input else {
+        bail!("modulemeta input module not a string");
+    };
+
+    let import = Import {
+        path: name.clone(),
+        kind: ImportKind::Include,
+        metadata: Map::new(),
+    };
+    let path = resolve(&import, &program_directory(search_path), search_path)?;
+    let text =
+        fs::read_to_string(&path).with_context(|| format!("Could not open {}", path.display()))?;
+    let module = parse_module_text(&text, &path)?;
+
+    let deps = module
+        .imports
+        .into_iter()
+        .map(|import| {
+            let is_data = matches!(import.kind, ImportKind::Data(_));
+            let mut dep = import.metadata;
+            match import.kind {
+                ImportKind::Include => {}
+                ImportKind::Module(alias) | ImportKind::Data(alias) => {
+                    dep.insert("as".to_owned(), Value::String(alias));
+                }
+            }
+            dep.insert("is_data".to_owned(), Value::Bool(is_data));
+            dep.insert("relpath".to_owned(), Value::String(import.path));
+            Value::Object(dep)
+        })
+        .collect();
+    let defs = module
+        .definitions
+        .iter()
+        .map(|definition| {
+            let name = format!("{}/{}", definition.name, definition.params.len());
+            Value::String(name)
+        })
+        .collect();
+
+    let mut metadata = module.metadata;
+    metadata.insert("deps".to_owned(), Value::Array(deps));
+    metadata.insert("defs".to_owned(), Value::Array(defs));
+    Ok(Value::Object(metadata))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::error::Undefined;
+    use crate::parser::parse_program;
+    use crate::token::{apply_tokens_with, Scope, Shared};
+
+    /// A directory that's removed along with everything in it when dropped.
+    struct TempDir(PathBuf);
+
+    impl std::ops::Deref for TempDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Writes the files in a fresh directory of its own and returns it.
+    fn write_files(name: &str, files: &[(&str, &str)]) -> TempDir {
+        let directory = env::temp_dir().join(format!("jq-clone-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        for (path, text) in files {
+            let path = directory.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, text).unwrap();
+        }
+        TempDir(directory)
+    }
+
+    fn search_path(directory: &Path) -> SearchPath {
+        SearchPath {
+            directories: vec![directory.join("lib")],
+            program_directory: Some(directory.to_owned()),
+            prelude: None,
+        }
+    }
+
+    fn run(filter: &str, search_path: &SearchPath) -> anyhow::Result<Vec<Value>> {
+        let mut remaining = filter;
+        let program = parse_program.parse_next(&mut remaining).unwrap();
+        assert!(remaining.is_empty());
+        let library = Library::load(&program, search_path)?;
+        let context = library.link(Context::default())?;
+        let scope = Scope::new(&context);
+        apply_tokens_with(&scope, Shared::from(Value::Null), &program.tokens)
+            .map(|value| value.map(Shared::into_owned))
+            .collect()
+    }
+
+    #[test]
+    fn resolve_module_files() {
+        let directory = write_files(
+            "resolve",
+            &[
+                ("lib/a.jq", ""),
+                ("lib/b/b.jq", ""),
+                ("lib/data.json", "1"),
+                ("local.jq", ""),
+                ("other/c.jq", ""),
+            ],
+        );
+        let search_path = search_path(&directory);
+        let import = |path: &str, kind, metadata: Value| Import {
+            path: path.to_owned(),
+            kind,
+            metadata: metadata.as_object().unwrap().clone(),
+        };
+        let resolve = |import| resolve(&import, &directory, &search_path);
+
+        let a = import("a", ImportKind::Include, json!({}));
+        assert_eq!(resolve(a).unwrap(), directory.join("lib/a.jq"));
+        let b = import("b", ImportKind::Include, json!({}));
+        assert_eq!(resolve(b).unwrap(), directory.join("lib/b/b.jq"));
+        let data = import("data", ImportKind::Data("d".to_owned()), json!({}));
+        assert_eq!(resolve(data).unwrap(), directory.join("lib/data.json"));
+        let local = import("local", ImportKind::Include, json!({}));
+        assert_eq!(resolve(local).unwrap(), directory.join("local.jq"));
+        let c = import("c", ImportKind::Include, json!({"search": "./other"}));
+        assert_eq!(resolve(c).unwrap(), directory.join("./other/c.jq"));
+
+        let missing = import("c", ImportKind::Include, json!({}));
+        assert_eq!(
+            resolve(missing).unwrap_err().to_string(),
+            "module not found: c"
+        );
+    }
+
+    #[test]
+    fn import_and_include_modules() {
+        let directory = write_files(
+            "import",
+            &[
+                (
+                    "lib/math.jq",
+                    "import \"util\" as util {search: \"./\"};\n\
+                     def double: . * 2;\n\
+                     def quadruple: double | double;\n\
+                     def add($n): util::plus($n);\n",
+                ),
+                ("lib/util.jq", "def plus($n): . + $n;\n"),
+                ("lib/numbers.json", "1 2"),
+            ],
+        );
+        let search_path = search_path(&directory);
+
+        let values = run(
+            "import \"math\" as math; import \"numbers\" as $numbers;\n\
+             $numbers::numbers, $numbers",
+            &search_path,
+        )
+        .unwrap();
+        assert_eq!(values, vec![json!([1, 2]), json!([1, 2])]);
+
+        let values = run(
+            "import \"math\" as math; 2 | math::quadruple, math::add(3)",
+            &search_path,
+        )
+        .unwrap();
+        assert_eq!(values, vec![json!(8), json!(5)]);
+
+        let values = run("include \"math\"; 3 | double", &search_path).unwrap();
+        assert_eq!(values, vec![json!(6)]);
+
+        // Functions imported by a module aren't visible to the program.
+        assert!(run("import \"math\" as math; 1 | util::plus(1)", &search_path).is_err());
+        assert!(run("import \"missing\" as m; 1", &search_path).is_err());
+    }
+
+    #[test]
+    fn undefined_functions_in_modules_fail_to_link() {
+        let directory = write_files(
+            "undefined",
+            &[(
+                "lib/broken.jq",
+                "def ok: 1;\ndef broken: ok | missing(1);\n",
+            )],
+        );
+
+        let error = run("include \"broken\"; ok", &search_path(&directory)).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<Undefined>(),
+            Some(&Undefined::Function {
+                name: "missing".to_owned(),
+                arity: 1,
+            })
+        );
+    }
+
+    #[test]
+    fn prelude_is_included() {
+        let directory = write_files("prelude", &[("prelude.jq", "def greet: \"hi\";\n")]);
+        let search_path = SearchPath {
+            prelude: Some(directory.join("prelude.jq")),
+            ..search_path(&directory)
+        };
+
+        assert_eq!(run("greet", &search_path).unwrap(), vec![json!("hi")]);
+    }
+
+    #[test]
+    fn describe_module() {
+        let directory = write_files(
+            "modulemeta",
+            &[(
+                "lib/meta.jq",
+                "module {name: \"meta\", version: 1};\n\
+                 import \"other\" as other {search: \"./\"};\n\
+                 import \"data\" as $data;\n\
+                 include \"more\";\n\
+                 def f: .;\n\
+                 def g(a; $b): .;\n",
+            )],
+        );
+
+        let described = metadata(&json!("meta"), &search_path(&directory)).unwrap();
+        assert_eq!(
+            described,
+            json!({
+                "name": "meta",
+                "version": 1,
+                "deps": [
+                    {"search": "./", "as": "other", "is_data": false, "relpath": "other"},
+                    {"as": "data", "is_data": true, "relpath": "data"},
+                    {"is_data": false, "relpath": "more"},
+                ],
+                "defs": ["f/0", "g/2"],
+            })
+        );
+
+        assert!(metadata(&json!(1), &search_path(&directory)).is_err());
+    }
+}
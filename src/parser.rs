@@ -1,34 +1,482 @@
-use winnow::ascii::digit1;
-use winnow::combinator::{alt, delimited, dispatch, fail, repeat, terminated};
-use winnow::token::{any, take_till};
+use std::sync::Arc;
+
+use serde_json::{Map, Value};
+use winnow::ascii::{digit1, multispace1};
+use winnow::combinator::{
+    alt, cut_err, delimited, dispatch, fail, not, opt, peek, preceded, repeat, separated,
+    separated_pair, terminated,
+};
+use winnow::error::{ContextError, ErrMode, StrContext, StrContextValue};
+use winnow::stream::Stream;
+use winnow::token::{any, none_of, one_of, take_till, take_while};
 use winnow::{PResult, Parser};
 
-use crate::token::Token;
+use crate::module::{Import, ImportKind, Module, Program};
+use crate::token::{number_to_value, Definition, Operator, Parameter, Token};
+
+/// Parses a whole program: the modules it imports followed by its filter.
+pub fn parse_program(input: &mut &str) -> PResult<Program> {
+    (
+        preceded(ws, opt(parse_module_directive)),
+        repeat(0.., parse_import),
+        delimited(ws, parse_pipeline, ws),
+    )
+        .map(|(_, imports, tokens)| Program { imports, tokens })
+        .parse_next(input)
+}
+
+/// Parses a file that can be imported: the functions it defines, after its
+/// metadata and the modules it imports itself.
+pub fn parse_module(input: &mut &str) -> PResult<Module> {
+    (
+        preceded(ws, opt(parse_module_directive)),
+        repeat(0.., parse_import),
+        repeat(0.., delimited(ws, parse_definition, ws).map(Arc::new)),
+    )
+        .map(|(metadata, imports, definitions)| Module {
+            metadata: metadata.unwrap_or_default(),
+            imports,
+            definitions,
+        })
+        .parse_next(input)
+}
+
+/// Parses `module {metadata};`.
+fn parse_module_directive(input: &mut &str) -> PResult<Map<String, Value>> {
+    preceded(
+        (parse_keyword("module"), ws),
+        cut_err(terminated(
+            parse_metadata.context(described("an object")),
+            (ws, ';'.context(expected(';')), ws),
+        )),
+    )
+    .parse_next(input)
+}
+
+/// Parses `import "path" as name;`, `import "path" as $name;` or
+/// `include "path";`, each optionally followed by metadata.
+fn parse_import(input: &mut &str) -> PResult<Import> {
+    let path = || {
+        preceded(
+            peek('"').context(described("a module path")),
+            parse_string_literal,
+        )
+    };
+    let alias = alt((
+        preceded('$', parse_identifier).map(|name| ImportKind::Data(name.to_owned())),
+        parse_identifier.map(|name| ImportKind::Module(name.to_owned())),
+    ))
+    .context(described("a name"));
+    let directive = alt((
+        preceded(
+            (parse_keyword("import"), ws),
+            cut_err((
+                path(),
+                preceded((ws, parse_keyword("as").context(expected("as")), ws), alias),
+            )),
+        ),
+        preceded((parse_keyword("include"), ws), cut_err(path()))
+            .map(|path| (path, ImportKind::Include)),
+    ));
+
+    (
+        directive,
+        cut_err(terminated(
+            opt(preceded(ws, parse_metadata)),
+            (ws, ';'.context(expected(';')), ws),
+        )),
+    )
+        .map(|((path, kind), metadata)| Import {
+            path,
+            kind,
+            metadata: metadata.unwrap_or_default(),
+        })
+        .parse_next(input)
+}
+
+/// Parses the metadata of a module or an import, a constant object whose
+/// keys don't need quotes like `{search: "./lib"}`.
+fn parse_metadata(input: &mut &str) -> PResult<Map<String, Value>> {
+    let key = alt((parse_identifier.map(str::to_owned), parse_string_literal));
+    delimited(
+        ('{', ws),
+        separated(
+            0..,
+            separated_pair(key, (ws, ':', ws), parse_constant),
+            (ws, ',', ws),
+        ),
+        (ws, '}'),
+    )
+    .map(|entries: Vec<(String, Value)>| entries.into_iter().collect())
+    .parse_next(input)
+}
+
+fn parse_constant(input: &mut &str) -> PResult<Value> {
+    alt((
+        parse_number,
+        parse_string,
+        parse_metadata.map(Value::Object),
+        delimited(
+            ('[', ws),
+            separated(0.., parse_constant, (ws, ',', ws)),
+            (ws, ']'),
+        )
+        .map(Value::Array),
+        parse_identifier.verify_map(|word| match word {
+            "null" => Some(Value::Null),
+            "true" => Some(Value::Bool(true)),
+            "false" => Some(Value::Bool(false)),
+            _ => None,
+        }),
+    ))
+    .parse_next(input)
+}
+
+/// Parses `def name(params): body;`, where the parameters are optional.
+fn parse_definition(input: &mut &str) -> PResult<Definition> {
+    let parameter = alt((
+        preceded('$', parse_identifier).map(|name| Parameter::Value(name.into())),
+        parse_identifier.map(|name| Parameter::Filter(name.into())),
+    ))
+    .context(described("a parameter"));
+    let parameters = delimited(
+        ('(', ws),
+        separated(1.., parameter, (ws, ';', ws)),
+        (ws, ')'.context(expected(')')).context(expected(';'))),
+    );
+
+    preceded(
+        (parse_keyword("def"), ws),
+        cut_err((
+            parse_identifier.context(described("a function name")),
+            opt(preceded(ws, parameters)),
+            delimited(
+                (ws, ':'.context(expected(':')), ws),
+                parse_pipeline,
+                (ws, ';'.context(expected(';'))),
+            ),
+        )),
+    )
+    .map(|(name, params, body)| Definition {
+        name: name.into(),
+        params: params.unwrap_or_default(),
+        body,
+    })
+    .parse_next(input)
+}
+
+fn parse_keyword<'a>(keyword: &'static str) -> impl Parser<&'a str, &'a str, ContextError> {
+    parse_identifier.verify(move |word: &str| word == keyword)
+}
+
+/// What was expected when a literal token is missing, like `]`.
+fn expected(value: impl Into<StrContextValue>) -> StrContext {
+    StrContext::Expected(value.into())
+}
+
+/// What was expected when something more elaborate is missing, like a
+/// filter.
+fn described(description: &'static str) -> StrContext {
+    StrContext::Expected(StrContextValue::Description(description))
+}
+
+/// Parses a whole filter: chains of tokens separated by pipes.
+///
+/// Piping into the next chain is the same as applying its tokens right after
+/// the previous ones, so the chains are flattened into a single list. A
+/// binding (`source as $name | body`) takes the rest of the pipeline as its
+/// body, so it's always the last token of the list.
+pub fn parse_pipeline(input: &mut &str) -> PResult<Vec<Token>> {
+    opt(parse_piped)
+        .map(Option::unwrap_or_default)
+        .parse_next(input)
+}
+
+fn parse_piped(input: &mut &str) -> PResult<Vec<Token>> {
+    if let Some(definition) = opt(terminated(parse_definition, ws)).parse_next(input)? {
+        let rest = parse_piped(input)?;
+        return Ok(vec![Token::Define(Arc::new(definition), rest)]);
+    }
+
+    let mut tokens = parse_comma.parse_next(input)?;
+
+    let binding = preceded((ws, parse_keyword("as"), ws), cut_err(parse_variable));
+    if let Some(name) = opt(binding).parse_next(input)? {
+        let body = preceded((ws, '|'.context(expected('|')), ws), parse_piped);
+        let body = cut_err(body).parse_next(input)?;
+        return Ok(vec![Token::Bind(tokens, name, body)]);
+    }
+
+    let rest = opt(preceded((ws, '|', ws), cut_err(parse_piped))).parse_next(input)?;
+    tokens.extend(rest.into_iter().flatten());
+    Ok(tokens)
+}
+
+fn parse_comma(input: &mut &str) -> PResult<Vec<Token>> {
+    let first = parse_additive.parse_next(input)?;
+    let mut branches: Vec<_> =
+        repeat(0.., preceded((ws, ',', ws), cut_err(parse_additive))).parse_next(input)?;
+
+    if branches.is_empty() {
+        return Ok(first);
+    }
+    branches.insert(0, first);
+    Ok(vec![Token::Comma(branches)])
+}
+
+fn parse_additive(input: &mut &str) -> PResult<Vec<Token>> {
+    let operator = alt(('+'.value(Operator::Add), '-'.value(Operator::Subtract)));
+    parse_operations(operator, parse_multiplicative).parse_next(input)
+}
+
+fn parse_multiplicative(input: &mut &str) -> PResult<Vec<Token>> {
+    let operator = alt((
+        '*'.value(Operator::Multiply),
+        terminated('/', not('/')).value(Operator::Divide),
+        '%'.value(Operator::Modulo),
+    ));
+    parse_operations(operator, parse_negation).parse_next(input)
+}
+
+/// Parses an operand that may be negated, like `-1` or `-.a`, which like in
+/// jq pipes it into `_negate`.
+fn parse_negation(input: &mut &str) -> PResult<Vec<Token>> {
+    let negated = preceded(('-', ws), cut_err(parse_negation)).map(|mut tokens| {
+        tokens.push(Token::Function("_negate".into(), Vec::new()));
+        tokens
+    });
+    alt((negated, parse_chain)).parse_next(input)
+}
+
+/// Parses operands separated by left-associative operators of the same
+/// precedence, e.g. `1 - 2 + 3` as `(1 - 2) + 3`.
+fn parse_operations<'a>(
+    mut operator: impl Parser<&'a str, Operator, ContextError>,
+    mut operand: impl Parser<&'a str, Vec<Token>, ContextError>,
+) -> impl Parser<&'a str, Vec<Token>, ContextError> {
+    move |input: &mut &'a str| {
+        let mut lhs = operand.parse_next(input)?;
+
+        loop {
+            let checkpoint = input.checkpoint();
+            let next = (
+                delimited(ws, terminated(operator.by_ref(), not('=')), ws),
+                cut_err(operand.by_ref()),
+            )
+                .parse_next(input);
+
+            match next {
+                Ok((operator, rhs)) => lhs = vec![Token::Operation(operator, lhs, rhs)],
+                Err(ErrMode::Backtrack(_)) => {
+                    input.reset(&checkpoint);
+                    return Ok(lhs);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Parses a term followed by the suffixes that index into it, like
+/// `.a[0] .b`.
+fn parse_chain(input: &mut &str) -> PResult<Vec<Token>> {
+    let first = parse_token.parse_next(input)?;
+    let rest: Vec<_> = repeat(0.., preceded(ws, parse_suffix)).parse_next(input)?;
+
+    let mut tokens = vec![first];
+    tokens.extend(rest);
+    Ok(tokens)
+}
+
+/// Skips whitespace and comments, which can appear between any two tokens.
+pub fn ws(input: &mut &str) -> PResult<()> {
+    repeat(0.., alt((multispace1.void(), parse_comment))).parse_next(input)
+}
+
+/// Parses a comment up to the end of the line. Like in jq 1.7, a line ending
+/// with an odd number of backslashes continues the comment on the next one.
+fn parse_comment(input: &mut &str) -> PResult<()> {
+    (
+        '#',
+        repeat::<_, _, (), _, _>(
+            0..,
+            alt((
+                "\\\r\n".void(),
+                preceded('\\', any).void(),
+                '\\'.void(),
+                none_of(['\n', '\\']).void(),
+            )),
+        ),
+    )
+        .void()
+        .parse_next(input)
+}
 
-pub fn parse_token<'a>(input: &mut &'a str) -> PResult<Token<'a>> {
-    dispatch! {any;
-        '.' => alt((
+pub fn parse_token(input: &mut &str) -> PResult<Token> {
+    dispatch! {peek(any).context(described("a filter"));
+        '.' => preceded('.', alt((
             parse_key_token,
-            "[]".value(Token::Iterate),
+            preceded('[', cut_err(parse_brackets)),
             "".value(Token::Identity)
+        ))),
+        '[' => preceded('[', parse_array_wrapper).map(Token::Array),
+        '0'..='9' => parse_number.map(Token::Literal),
+        '"' => parse_string.map(Token::Literal),
+        '@' => preceded('@', cut_err(parse_identifier.context(described("a format name"))))
+            .map(|name| Token::Format(name.into())),
+        '$' => parse_variable.map(Token::Variable),
+        c if c.is_ascii_alphabetic() || c == '_' => parse_function_token,
+        _ => fail.context(described("a filter"))
+    }
+    .parse_next(input)
+}
+
+/// Parses what can follow a term, even after whitespace, to index into it.
+/// Other terms can't follow it, so `1 2` is an error like in jq, and a
+/// bracket after a term always indexes it: `[1] [0]` is `1`.
+fn parse_suffix(input: &mut &str) -> PResult<Token> {
+    dispatch! {peek(any);
+        '.' => parse_token,
+        '[' => preceded('[', cut_err(parse_brackets)),
+        _ => fail,
+    }
+    .parse_next(input)
+}
+
+/// Parses a number literal. Like in jq, one too large for a float is the
+/// largest float rather than an error.
+fn parse_number(input: &mut &str) -> PResult<Value> {
+    (
+        digit1,
+        opt(('.', digit1)),
+        opt((one_of(['e', 'E']), opt(one_of(['+', '-'])), digit1)),
+    )
+        .recognize()
+        .try_map(|number: &str| match serde_json::from_str(number) {
+            Ok(value) => Ok(value),
+            Err(_) => number.parse().map(number_to_value),
+        })
+        .parse_next(input)
+}
+
+fn parse_string(input: &mut &str) -> PResult<Value> {
+    let contents = repeat::<_, _, (), _, _>(0.., alt((preceded('\\', any), none_of(['"', '\\']))));
+    ('"', cut_err((contents, '"'.context(expected('"')))))
+        .recognize()
+        .try_map(serde_json::from_str)
+        .parse_next(input)
+}
+
+fn parse_string_literal(input: &mut &str) -> PResult<String> {
+    parse_string
+        .verify_map(|value| match value {
+            Value::String(string) => Some(string),
+            _ => None,
+        })
+        .parse_next(input)
+}
+
+fn parse_identifier<'a>(input: &mut &'a str) -> PResult<&'a str> {
+    (
+        one_of(|c: char| c.is_ascii_alphabetic() || c == '_'),
+        take_while(0.., |c: char| c.is_ascii_alphanumeric() || c == '_'),
+    )
+        .recognize()
+        .parse_next(input)
+}
+
+/// Parses a name that may be prefixed by the module it comes from, like
+/// `lib::name`.
+fn parse_qualified_identifier<'a>(input: &mut &'a str) -> PResult<&'a str> {
+    (parse_identifier, opt(("::", parse_identifier)))
+        .recognize()
+        .parse_next(input)
+}
+
+fn parse_variable(input: &mut &str) -> PResult<Arc<str>> {
+    preceded(
+        '$'.context(expected('$')),
+        cut_err(parse_qualified_identifier.context(described("a variable name"))),
+    )
+    .map(Arc::from)
+    .parse_next(input)
+}
+
+fn parse_function_arguments(input: &mut &str) -> PResult<Vec<Vec<Token>>> {
+    preceded(
+        ('(', ws),
+        cut_err(terminated(
+            separated(1.., parse_pipeline, (ws, ';', ws)),
+            (ws, ')'.context(expected(')')).context(expected(';'))),
         )),
-        '[' => alt((
-            parse_index_token,
-            parse_key_string_token,
-            parse_array_wrapper.map(Token::Array),
-        )),
-        _ => fail
+    )
+    .parse_next(input)
+}
+
+fn parse_function_token(input: &mut &str) -> PResult<Token> {
+    let name = parse_qualified_identifier
+        .verify(|name| !KEYWORDS.contains(name))
+        .context(described("a filter"))
+        .parse_next(input)?;
+
+    if name == "reduce" {
+        return parse_reduce(input);
     }
+
+    opt(parse_function_arguments)
+        .map(|arguments| match (name, arguments) {
+            ("null", None) => Token::Literal(Value::Null),
+            ("true", None) => Token::Literal(Value::Bool(true)),
+            ("false", None) => Token::Literal(Value::Bool(false)),
+            (name, arguments) => Token::Function(name.into(), arguments.unwrap_or_default()),
+        })
+        .parse_next(input)
+}
+
+/// Words that can't be used as function names.
+pub(crate) const KEYWORDS: &[&str] = &[
+    "as", "def", "if", "then", "elif", "else", "end", "and", "or", "foreach", "try", "catch",
+    "label", "import", "include", "module", "__loc__",
+];
+
+/// Parses what follows the `reduce` keyword: `source as $name (init; update)`.
+fn parse_reduce(input: &mut &str) -> PResult<Token> {
+    cut_err((
+        preceded(ws, parse_chain),
+        preceded(
+            (ws, parse_keyword("as").context(expected("as")), ws),
+            parse_variable,
+        ),
+        preceded(
+            (ws, '('.context(expected('(')), ws),
+            terminated(parse_pipeline, (ws, ';'.context(expected(';')), ws)),
+        ),
+        terminated(parse_pipeline, (ws, ')'.context(expected(')')))),
+    ))
+    .map(|(source, name, init, update)| Token::Reduce(source, name, init, update))
+    .parse_next(input)
+}
+
+/// Parses what follows the bracket in `.[` or after a term, which can only
+/// index or iterate: `.[-1]` must not be read as `.` followed by the array
+/// `[-1]`.
+fn parse_brackets(input: &mut &str) -> PResult<Token> {
+    alt((
+        (ws, ']').value(Token::Iterate),
+        parse_index_token,
+        parse_key_string_token,
+    ))
+    .context(described("an index"))
     .parse_next(input)
 }
 
 fn parse_index(input: &mut &str) -> PResult<usize> {
-    terminated(digit1, ']')
+    delimited(ws, digit1, (ws, ']'))
         .try_map(str::parse)
         .parse_next(input)
 }
 
-fn parse_index_token<'a>(input: &mut &'a str) -> PResult<Token<'a>> {
+fn parse_index_token(input: &mut &str) -> PResult<Token> {
     alt((
         terminated(terminated(parse_index, "[]"), '?').map(Token::IterateOptionalIndex),
         terminated(parse_index, "[]").map(Token::IterateIndex),
@@ -38,11 +486,13 @@ fn parse_index_token<'a>(input: &mut &'a str) -> PResult<Token<'a>> {
     .parse_next(input)
 }
 
-fn parse_key_string<'a>(input: &mut &'a str) -> PResult<&'a str> {
-    terminated(delimited('"', parse_key, '"'), ']').parse_next(input)
+fn parse_key_string(input: &mut &str) -> PResult<Arc<str>> {
+    delimited((ws, '"'), take_till(1.., '"'), ('"', ws, ']'))
+        .map(Arc::from)
+        .parse_next(input)
 }
 
-fn parse_key_string_token<'a>(input: &mut &'a str) -> PResult<Token<'a>> {
+fn parse_key_string_token(input: &mut &str) -> PResult<Token> {
     alt((
         terminated(terminated(parse_key_string, "[]"), '?').map(Token::IterateOptionalKey),
         terminated(parse_key_string, "[]").map(Token::IterateKey),
@@ -52,13 +502,23 @@ fn parse_key_string_token<'a>(input: &mut &'a str) -> PResult<Token<'a>> {
     .parse_next(input)
 }
 
-fn parse_key<'a>(input: &mut &'a str) -> PResult<&'a str> {
-    take_till(1.., ['.', '[', ']', '"', '?'])
-        .recognize()
-        .parse_next(input)
+fn parse_key(input: &mut &str) -> PResult<Arc<str>> {
+    take_till(1.., |c: char| {
+        matches!(
+            c,
+            '.' | '[' | ']' | '"' | '?' | '|' | ';' | ',' | '(' | ')' | '+' | '-' | '*' | '/' | '%'
+        ) || NOT_IN_KEYS.contains(c)
+            || c.is_whitespace()
+    })
+    .map(Arc::from)
+    .parse_next(input)
 }
 
-fn parse_key_token<'a>(input: &mut &'a str) -> PResult<Token<'a>> {
+/// Characters that can't be part of a key written after a dot, but that
+/// don't end one either, so they need the key to be quoted like `.["a:b"]`.
+pub(crate) const NOT_IN_KEYS: &str = "'#:!=<>&{}\\`^~";
+
+fn parse_key_token(input: &mut &str) -> PResult<Token> {
     alt((
         terminated(terminated(parse_key, "[]"), '?').map(Token::IterateOptionalKey),
         terminated(parse_key, "[]").map(Token::IterateKey),
@@ -68,8 +528,12 @@ fn parse_key_token<'a>(input: &mut &'a str) -> PResult<Token<'a>> {
     .parse_next(input)
 }
 
-fn parse_array_wrapper<'a>(input: &mut &'a str) -> PResult<Vec<Token<'a>>> {
-    terminated(repeat(0.., parse_token), ']').parse_next(input)
+fn parse_array_wrapper(input: &mut &str) -> PResult<Vec<Token>> {
+    terminated(
+        delimited(ws, parse_pipeline, ws),
+        cut_err(']'.context(expected(']'))),
+    )
+    .parse_next(input)
 }
 
 #[cfg(test)]
@@ -88,7 +552,7 @@ mod tests {
     #[test]
     fn parse_single_digit_index_token() {
         let mut input = "[1]";
-        let output = parse_token.parse_next(&mut input).unwrap();
+        let output = parse_suffix.parse_next(&mut input).unwrap();
         assert_eq!(output, Token::Index(1));
         assert!(input.is_empty());
     }
@@ -96,7 +560,7 @@ mod tests {
     #[test]
     fn parse_multiple_digit_index_token() {
         let mut input = "[5280]";
-        let output = parse_token.parse_next(&mut input).unwrap();
+        let output = parse_suffix.parse_next(&mut input).unwrap();
         assert_eq!(output, Token::Index(5280));
         assert!(input.is_empty());
     }
@@ -104,7 +568,7 @@ mod tests {
     #[test]
     fn parse_single_digit_optional_index_token() {
         let mut input = "[1]?";
-        let output = parse_token.parse_next(&mut input).unwrap();
+        let output = parse_suffix.parse_next(&mut input).unwrap();
         assert_eq!(output, Token::OptionalIndex(1));
         assert!(input.is_empty());
     }
@@ -112,7 +576,7 @@ mod tests {
     #[test]
     fn parse_multiple_digit_optional_index_token() {
         let mut input = "[5280]?";
-        let output = parse_token.parse_next(&mut input).unwrap();
+        let output = parse_suffix.parse_next(&mut input).unwrap();
         assert_eq!(output, Token::OptionalIndex(5280));
         assert!(input.is_empty());
     }
@@ -120,55 +584,59 @@ mod tests {
     #[test]
     fn parse_key_array_index() {
         let mut input = "[\"key\"]";
-        let output = parse_token.parse_next(&mut input).unwrap();
-        assert_eq!(output, Token::Key("key"));
+        let output = parse_suffix.parse_next(&mut input).unwrap();
+        assert_eq!(output, Token::Key("key".into()));
         assert!(input.is_empty());
     }
 
     #[test]
     fn parse_key_array_index_with_digits() {
         let mut input = "[\"key123\"]";
-        let output = parse_token.parse_next(&mut input).unwrap();
-        assert_eq!(output, Token::Key("key123"));
+        let output = parse_suffix.parse_next(&mut input).unwrap();
+        assert_eq!(output, Token::Key("key123".into()));
         assert!(input.is_empty());
     }
 
     #[test]
     fn parse_key_array_index_with_digits_prefixed() {
         let mut input = "[\"123key\"]";
-        let output = parse_token.parse_next(&mut input).unwrap();
-        assert_eq!(output, Token::Key("123key"));
+        let output = parse_suffix.parse_next(&mut input).unwrap();
+        assert_eq!(output, Token::Key("123key".into()));
         assert!(input.is_empty());
     }
 
     #[test]
     fn parse_optional_key_array_index() {
         let mut input = "[\"key\"]?";
-        let output = parse_token.parse_next(&mut input).unwrap();
-        assert_eq!(output, Token::OptionalKey("key"));
+        let output = parse_suffix.parse_next(&mut input).unwrap();
+        assert_eq!(output, Token::OptionalKey("key".into()));
         assert!(input.is_empty());
     }
 
     #[test]
     fn parse_optional_key_array_index_with_digits() {
         let mut input = "[\"key123\"]?";
-        let output = parse_token.parse_next(&mut input).unwrap();
-        assert_eq!(output, Token::OptionalKey("key123"));
+        let output = parse_suffix.parse_next(&mut input).unwrap();
+        assert_eq!(output, Token::OptionalKey("key123".into()));
         assert!(input.is_empty());
     }
 
     #[test]
     fn array_key_index_without_quotes() {
+        // Without quotes `key` is a call to a function, not an object key
         let mut input = "[key]";
-        let output = parse_token.parse_next(&mut input);
-        assert!(output.is_err());
+        let output = parse_token.parse_next(&mut input).unwrap();
+        assert_eq!(
+            output,
+            Token::Array(vec![Token::Function("key".into(), vec![])])
+        );
     }
 
     #[test]
     fn key_is_one_letter() {
         let mut input = "a";
         let output = parse_key.parse_next(&mut input).unwrap();
-        assert_eq!(output, "a");
+        assert_eq!(output, "a".into());
         assert!(input.is_empty());
     }
 
@@ -176,7 +644,7 @@ mod tests {
     fn key_is_two_letter() {
         let mut input = "ab";
         let output = parse_key.parse_next(&mut input).unwrap();
-        assert_eq!(output, "ab");
+        assert_eq!(output, "ab".into());
         assert!(input.is_empty());
     }
 
@@ -184,7 +652,7 @@ mod tests {
     fn key_is_one_letter_one_digit() {
         let mut input = "a1";
         let output = parse_key.parse_next(&mut input).unwrap();
-        assert_eq!(output, "a1");
+        assert_eq!(output, "a1".into());
         assert!(input.is_empty());
     }
 
@@ -192,7 +660,7 @@ mod tests {
     fn key_is_one_letter_one_underscore() {
         let mut input = "a_";
         let output = parse_key.parse_next(&mut input).unwrap();
-        assert_eq!(output, "a_");
+        assert_eq!(output, "a_".into());
         assert!(input.is_empty());
     }
 
@@ -200,7 +668,7 @@ mod tests {
     fn key_is_one_letter_multiple_digits() {
         let mut input = "a1034803141";
         let output = parse_key.parse_next(&mut input).unwrap();
-        assert_eq!(output, "a1034803141");
+        assert_eq!(output, "a1034803141".into());
         assert!(input.is_empty());
     }
 
@@ -208,7 +676,7 @@ mod tests {
     fn key_is_one_letter_multiple_underscores() {
         let mut input = "a________";
         let output = parse_key.parse_next(&mut input).unwrap();
-        assert_eq!(output, "a________");
+        assert_eq!(output, "a________".into());
         assert!(input.is_empty());
     }
 
@@ -216,7 +684,7 @@ mod tests {
     fn key_is_underscore_separated() {
         let mut input = "a_b_c_1_2";
         let output = parse_key.parse_next(&mut input).unwrap();
-        assert_eq!(output, "a_b_c_1_2");
+        assert_eq!(output, "a_b_c_1_2".into());
         assert!(input.is_empty());
     }
 
@@ -224,7 +692,7 @@ mod tests {
     fn key_is_unicode_characters() {
         let mut input = "🎉🎆✨";
         let output = parse_key.parse_next(&mut input).unwrap();
-        assert_eq!(output, "🎉🎆✨");
+        assert_eq!(output, "🎉🎆✨".into());
         assert!(input.is_empty());
     }
 
@@ -232,7 +700,7 @@ mod tests {
     fn parse_key_dot_notation() {
         let mut input = ".quote";
         let output = parse_token.parse_next(&mut input).unwrap();
-        assert_eq!(output, Token::Key("quote"));
+        assert_eq!(output, Token::Key("quote".into()));
         assert_eq!(input, "");
     }
 
@@ -240,7 +708,7 @@ mod tests {
     fn parse_key_dot_notation_stops_at_dot() {
         let mut input = ".quote.quote";
         let output = parse_token.parse_next(&mut input).unwrap();
-        assert_eq!(output, Token::Key("quote"));
+        assert_eq!(output, Token::Key("quote".into()));
         assert_eq!(input, ".quote");
     }
 
@@ -248,7 +716,7 @@ mod tests {
     fn parse_optional_key_dot_notation() {
         let mut input = ".quote?";
         let output = parse_token.parse_next(&mut input).unwrap();
-        assert_eq!(output, Token::OptionalKey("quote"));
+        assert_eq!(output, Token::OptionalKey("quote".into()));
         assert_eq!(input, "");
     }
 
@@ -257,7 +725,7 @@ mod tests {
         // TODO: This might not be valid syntax
         let mut input = ".quote?[]";
         let output = parse_token.parse_next(&mut input).unwrap();
-        assert_eq!(output, Token::OptionalKey("quote"));
+        assert_eq!(output, Token::OptionalKey("quote".into()));
         assert_eq!(input, "[]");
     }
 
@@ -265,15 +733,31 @@ mod tests {
     fn parse_optional_key_dot_notation_stops_at_dot() {
         let mut input = ".quote?.quote";
         let output = parse_token.parse_next(&mut input).unwrap();
-        assert_eq!(output, Token::OptionalKey("quote"));
+        assert_eq!(output, Token::OptionalKey("quote".into()));
         assert_eq!(input, ".quote");
     }
 
+    #[test]
+    fn dot_bracket_is_always_an_index() {
+        let mut input = ".[1]";
+        let output = parse_token.parse_next(&mut input).unwrap();
+        assert_eq!(output, Token::Index(1));
+        assert!(input.is_empty());
+
+        for filter in [".[-1]", ".[nan]", ".[1,2]"] {
+            let mut input = filter;
+            assert!(
+                matches!(parse_pipeline.parse_next(&mut input), Err(ErrMode::Cut(_))),
+                "{filter} should be a syntax error"
+            );
+        }
+    }
+
     #[test]
     fn parse_iterator_token() {
         let mut input = ".quote[]";
         let output = parse_token.parse_next(&mut input).unwrap();
-        assert_eq!(output, Token::IterateKey("quote"));
+        assert_eq!(output, Token::IterateKey("quote".into()));
         assert!(input.is_empty());
     }
 
@@ -290,7 +774,10 @@ mod tests {
         let mut input = "[.quote]";
         let output = parse_token.parse_next(&mut input);
 
-        assert_eq!(output.unwrap(), Token::Array(vec![Token::Key("quote")]));
+        assert_eq!(
+            output.unwrap(),
+            Token::Array(vec![Token::Key("quote".into())])
+        );
     }
 
     #[test]
@@ -300,7 +787,7 @@ mod tests {
 
         assert_eq!(
             output.unwrap(),
-            Token::Array(vec![Token::IterateKey("quotes")])
+            Token::Array(vec![Token::IterateKey("quotes".into())])
         );
     }
 
@@ -311,4 +798,452 @@ mod tests {
 
         assert_eq!(output.unwrap(), Token::Array(vec![Token::Array(vec![])]));
     }
+
+    #[test]
+    fn parse_number_literal() {
+        let mut input = "42";
+        let output = parse_token.parse_next(&mut input).unwrap();
+        assert_eq!(output, Token::Literal(serde_json::json!(42)));
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn parse_float_literal() {
+        let mut input = "1.5e3";
+        let output = parse_token.parse_next(&mut input).unwrap();
+        assert_eq!(output, Token::Literal(serde_json::json!(1500.0)));
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn parse_out_of_range_literal() {
+        let mut input = "1e1000";
+        let output = parse_token.parse_next(&mut input).unwrap();
+        assert_eq!(output, Token::Literal(serde_json::json!(f64::MAX)));
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn parse_keyword_literals() {
+        let mut input = "null";
+        let output = parse_token.parse_next(&mut input).unwrap();
+        assert_eq!(output, Token::Literal(Value::Null));
+
+        let mut input = "true";
+        let output = parse_token.parse_next(&mut input).unwrap();
+        assert_eq!(output, Token::Literal(Value::Bool(true)));
+    }
+
+    #[test]
+    fn parse_function_without_arguments() {
+        let mut input = "floor";
+        let output = parse_token.parse_next(&mut input).unwrap();
+        assert_eq!(output, Token::Function("floor".into(), vec![]));
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn parse_function_with_arguments() {
+        let mut input = "pow(.base; 2)";
+        let output = parse_token.parse_next(&mut input).unwrap();
+        assert_eq!(
+            output,
+            Token::Function(
+                "pow".into(),
+                vec![
+                    vec![Token::Key("base".into())],
+                    vec![Token::Literal(serde_json::json!(2))]
+                ]
+            )
+        );
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn parse_pipe_between_tokens() {
+        let mut input = ".a | floor";
+        let output = parse_pipeline.parse_next(&mut input).unwrap();
+        assert_eq!(
+            output,
+            vec![
+                Token::Key("a".into()),
+                Token::Function("floor".into(), vec![])
+            ]
+        );
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn parse_pipe_in_array_wrapper() {
+        let mut input = "[.[] | ceil]";
+        let output = parse_token.parse_next(&mut input).unwrap();
+        assert_eq!(
+            output,
+            Token::Array(vec![Token::Iterate, Token::Function("ceil".into(), vec![])])
+        );
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn parse_string_literal() {
+        let mut input = r#""%Y-%m-%d""#;
+        let output = parse_token.parse_next(&mut input).unwrap();
+        assert_eq!(output, Token::Literal(Value::from("%Y-%m-%d")));
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn parse_string_literal_with_escapes() {
+        let mut input = r#""a \"quoted\" \u00e9""#;
+        let output = parse_token.parse_next(&mut input).unwrap();
+        assert_eq!(output, Token::Literal(Value::from("a \"quoted\" é")));
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn parse_unterminated_string_literal() {
+        let mut input = r#""abc"#;
+        assert!(parse_token.parse_next(&mut input).is_err());
+    }
+
+    #[test]
+    fn parse_format_token() {
+        let mut input = "@text";
+        let output = parse_token.parse_next(&mut input).unwrap();
+        assert_eq!(output, Token::Format("text".into()));
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn parse_comma_between_chains() {
+        let mut input = ".a, .b[0]";
+        let output = parse_pipeline.parse_next(&mut input).unwrap();
+        assert_eq!(
+            output,
+            vec![Token::Comma(vec![
+                vec![Token::Key("a".into())],
+                vec![Token::Key("b".into()), Token::Index(0)]
+            ])]
+        );
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn parse_comma_binds_tighter_than_pipe() {
+        let mut input = ".a, .b | floor";
+        let output = parse_pipeline.parse_next(&mut input).unwrap();
+        assert_eq!(
+            output,
+            vec![
+                Token::Comma(vec![
+                    vec![Token::Key("a".into())],
+                    vec![Token::Key("b".into())]
+                ]),
+                Token::Function("floor".into(), vec![])
+            ]
+        );
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn parse_comma_in_array_wrapper() {
+        let mut input = "[1, 2]";
+        let output = parse_token.parse_next(&mut input).unwrap();
+        assert_eq!(
+            output,
+            Token::Array(vec![Token::Comma(vec![
+                vec![Token::Literal(serde_json::json!(1))],
+                vec![Token::Literal(serde_json::json!(2))]
+            ])])
+        );
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn parse_variable_token() {
+        let mut input = "$x.size";
+        let output = parse_pipeline.parse_next(&mut input).unwrap();
+        assert_eq!(
+            output,
+            vec![Token::Variable("x".into()), Token::Key("size".into())]
+        );
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn parse_arithmetic_precedence() {
+        let mut input = "1 + .a * 2 - 3";
+        let output = parse_pipeline.parse_next(&mut input).unwrap();
+        let number = |n: i64| vec![Token::Literal(Value::from(n))];
+        assert_eq!(
+            output,
+            vec![Token::Operation(
+                Operator::Subtract,
+                vec![Token::Operation(
+                    Operator::Add,
+                    number(1),
+                    vec![Token::Operation(
+                        Operator::Multiply,
+                        vec![Token::Key("a".into())],
+                        number(2)
+                    )]
+                )],
+                number(3)
+            )]
+        );
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn parse_negation() {
+        let mut input = "pow(.; -1) - -.a";
+        let output = parse_pipeline.parse_next(&mut input).unwrap();
+        let negate = || Token::Function("_negate".into(), vec![]);
+        assert_eq!(
+            output,
+            vec![Token::Operation(
+                Operator::Subtract,
+                vec![Token::Function(
+                    "pow".into(),
+                    vec![
+                        vec![Token::Identity],
+                        vec![Token::Literal(Value::from(1)), negate()]
+                    ]
+                )],
+                vec![Token::Key("a".into()), negate()]
+            )]
+        );
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn parse_key_stops_at_operator() {
+        let mut input = ".a-1";
+        let output = parse_pipeline.parse_next(&mut input).unwrap();
+        assert_eq!(
+            output,
+            vec![Token::Operation(
+                Operator::Subtract,
+                vec![Token::Key("a".into())],
+                vec![Token::Literal(Value::from(1))]
+            )]
+        );
+    }
+
+    #[test]
+    fn parse_binding_takes_rest_of_pipeline() {
+        let mut input = ".a as $x | $x, . | floor";
+        let output = parse_pipeline.parse_next(&mut input).unwrap();
+        assert_eq!(
+            output,
+            vec![Token::Bind(
+                vec![Token::Key("a".into())],
+                "x".into(),
+                vec![
+                    Token::Comma(vec![
+                        vec![Token::Variable("x".into())],
+                        vec![Token::Identity]
+                    ]),
+                    Token::Function("floor".into(), vec![])
+                ]
+            )]
+        );
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn parse_reduce_token() {
+        let mut input = "reduce .[] as $x (0; . + $x)";
+        let output = parse_token.parse_next(&mut input).unwrap();
+        assert_eq!(
+            output,
+            Token::Reduce(
+                vec![Token::Iterate],
+                "x".into(),
+                vec![Token::Literal(Value::from(0))],
+                vec![Token::Operation(
+                    Operator::Add,
+                    vec![Token::Identity],
+                    vec![Token::Variable("x".into())]
+                )]
+            )
+        );
+        assert!(input.is_empty());
+
+        let mut input = "reduce .[] as $x (0)";
+        assert!(parse_token.parse_next(&mut input).is_err());
+    }
+
+    #[test]
+    fn parse_whitespace_between_tokens() {
+        let mut input = ".a .b [ 0 ] .[ ] | . [\"c\"]";
+        let output = parse_pipeline.parse_next(&mut input).unwrap();
+        assert_eq!(
+            output,
+            vec![
+                Token::Key("a".into()),
+                Token::Key("b".into()),
+                Token::Index(0),
+                Token::Iterate,
+                Token::Identity,
+                Token::Key("c".into())
+            ]
+        );
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn only_suffixes_follow_a_term() {
+        for filter in ["1 2", ".a 1", "\"x\" \"y\"", ".a length", "$x .a $y"] {
+            let mut input = filter;
+            parse_pipeline.parse_next(&mut input).unwrap();
+            assert!(!input.is_empty(), "{filter} should not be parsed whole");
+        }
+
+        let mut input = "length .a [0]";
+        let output = parse_pipeline.parse_next(&mut input).unwrap();
+        assert_eq!(
+            output,
+            vec![
+                Token::Function("length".into(), vec![]),
+                Token::Key("a".into()),
+                Token::Index(0)
+            ]
+        );
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn parse_comments() {
+        let mut input = "# leading comment\n.a # the key\n\t| # pipe\n floor # trailing";
+        ws.parse_next(&mut input).unwrap();
+        let output = parse_pipeline.parse_next(&mut input).unwrap();
+        assert_eq!(
+            output,
+            vec![
+                Token::Key("a".into()),
+                Token::Function("floor".into(), vec![])
+            ]
+        );
+        ws.parse_next(&mut input).unwrap();
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn parse_comment_continued_by_backslash() {
+        let mut input = "# continued \\\n.a\n.b";
+        ws.parse_next(&mut input).unwrap();
+        assert_eq!(input, ".b");
+
+        let mut input = "# not continued \\\\\n.a";
+        ws.parse_next(&mut input).unwrap();
+        assert_eq!(input, ".a");
+
+        let mut input = "# continued with CRLF \\\r\n.a\r\n.b";
+        ws.parse_next(&mut input).unwrap();
+        assert_eq!(input, ".b");
+    }
+
+    #[test]
+    fn parse_definitions() {
+        let mut input = "def inc: . + 1; def apply(f; $n): $n | f; apply(inc; 2)";
+        let output = parse_pipeline.parse_next(&mut input).unwrap();
+
+        let inc = Definition {
+            name: "inc".into(),
+            params: vec![],
+            body: vec![Token::Operation(
+                Operator::Add,
+                vec![Token::Identity],
+                vec![Token::Literal(Value::from(1))],
+            )],
+        };
+        let apply = Definition {
+            name: "apply".into(),
+            params: vec![Parameter::Filter("f".into()), Parameter::Value("n".into())],
+            body: vec![
+                Token::Variable("n".into()),
+                Token::Function("f".into(), vec![]),
+            ],
+        };
+        let call = Token::Function(
+            "apply".into(),
+            vec![
+                vec![Token::Function("inc".into(), vec![])],
+                vec![Token::Literal(Value::from(2))],
+            ],
+        );
+        assert_eq!(
+            output,
+            vec![Token::Define(
+                Arc::new(inc),
+                vec![Token::Define(Arc::new(apply), vec![call])]
+            )]
+        );
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn parse_qualified_names() {
+        let mut input = "lib::f($data::data)";
+        let output = parse_pipeline.parse_next(&mut input).unwrap();
+        assert_eq!(
+            output,
+            vec![Token::Function(
+                "lib::f".into(),
+                vec![vec![Token::Variable("data::data".into())]]
+            )]
+        );
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn parse_program_with_imports() {
+        let mut input = "import \"a/lib\" as lib {search: \"./\", n: [1, null]};\n\
+                         import \"data\" as $d; include \"other\"; lib::f";
+        let output = parse_program.parse_next(&mut input).unwrap();
+
+        let metadata = serde_json::json!({"search": "./", "n": [1, null]});
+        assert_eq!(
+            output.imports,
+            vec![
+                Import {
+                    path: "a/lib".to_owned(),
+                    kind: ImportKind::Module("lib".to_owned()),
+                    metadata: metadata.as_object().unwrap().clone(),
+                },
+                Import {
+                    path: "data".to_owned(),
+                    kind: ImportKind::Data("d".to_owned()),
+                    metadata: Map::new(),
+                },
+                Import {
+                    path: "other".to_owned(),
+                    kind: ImportKind::Include,
+                    metadata: Map::new(),
+                },
+            ]
+        );
+        assert_eq!(
+            output.tokens,
+            vec![Token::Function("lib::f".into(), vec![])]
+        );
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn parse_module_file() {
+        let mut input = "module {\"name\": \"m\"};\ninclude \"x\";\ndef a: 1;\ndef b($v): $v;\n";
+        let output = parse_module.parse_next(&mut input).unwrap();
+
+        assert_eq!(output.metadata.get("name"), Some(&Value::from("m")));
+        assert_eq!(output.imports.len(), 1);
+        let names = output
+            .definitions
+            .iter()
+            .map(|definition| &*definition.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["a", "b"]);
+        assert!(input.is_empty());
+    }
 }
@@ -0,0 +1,566 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context};
+
+const SECONDS_PER_DAY: i64 = 86_400;
+
+const WEEKDAYS: [&str; 7] = [
+    "Sunday",
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+];
+
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+/// Where `now` gets the current time from.
+///
+/// Embedders and tests can pin it with `Clock::Fixed` to get deterministic
+/// output out of filters that depend on the current time.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Clock {
+    #[default]
+    System,
+    Fixed(f64),
+}
+
+impl Clock {
+    pub fn now(&self) -> f64 {
+        match self {
+            Clock::System => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0.0, |d| d.as_secs_f64()),
+            Clock::Fixed(timestamp) => *timestamp,
+        }
+    }
+}
+
+/// A UTC date split into its components, like C's `struct tm`.
+///
+/// jq represents it as an array of numbers in this same order, from the year
+/// down to the seconds and then the day of the week and of the year. Like in
+/// `struct tm` the month starts from 0, but the year is the full year rather
+/// than since 1900.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BrokenDownTime {
+    pub year: i64,
+    pub month: i64,
+    pub day: i64,
+    pub hours: i64,
+    pub minutes: i64,
+    pub seconds: f64,
+    pub weekday: i64,
+    pub year_day: i64,
+}
+
+impl BrokenDownTime {
+    /// Splits a timestamp into its components, or returns `None` when its
+    /// year doesn't fit in an `int` like `gmtime` would.
+    pub fn from_timestamp(timestamp: f64) -> Option<Self> {
+        if !timestamp.is_finite() {
+            return None;
+        }
+
+        // Casting saturates, which the year check below then rejects.
+        let whole = timestamp.floor() as i64;
+        let days = whole.div_euclid(SECONDS_PER_DAY);
+        let time = whole.rem_euclid(SECONDS_PER_DAY);
+        let (year, month, day) = civil_from_days(days)?;
+        i32::try_from(year).ok()?;
+
+        Some(Self {
+            year,
+            month: month - 1,
+            day,
+            hours: time / 3600,
+            minutes: time / 60 % 60,
+            seconds: (time % 60) as f64 + (timestamp - timestamp.floor()),
+            weekday: (days + 4).rem_euclid(7),
+            year_day: days - days_from_civil(year, 1, 1)?,
+        })
+    }
+
+    /// Converts the date back into seconds since the epoch, normalising out
+    /// of range components the way `timegm` does.
+    pub fn to_timestamp(self) -> Option<f64> {
+        let year = self.year.checked_add(self.month.div_euclid(12))?;
+        let month = self.month.rem_euclid(12) + 1;
+        let days = days_from_civil(year, month, self.day)?;
+        let seconds = days
+            .checked_mul(SECONDS_PER_DAY)?
+            .checked_add(self.hours.checked_mul(3600)?)?
+            .checked_add(self.minutes.checked_mul(60)?)?;
+
+        Some(seconds as f64 + self.seconds.floor())
+    }
+
+    /// Reads the components in jq's order, or returns `None` when there are
+    /// fewer than 6 or one of them doesn't fit in an `int` like the fields
+    /// of `struct tm`.
+    pub fn from_numbers(numbers: &[f64]) -> Option<Self> {
+        if numbers.len() < 6 {
+            return None;
+        }
+
+        let field = |i: usize| {
+            let number = numbers.get(i).copied().unwrap_or_default();
+            let in_range = (f64::from(i32::MIN)..=f64::from(i32::MAX)).contains(&number);
+            in_range.then_some(number)
+        };
+
+        let time = Self {
+            year: field(0)? as i64,
+            month: field(1)? as i64,
+            day: field(2)? as i64,
+            hours: field(3)? as i64,
+            minutes: field(4)? as i64,
+            seconds: field(5)?,
+            weekday: field(6)? as i64,
+            year_day: field(7)? as i64,
+        };
+        Some(time)
+    }
+
+    pub fn to_numbers(self) -> [f64; 8] {
+        [
+            self.year as f64,
+            self.month as f64,
+            self.day as f64,
+            self.hours as f64,
+            self.minutes as f64,
+            self.seconds,
+            self.weekday as f64,
+            self.year_day as f64,
+        ]
+    }
+
+    /// Formats the date following the conversion specifications of C's `strftime`.
+    pub fn format(&self, format: &str) -> anyhow::Result<String> {
+        let format = expand_composites(format);
+        let mut output = String::new();
+        let mut chars = format.chars();
+
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                output.push(c);
+                continue;
+            }
+
+            let Some(specifier) = chars.next() else {
+                bail!("strftime/1: format ends with an incomplete conversion");
+            };
+            match specifier {
+                'a' => output.push_str(&WEEKDAYS[self.weekday.rem_euclid(7) as usize][..3]),
+                'A' => output.push_str(WEEKDAYS[self.weekday.rem_euclid(7) as usize]),
+                'b' | 'h' => output.push_str(&MONTHS[self.month.rem_euclid(12) as usize][..3]),
+                'B' => output.push_str(MONTHS[self.month.rem_euclid(12) as usize]),
+                'C' => output.push_str(&format!("{:02}", self.year.div_euclid(100))),
+                'd' => output.push_str(&format!("{:02}", self.day)),
+                'e' => output.push_str(&format!("{:2}", self.day)),
+                'H' => output.push_str(&format!("{:02}", self.hours)),
+                'I' => output.push_str(&format!("{:02}", (self.hours + 11) % 12 + 1)),
+                'j' => output.push_str(&format!("{:03}", self.year_day + 1)),
+                'k' => output.push_str(&format!("{:2}", self.hours)),
+                'l' => output.push_str(&format!("{:2}", (self.hours + 11) % 12 + 1)),
+                'm' => output.push_str(&format!("{:02}", self.month + 1)),
+                'M' => output.push_str(&format!("{:02}", self.minutes)),
+                'n' => output.push('\n'),
+                'p' => output.push_str(if self.hours < 12 { "AM" } else { "PM" }),
+                's' => {
+                    let timestamp = self
+                        .to_timestamp()
+                        .context("strftime/1: time out of range")?;
+                    output.push_str(&timestamp.to_string());
+                }
+                'S' => output.push_str(&format!("{:02}", self.seconds.floor() as i64)),
+                't' => output.push('\t'),
+                'u' => output.push_str(&format!("{}", (self.weekday + 6).rem_euclid(7) + 1)),
+                'U' => output.push_str(&format!("{:02}", self.week_number(0))),
+                'w' => output.push_str(&format!("{}", self.weekday)),
+                'W' => output.push_str(&format!("{:02}", self.week_number(1))),
+                'y' => output.push_str(&format!("{:02}", self.year.rem_euclid(100))),
+                'Y' => output.push_str(&format!("{}", self.year)),
+                'z' => output.push_str("+0000"),
+                'Z' => output.push_str("UTC"),
+                '%' => output.push('%'),
+                other => bail!("strftime/1: unsupported conversion %{other}"),
+            }
+        }
+
+        Ok(output)
+    }
+
+    /// Week of the year where weeks start on `first_weekday` (0 for Sunday,
+    /// 1 for Monday) and days before the first one belong to week 0.
+    fn week_number(&self, first_weekday: i64) -> i64 {
+        (self.year_day + 7 - (self.weekday - first_weekday).rem_euclid(7)) / 7
+    }
+
+    /// Parses a date following the conversion specifications of C's `strptime`.
+    ///
+    /// Like jq, fields missing from the format are left as in a zeroed
+    /// `struct tm`, so the year defaults to 1900 and the day of the month to
+    /// 0. The parsed fields are kept as they are, while the day of the week
+    /// and of the year are always computed from them, and time zone offsets
+    /// are accepted but ignored.
+    pub fn parse(input: &str, format: &str) -> anyhow::Result<Self> {
+        let mismatch = || format!("date \"{input}\" does not match format \"{format}\"");
+
+        let expanded = expand_composites(format);
+        let mut time = Self {
+            year: 1900,
+            month: 0,
+            day: 0,
+            hours: 0,
+            minutes: 0,
+            seconds: 0.0,
+            weekday: 0,
+            year_day: 0,
+        };
+        let mut pm = None;
+        let mut rest = input;
+        let mut chars = expanded.chars();
+
+        while let Some(c) = chars.next() {
+            if c.is_whitespace() {
+                rest = rest.trim_start();
+                continue;
+            }
+            if c != '%' {
+                rest = rest.strip_prefix(c).with_context(mismatch)?;
+                continue;
+            }
+
+            let specifier = chars.next().with_context(mismatch)?;
+            if matches!(specifier, 'd' | 'e' | 'H' | 'k' | 'I' | 'l' | 'n' | 't') {
+                rest = rest.trim_start();
+            }
+            match specifier {
+                'a' | 'A' => rest = skip_name(rest, &WEEKDAYS).with_context(mismatch)?.1,
+                'b' | 'B' | 'h' => {
+                    let (month, remaining) = skip_name(rest, &MONTHS).with_context(mismatch)?;
+                    time.month = month as i64;
+                    rest = remaining;
+                }
+                'C' => {
+                    let century = parse_digits(&mut rest, 2).with_context(mismatch)?;
+                    time.year = century * 100 + time.year.rem_euclid(100);
+                }
+                'd' | 'e' => time.day = parse_digits(&mut rest, 2).with_context(mismatch)?,
+                'H' | 'k' => time.hours = parse_digits(&mut rest, 2).with_context(mismatch)?,
+                'I' | 'l' => time.hours = parse_digits(&mut rest, 2).with_context(mismatch)? % 12,
+                'j' => time.year_day = parse_digits(&mut rest, 3).with_context(mismatch)? - 1,
+                'm' => time.month = parse_digits(&mut rest, 2).with_context(mismatch)? - 1,
+                'M' => time.minutes = parse_digits(&mut rest, 2).with_context(mismatch)?,
+                'n' | 't' => {}
+                'p' => {
+                    let meridiem = rest.get(..2).with_context(mismatch)?;
+                    pm = match meridiem.to_ascii_uppercase().as_str() {
+                        "AM" => Some(false),
+                        "PM" => Some(true),
+                        _ => bail!(mismatch()),
+                    };
+                    rest = &rest[2..];
+                }
+                's' => {
+                    let timestamp = parse_signed(&mut rest, 20).with_context(mismatch)?;
+                    time = Self::from_timestamp(timestamp as f64).with_context(mismatch)?;
+                }
+                'S' => time.seconds = parse_digits(&mut rest, 2).with_context(mismatch)? as f64,
+                'y' => {
+                    let year = parse_digits(&mut rest, 2).with_context(mismatch)?;
+                    time.year = if year < 69 { 2000 + year } else { 1900 + year };
+                }
+                'Y' => time.year = parse_signed(&mut rest, 4).with_context(mismatch)?,
+                'z' => {
+                    if let Some(remaining) = rest.strip_prefix('Z') {
+                        rest = remaining;
+                    } else {
+                        rest = rest.strip_prefix(['+', '-']).with_context(mismatch)?;
+                        parse_digits(&mut rest, 2).with_context(mismatch)?;
+                        rest = rest.strip_prefix(':').unwrap_or(rest);
+                        parse_digits(&mut rest, 2).with_context(mismatch)?;
+                    }
+                }
+                'Z' => {
+                    let end = rest
+                        .find(|c: char| !c.is_ascii_alphabetic())
+                        .unwrap_or(rest.len());
+                    rest = &rest[end..];
+                }
+                '%' => rest = rest.strip_prefix('%').with_context(mismatch)?,
+                other => bail!("strptime/1: unsupported conversion %{other}"),
+            }
+        }
+
+        if !rest.is_empty() {
+            bail!(mismatch());
+        }
+        if pm == Some(true) {
+            time.hours += 12;
+        }
+
+        let timestamp = time.to_timestamp().with_context(mismatch)?;
+        let days = timestamp.div_euclid(SECONDS_PER_DAY as f64) as i64;
+        let year_start = days_from_civil(time.year, 1, 1).with_context(mismatch)?;
+        Ok(Self {
+            weekday: (days + 4).rem_euclid(7),
+            year_day: days - year_start,
+            ..time
+        })
+    }
+}
+
+/// Replaces the conversions that are shorthands for others, e.g. `%T` for `%H:%M:%S`.
+fn expand_composites(format: &str) -> String {
+    let mut expanded = String::with_capacity(format.len());
+    let mut chars = format.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            expanded.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('c') => expanded.push_str("%a %b %e %H:%M:%S %Y"),
+            Some('D' | 'x') => expanded.push_str("%m/%d/%y"),
+            Some('F') => expanded.push_str("%Y-%m-%d"),
+            Some('r') => expanded.push_str("%I:%M:%S %p"),
+            Some('R') => expanded.push_str("%H:%M"),
+            Some('T' | 'X') => expanded.push_str("%H:%M:%S"),
+            Some(other) => {
+                expanded.push('%');
+                expanded.push(other);
+            }
+            None => expanded.push('%'),
+        }
+    }
+
+    expanded
+}
+
+fn parse_digits(input: &mut &str, max_width: usize) -> Option<i64> {
+    let end = input
+        .char_indices()
+        .take(max_width)
+        .take_while(|(_, c)| c.is_ascii_digit())
+        .last()
+        .map(|(i, c)| i + c.len_utf8())?;
+
+    let number = input[..end].parse().ok()?;
+    *input = &input[end..];
+    Some(number)
+}
+
+fn parse_signed(input: &mut &str, max_width: usize) -> Option<i64> {
+    let negative = input.starts_with('-');
+    let mut rest = input.strip_prefix(['+', '-']).unwrap_or(input);
+    let number = parse_digits(&mut rest, max_width)?;
+
+    *input = rest;
+    Some(if negative { -number } else { number })
+}
+
+/// Matches either the full name or its three letter abbreviation, ignoring case.
+fn skip_name<'a>(input: &'a str, names: &[&str]) -> Option<(usize, &'a str)> {
+    names.iter().enumerate().find_map(|(i, name)| {
+        [*name, &name[..3]].into_iter().find_map(|candidate| {
+            let prefix = input.get(..candidate.len())?;
+            prefix
+                .eq_ignore_ascii_case(candidate)
+                .then(|| (i, &input[candidate.len()..]))
+        })
+    })
+}
+
+// Algorithms from http://howardhinnant.github.io/date_algorithms.html
+//
+// Both return `None` when a date is too far from the epoch to be counted in
+// an `i64`.
+fn days_from_civil(year: i64, month: i64, day: i64) -> Option<i64> {
+    let year = if month <= 2 {
+        year.checked_sub(1)?
+    } else {
+        year
+    };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let day_of_year = ((153 * ((month + 9) % 12) + 2) / 5)
+        .checked_add(day)?
+        .checked_sub(1)?;
+    let day_of_era =
+        (year_of_era * 365 + year_of_era / 4 - year_of_era / 100).checked_add(day_of_year)?;
+
+    era.checked_mul(146_097)?
+        .checked_add(day_of_era)?
+        .checked_sub(719_468)
+}
+
+fn civil_from_days(days: i64) -> Option<(i64, i64, i64)> {
+    let days = days.checked_add(719_468)?;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = era
+        .checked_mul(400)?
+        .checked_add(year_of_era + i64::from(month <= 2))?;
+
+    Some((year, month, day))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn broken_down_epoch() {
+        let time = BrokenDownTime::from_timestamp(0.0).unwrap();
+        assert_eq!(
+            time.to_numbers(),
+            [1970.0, 0.0, 1.0, 0.0, 0.0, 0.0, 4.0, 0.0]
+        );
+    }
+
+    #[test]
+    fn broken_down_timestamp_keeps_fractional_seconds() {
+        let time = BrokenDownTime::from_timestamp(1425599621.25).unwrap();
+        assert_eq!(
+            time.to_numbers(),
+            [2015.0, 2.0, 5.0, 23.0, 53.0, 41.25, 4.0, 63.0]
+        );
+    }
+
+    #[test]
+    fn broken_down_time_before_epoch() {
+        let time = BrokenDownTime::from_timestamp(-1.0).unwrap();
+        assert_eq!(
+            time.to_numbers(),
+            [1969.0, 11.0, 31.0, 23.0, 59.0, 59.0, 3.0, 364.0]
+        );
+    }
+
+    #[test]
+    fn timestamp_roundtrip() {
+        for timestamp in [0.0, 951782400.0, 1425599621.0, -86400.0 * 365.0] {
+            let time = BrokenDownTime::from_timestamp(timestamp).unwrap();
+            assert_eq!(time.to_timestamp(), Some(timestamp));
+        }
+    }
+
+    #[test]
+    fn timestamp_normalises_out_of_range_month() {
+        let time = BrokenDownTime::from_numbers(&[2015.0, 12.0, 1.0, 0.0, 0.0, 0.0]).unwrap();
+        assert_eq!(
+            time.to_timestamp(),
+            BrokenDownTime::parse("2016-01-01", "%Y-%m-%d")
+                .unwrap()
+                .to_timestamp()
+        );
+    }
+
+    #[test]
+    fn out_of_range_times_are_rejected() {
+        assert_eq!(BrokenDownTime::from_timestamp(1e300), None);
+        assert_eq!(BrokenDownTime::from_timestamp(-1e18), None);
+        assert_eq!(BrokenDownTime::from_timestamp(f64::NAN), None);
+        assert_eq!(
+            BrokenDownTime::from_numbers(&[1e18, 0.0, 1.0, 0.0, 0.0, 0.0]),
+            None
+        );
+        assert_eq!(
+            BrokenDownTime::from_numbers(&[2000.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1e30]),
+            None
+        );
+
+        let time = BrokenDownTime::from_numbers(&[2e9, 0.0, 1.0, 0.0, 0.0, 0.0]).unwrap();
+        assert!(time.to_timestamp().is_some());
+    }
+
+    #[test]
+    fn format_iso8601() {
+        let time = BrokenDownTime::from_timestamp(1425599621.0).unwrap();
+        assert_eq!(
+            time.format("%Y-%m-%dT%H:%M:%SZ").unwrap(),
+            "2015-03-05T23:53:41Z"
+        );
+    }
+
+    #[test]
+    fn format_names_and_padding() {
+        let time = BrokenDownTime::from_timestamp(1425599621.0).unwrap();
+        assert_eq!(
+            time.format("%a %A %b %B %e %j %I%p %%").unwrap(),
+            "Thu Thursday Mar March  5 064 11PM %"
+        );
+    }
+
+    #[test]
+    fn parse_iso8601() {
+        let time = BrokenDownTime::parse("2015-03-05T23:51:47Z", "%Y-%m-%dT%H:%M:%SZ").unwrap();
+        assert_eq!(
+            time.to_numbers(),
+            [2015.0, 2.0, 5.0, 23.0, 51.0, 47.0, 4.0, 63.0]
+        );
+    }
+
+    #[test]
+    fn parse_leaves_missing_fields_zeroed() {
+        let time = BrokenDownTime::parse("10:15", "%H:%M").unwrap();
+        assert_eq!(
+            time.to_numbers(),
+            [1900.0, 0.0, 0.0, 10.0, 15.0, 0.0, 0.0, -1.0]
+        );
+        assert_eq!(time.to_timestamp(), Some(-2209038300.0));
+
+        let time = BrokenDownTime::parse("2015-02-30", "%Y-%m-%d").unwrap();
+        assert_eq!(
+            time.to_numbers(),
+            [2015.0, 1.0, 30.0, 0.0, 0.0, 0.0, 1.0, 60.0]
+        );
+    }
+
+    #[test]
+    fn parse_month_names_and_composites() {
+        let time =
+            BrokenDownTime::parse("thu, 05 mar 2015 11:51:47 PM", "%a, %d %b %Y %r").unwrap();
+        assert_eq!(time.to_timestamp(), Some(1425599507.0));
+
+        let time = BrokenDownTime::parse("05 March 2015 23:51:47", "%d %B %Y %T").unwrap();
+        assert_eq!(time.to_timestamp(), Some(1425599507.0));
+    }
+
+    #[test]
+    fn parse_mismatched_date() {
+        let error = BrokenDownTime::parse("2015-03", "%Y-%m-%d").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "date \"2015-03\" does not match format \"%Y-%m-%d\""
+        );
+    }
+}
@@ -1,402 +1,3578 @@
-use anyhow::bail;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::fmt;
+use std::iter;
+use std::ops::Deref;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context as _};
 use serde_json::{json, Value};
 
+use crate::error::{InputError, RuntimeError, Undefined};
+use crate::format;
+use crate::input::Inputs;
+use crate::module::{self, SearchPath};
+use crate::time::{BrokenDownTime, Clock};
+
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub enum Token<'a> {
+pub enum Token {
     Identity,
     Index(usize),
     OptionalIndex(usize),
     IterateIndex(usize),
     IterateOptionalIndex(usize),
-    Key(&'a str),
-    OptionalKey(&'a str),
-    IterateKey(&'a str),
-    IterateOptionalKey(&'a str),
+    Key(Arc<str>),
+    OptionalKey(Arc<str>),
+    IterateKey(Arc<str>),
+    IterateOptionalKey(Arc<str>),
     Iterate,
-    Array(Vec<Token<'a>>),
+    Array(Vec<Token>),
+    Literal(Value),
+    Function(Arc<str>, Vec<Vec<Token>>),
+    Format(Arc<str>),
+    Comma(Vec<Vec<Token>>),
+    Variable(Arc<str>),
+    /// `source as $name | body`, where the body is the rest of the pipeline.
+    Bind(Vec<Token>, Arc<str>, Vec<Token>),
+    /// `reduce source as $name (init; update)`.
+    Reduce(Vec<Token>, Arc<str>, Vec<Token>, Vec<Token>),
+    Operation(Operator, Vec<Token>, Vec<Token>),
+    /// `def name: body; rest`, where the function is only visible in the rest
+    /// of the pipeline.
+    Define(Arc<Definition>, Vec<Token>),
+}
+
+/// A function defined with `def name(params): body;`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Definition {
+    pub name: Arc<str>,
+    pub params: Vec<Parameter>,
+    pub body: Vec<Token>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Parameter {
+    /// A filter evaluated every time the body refers to it, like `f`.
+    Filter(Arc<str>),
+    /// A value the body is evaluated with once for each output of the
+    /// argument, like `$x`. It can be referred to as `$x` or as `x`.
+    Value(Arc<str>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+}
+
+/// Environment a filter is evaluated in.
+#[derive(Debug, Clone)]
+pub struct Context {
+    /// Source of the current time for `now`.
+    pub clock: Clock,
+    /// What `$ENV` and `env` return.
+    pub environment: Environment,
+    /// Where `import`, `include` and `modulemeta` look for modules.
+    pub search_path: SearchPath,
+    pub(crate) variables: Variables,
+    pub(crate) functions: Functions,
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        let context = Self {
+            clock: Clock::default(),
+            environment: Environment::default(),
+            search_path: SearchPath::default(),
+            variables: Variables::default(),
+            functions: Functions::default(),
+        };
+        context.with_arguments(serde_json::Map::new(), Vec::new())
+    }
+}
+
+impl Context {
+    /// Makes `$name` available to the whole filter, like `--arg` does.
+    pub fn with_variable(self, name: &str, value: Value) -> Self {
+        Self {
+            variables: self.variables.bind(name, value),
+            ..self
+        }
+    }
+
+    /// Binds each named argument as a variable and exposes all of them
+    /// through `$ARGS`, as `$ARGS.named` and `$ARGS.positional`.
+    pub fn with_arguments(
+        self,
+        named: serde_json::Map<String, Value>,
+        positional: Vec<Value>,
+    ) -> Self {
+        let context = named.iter().fold(self, |context, (name, value)| {
+            context.with_variable(name, value.clone())
+        });
+        context.with_variable("ARGS", json!({"positional": positional, "named": named}))
+    }
+
+    /// Makes `function` callable as `name` from filters run in the context.
+    pub(crate) fn with_function(self, name: String, function: Function) -> Self {
+        let binding = Arc::new((name, function, self.functions.clone()));
+        Self {
+            functions: Functions(Some(binding)),
+            ..self
+        }
+    }
+}
+
+/// Where the environment variables exposed to filters come from.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Environment {
+    /// The variables of this process.
+    #[default]
+    Process,
+    /// A fixed set of variables, independent of the process.
+    Custom(serde_json::Map<String, Value>),
+}
+
+impl Environment {
+    fn to_value(&self) -> Value {
+        match self {
+            Environment::Process => std::env::vars_os()
+                .map(|(name, value)| {
+                    let value = value.to_string_lossy().into_owned();
+                    (name.to_string_lossy().into_owned(), Value::String(value))
+                })
+                .collect(),
+            Environment::Custom(variables) => Value::Object(variables.clone()),
+        }
+    }
 }
 
+/// Raised by `halt` and `halt_error` to stop processing every input and exit
+/// with `code`, after printing `message` to stderr if there is one.
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub enum Output {
-    Single(Value),
-    Multiple(Vec<Output>),
+pub struct Halt {
+    pub code: i32,
+    pub message: Option<Value>,
+}
+
+impl fmt::Display for Halt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.message {
+            Some(message) => write!(f, "halted with status {}: {message}", self.code),
+            None => write!(f, "halted with status {}", self.code),
+        }
+    }
+}
+
+impl std::error::Error for Halt {}
+
+/// Variables in scope, innermost first, kept as a linked list so that binding
+/// a new one doesn't copy the others.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Variables(Option<Arc<(String, Value, Variables)>>);
+
+impl Variables {
+    fn bind(&self, name: &str, value: Value) -> Self {
+        Self(Some(Arc::new((name.to_owned(), value, self.clone()))))
+    }
+
+    fn get(&self, name: &str) -> Option<&Value> {
+        let mut variables = self;
+        while let Some(binding) = &variables.0 {
+            let (variable, value, parent) = binding.as_ref();
+            if variable == name {
+                return Some(value);
+            }
+            variables = parent;
+        }
+        None
+    }
+}
+
+/// Functions in scope, innermost first, by the name they're called with.
+#[derive(Clone, Default)]
+pub(crate) struct Functions(Option<Arc<(String, Function, Functions)>>);
+
+impl Functions {
+    fn get(&self, name: &str, arity: usize) -> Option<&Function> {
+        let mut functions = self;
+        while let Some(binding) = &functions.0 {
+            let (function_name, function, parent) = binding.as_ref();
+            if function_name == name && function.definition.params.len() == arity {
+                return Some(function);
+            }
+            functions = parent;
+        }
+        None
+    }
+}
+
+impl fmt::Debug for Functions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut list = f.debug_list();
+        let mut functions = self;
+        while let Some(binding) = &functions.0 {
+            let (name, function, parent) = binding.as_ref();
+            list.entry(&format_args!("{name}/{}", function.definition.params.len()));
+            functions = parent;
+        }
+        list.finish()
+    }
+}
+
+/// A function along with the context it was defined in, which is the one its
+/// body runs in.
+#[derive(Debug, Clone)]
+pub(crate) struct Function {
+    definition: Arc<Definition>,
+    scope: Context,
+}
+
+impl Function {
+    pub(crate) fn new(definition: Arc<Definition>, scope: Context) -> Self {
+        Self { definition, scope }
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.definition.name
+    }
+}
+
+/// What a filter sees while it runs: the context it was compiled in, along
+/// with the variables and functions it binds on the way.
+#[derive(Clone)]
+pub(crate) struct Scope<'a> {
+    context: &'a Context,
+    /// Where `input` and `inputs` read from, lent by the caller for the run
+    /// and shared with every function called during it.
+    inputs: Option<Rc<dyn InputStream + 'a>>,
+    variables: Bindings<'a>,
+    functions: Callables<'a>,
+}
+
+impl<'a> Scope<'a> {
+    pub(crate) fn new(context: &'a Context) -> Self {
+        Self {
+            context,
+            inputs: None,
+            variables: Bindings::default(),
+            functions: Callables::default(),
+        }
+    }
+
+    /// Lets filters run in the scope read the rest of `inputs`.
+    pub(crate) fn with_inputs(self, inputs: &'a mut Inputs) -> Self {
+        Self {
+            inputs: Some(Rc::new(RefCell::new(inputs))),
+            ..self
+        }
+    }
+
+    fn next_input(&self) -> Option<Result<Value, InputError>> {
+        self.inputs.as_ref()?.next_input()
+    }
+
+    fn bind(&self, name: &'a str, value: Shared<'a>) -> Self {
+        let binding = Rc::new((name, value, self.variables.clone()));
+        Self {
+            variables: Bindings(Some(binding)),
+            ..self.clone()
+        }
+    }
+
+    /// Finds the innermost variable called `name`, falling back to the ones
+    /// of the context.
+    fn variable(&self, name: &str) -> Option<Shared<'a>> {
+        let mut variables = &self.variables;
+        while let Some(binding) = &variables.0 {
+            let (variable, value, parent) = binding.as_ref();
+            if *variable == name {
+                return Some(value.clone());
+            }
+            variables = parent;
+        }
+
+        self.context.variables.get(name).map(Shared::Borrowed)
+    }
+
+    fn with_function(&self, name: &'a str, callable: Callable<'a>) -> Self {
+        let binding = Rc::new((name, callable, self.functions.clone()));
+        Self {
+            functions: Callables(Some(binding)),
+            ..self.clone()
+        }
+    }
+
+    /// Defines a function whose body sees everything this scope does.
+    fn define(&self, definition: &'a Definition) -> Self {
+        self.with_function(
+            &definition.name,
+            Callable::Defined(definition, self.clone()),
+        )
+    }
+
+    /// Finds the innermost function called `name` taking `arity` arguments,
+    /// falling back to the ones the context was linked with.
+    fn function(&self, name: &str, arity: usize) -> Option<Callable<'a>> {
+        let mut functions = &self.functions;
+        while let Some(binding) = &functions.0 {
+            let (function_name, callable, parent) = binding.as_ref();
+            if *function_name == name && callable.arity() == arity {
+                return Some(callable.clone());
+            }
+            functions = parent;
+        }
+
+        let function = self.context.functions.get(name, arity)?;
+        let scope = Scope {
+            inputs: self.inputs.clone(),
+            ..Scope::new(&function.scope)
+        };
+        Some(Callable::Defined(&function.definition, scope))
+    }
+}
+
+/// Inputs lent to a run, kept behind a trait object rather than as a
+/// `RefCell<&mut Inputs>` so that scopes stay covariant in their lifetime.
+trait InputStream {
+    fn next_input(&self) -> Option<Result<Value, InputError>>;
+    fn filename(&self) -> Option<String>;
+}
+
+impl InputStream for RefCell<&mut Inputs> {
+    fn next_input(&self) -> Option<Result<Value, InputError>> {
+        self.borrow_mut().next()
+    }
+
+    fn filename(&self) -> Option<String> {
+        self.borrow().filename().map(str::to_owned)
+    }
+}
+
+/// Variables bound while a filter runs, innermost first.
+#[derive(Clone, Default)]
+struct Bindings<'a>(Option<Rc<(&'a str, Shared<'a>, Bindings<'a>)>>);
+
+/// Something a filter can call by name while it runs.
+#[derive(Clone)]
+enum Callable<'a> {
+    /// A function defined with `def`, along with the scope it was defined in.
+    Defined(&'a Definition, Scope<'a>),
+    /// A filter argument, which runs in the scope of the caller.
+    Argument(&'a [Token], Scope<'a>),
+    /// A `$name` argument, which can also be called as `name`.
+    Value(Shared<'a>),
+}
+
+impl Callable<'_> {
+    fn arity(&self) -> usize {
+        match self {
+            Callable::Defined(definition, _) => definition.params.len(),
+            Callable::Argument(..) | Callable::Value(_) => 0,
+        }
+    }
+}
+
+/// Functions bound while a filter runs, innermost first.
+#[derive(Clone, Default)]
+struct Callables<'a>(Option<Rc<(&'a str, Callable<'a>, Callables<'a>)>>);
+
+/// A value passed along while a filter runs: either borrowed from the input,
+/// the filter or its context, or built by the filter and counted, so that
+/// the variables bound to it and everything reading them share one copy.
+#[derive(Debug, Clone)]
+pub(crate) enum Shared<'a> {
+    Borrowed(&'a Value),
+    Owned(Rc<Value>),
+    /// NaN or an infinity, along with the JSON value it's written as (see
+    /// `number_to_value`). It's only lowered to that value once it's put in
+    /// an array or an object or output, so that until then `isnan` and
+    /// `isinfinite` can tell it apart from `null` and the largest float.
+    /// Once lowered it can't be told apart anymore: unlike in jq,
+    /// `[nan] | .[0]` is `null` rather than a number.
+    NonFinite(f64, Value),
+}
+
+impl<'a> Shared<'a> {
+    /// The result of a numeric operation.
+    fn number(number: f64) -> Self {
+        match number_to_value(number) {
+            value if number.is_finite() => Shared::from(value),
+            value => Shared::NonFinite(number, value),
+        }
+    }
+
+    /// The number the value stands for, if it's one.
+    fn as_f64(&self) -> Option<f64> {
+        match self {
+            Shared::NonFinite(number, _) => Some(*number),
+            value => Value::as_f64(value),
+        }
+    }
+
+    /// Like `as_f64`, but failing like jq when the value isn't a number.
+    fn to_number(&self) -> anyhow::Result<f64> {
+        match self.as_f64() {
+            Some(number) => Ok(number),
+            None => as_number(self),
+        }
+    }
+
+    /// Whether the value is `null`. NaN is written as `null` but it's still a
+    /// number, so unlike `null` it can't be indexed or added to anything.
+    fn is_null(&self) -> bool {
+        !matches!(self, Shared::NonFinite(..)) && Value::is_null(self)
+    }
+
+    /// Like `type_name`, but knowing that NaN is a number.
+    fn type_name(&self) -> &'static str {
+        match self {
+            Shared::NonFinite(..) => "number",
+            value => type_name(value),
+        }
+    }
+
+    /// Like `describe`, but knowing that NaN is a number.
+    fn describe(&self) -> String {
+        describe_as(self.type_name(), self)
+    }
+
+    /// Takes the value out, copying it only when something else refers to it.
+    pub(crate) fn into_owned(self) -> Value {
+        match self {
+            Shared::Borrowed(value) => value.clone(),
+            Shared::Owned(value) => Rc::unwrap_or_clone(value),
+            Shared::NonFinite(_, value) => value,
+        }
+    }
+
+    pub(crate) fn into_cow(self) -> Cow<'a, Value> {
+        match self {
+            Shared::Borrowed(value) => Cow::Borrowed(value),
+            value => Cow::Owned(value.into_owned()),
+        }
+    }
+}
+
+impl From<Value> for Shared<'_> {
+    fn from(value: Value) -> Self {
+        Shared::Owned(Rc::new(value))
+    }
+}
+
+impl Deref for Shared<'_> {
+    type Target = Value;
+
+    fn deref(&self) -> &Value {
+        match self {
+            Shared::Borrowed(value) => value,
+            Shared::Owned(value) => value,
+            Shared::NonFinite(_, value) => value,
+        }
+    }
+}
+
+/// Results of running a filter, produced one at a time as they're asked for
+/// so that none have to be kept around and consumers can stop early.
+///
+/// Values are borrowed from the input, the filter and its variables for as
+/// long as they're passed along unchanged, so selecting any part of the input
+/// doesn't copy it.
+pub(crate) type Results<'a, T = Shared<'a>> = Box<dyn Iterator<Item = anyhow::Result<T>> + 'a>;
+
+fn once<'a, T: 'a>(result: anyhow::Result<T>) -> Results<'a, T> {
+    Box::new(iter::once(result))
+}
+
+/// Runs `f` on every value of `results`, passing their errors along.
+fn and_then<'a, T: 'a, U: 'a>(
+    results: Results<'a, T>,
+    mut f: impl FnMut(T) -> Results<'a, U> + 'a,
+) -> Results<'a, U> {
+    Box::new(results.flat_map(move |result| match result {
+        Ok(value) => f(value),
+        Err(e) => once(Err(e)),
+    }))
+}
+
+pub(crate) fn apply_tokens_with<'a>(
+    scope: &Scope<'a>,
+    input: Shared<'a>,
+    tokens: &'a [Token],
+) -> Results<'a> {
+    let mut output = input;
+    // The keys and indices followed from the input to `output`, to tell
+    // where runtime errors happen.
+    let mut path = Vec::new();
+
+    for (i, token) in tokens.iter().enumerate() {
+        let next_tokens = &tokens[i + 1..];
+        match token {
+            Token::Identity => {}
+            Token::Index(_) | Token::IterateIndex(_) if !output.is_array() && !output.is_null() => {
+                let message = format!("Cannot index {} with number", output.type_name());
+                return once(Err(RuntimeError::new(message, &output, &path).into()));
+            }
+            Token::Index(index) | Token::OptionalIndex(index) => {
+                output = get_index(output, *index);
+                path.push(Value::from(*index));
+            }
+            Token::Key(key) | Token::IterateKey(key)
+                if !output.is_object() && !output.is_null() =>
+            {
+                let message = format!("Cannot index {} with \"{key}\"", output.type_name());
+                return once(Err(RuntimeError::new(message, &output, &path).into()));
+            }
+            Token::Key(key) | Token::OptionalKey(key) => {
+                output = get_key(output, key);
+                path.push(Value::from(&**key));
+            }
+            Token::IterateIndex(index) | Token::IterateOptionalIndex(index) => {
+                output = get_index(output, *index);
+                path.push(Value::from(*index));
+                return locate_all(iterate(scope, output, next_tokens), Some(path));
+            }
+            Token::IterateKey(key) | Token::IterateOptionalKey(key) => {
+                output = get_key(output, key);
+                path.push(Value::from(&**key));
+                return locate_all(iterate(scope, output, next_tokens), Some(path));
+            }
+            Token::Iterate => {
+                return locate_all(iterate(scope, output, next_tokens), Some(path));
+            }
+            token => {
+                let applied = locate_all(apply_token(scope, output, token), Some(path));
+                if next_tokens.is_empty() {
+                    return applied;
+                }
+                // What the token outputs isn't found by following a path.
+                let scope = scope.clone();
+                return and_then(applied, move |value| {
+                    locate_all(apply_tokens_with(&scope, value, next_tokens), None)
+                });
+            }
+        }
+    }
+
+    once(Ok(output))
+}
+
+/// The element at `index` of an array, or `null` when there isn't one. It's
+/// moved out of arrays the filter built rather than copied, unless something
+/// else still refers to them.
+fn get_index(value: Shared<'_>, index: usize) -> Shared<'_> {
+    match value {
+        Shared::Borrowed(value) => Shared::Borrowed(value.get(index).unwrap_or(&Value::Null)),
+        Shared::Owned(value) => match Rc::try_unwrap(value) {
+            Ok(Value::Array(mut array)) if index < array.len() => {
+                Shared::from(array.swap_remove(index))
+            }
+            Ok(_) => Shared::from(Value::Null),
+            Err(value) => Shared::from(value.get(index).cloned().unwrap_or(Value::Null)),
+        },
+        // Neither NaN nor infinities have any elements.
+        Shared::NonFinite(..) => Shared::from(Value::Null),
+    }
+}
+
+/// The value of `key` in an object, or `null` when there isn't one. It's
+/// moved out of objects the filter built rather than copied, unless something
+/// else still refers to them.
+fn get_key<'a>(value: Shared<'a>, key: &str) -> Shared<'a> {
+    match value {
+        Shared::Borrowed(value) => Shared::Borrowed(value.get(key).unwrap_or(&Value::Null)),
+        Shared::Owned(value) => match Rc::try_unwrap(value) {
+            Ok(Value::Object(mut map)) => Shared::from(map.remove(key).unwrap_or(Value::Null)),
+            Ok(_) => Shared::from(Value::Null),
+            Err(value) => Shared::from(value.get(key).cloned().unwrap_or(Value::Null)),
+        },
+        Shared::NonFinite(..) => Shared::from(Value::Null),
+    }
+}
+
+/// Applies a token to its input, leaving the rest of the pipeline to the
+/// caller unless the token takes it as its body.
+fn apply_token<'a>(scope: &Scope<'a>, input: Shared<'a>, token: &'a Token) -> Results<'a> {
+    let value = match token {
+        // An empty pipeline is the identity, but `[]` is an empty array.
+        Token::Array(array) if array.is_empty() => Ok(Shared::from(json!([]))),
+        Token::Array(array) => apply_tokens_with(scope, input, array)
+            .map(|value| value.map(Shared::into_owned))
+            .collect::<anyhow::Result<_>>()
+            .map(|array| Shared::from(Value::Array(array))),
+        Token::Literal(value) => Ok(Shared::Borrowed(value)),
+        Token::Function(name, args) => return call_function(scope, name, args, input),
+        Token::Format(name) => apply_format(name, &input).map(Shared::from),
+        Token::Comma(branches) => {
+            let scope = scope.clone();
+            return Box::new(
+                branches
+                    .iter()
+                    .flat_map(move |branch| apply_tokens_with(&scope, input.clone(), branch)),
+            );
+        }
+        Token::Variable(name) if &**name == "ENV" && scope.variable("ENV").is_none() => {
+            Ok(Shared::from(scope.context.environment.to_value()))
+        }
+        Token::Variable(name) => scope
+            .variable(name)
+            .with_context(|| format!("${name} is not defined")),
+        Token::Bind(source, name, body) => {
+            let scope = scope.clone();
+            let values = apply_tokens_with(&scope, input.clone(), source);
+            return and_then(values, move |value| {
+                apply_tokens_with(&scope.bind(name, value), input.clone(), body)
+            });
+        }
+        Token::Reduce(source, name, init, update) => {
+            return reduce(scope, input, source, name, init, update)
+        }
+        Token::Operation(operator, lhs, rhs) => return operate(scope, input, *operator, lhs, rhs),
+        Token::Define(definition, rest) => {
+            return apply_tokens_with(&scope.define(definition), input, rest)
+        }
+        path => return apply_tokens_with(scope, input, std::slice::from_ref(path)),
+    };
+
+    once(value)
+}
+
+/// Makes the path of a runtime error relative to the value `path` was
+/// followed from, or forgets it when the value it's relative to wasn't
+/// reached by following a path.
+fn locate(mut error: anyhow::Error, path: Option<&[Value]>) -> anyhow::Error {
+    if let Some(error) = error.downcast_mut::<RuntimeError>() {
+        error.prefix_path(path);
+    }
+    error
+}
+
+/// Like `locate`, for every error of `results`.
+fn locate_all(results: Results<'_>, path: Option<Vec<Value>>) -> Results<'_> {
+    Box::new(results.map(move |result| result.map_err(|e| locate(e, path.as_deref()))))
+}
+
+fn iterate<'a>(scope: &Scope<'a>, input: Shared<'a>, next_tokens: &'a [Token]) -> Results<'a> {
+    fn cannot_iterate<'a>(type_name: &str, input: &Value) -> Results<'a> {
+        let message = format!("Cannot iterate over {}", describe_as(type_name, input));
+        once(Err(RuntimeError::new(message, input, &[]).into()))
+    }
+
+    let scope = scope.clone();
+    let locate_at = |key: Value| {
+        move |result: anyhow::Result<_>| {
+            result.map_err(|e| locate(e, Some(std::slice::from_ref(&key))))
+        }
+    };
+    match input {
+        Shared::Borrowed(Value::Array(array)) => {
+            Box::new(array.iter().enumerate().flat_map(move |(i, v)| {
+                apply_tokens_with(&scope, Shared::Borrowed(v), next_tokens).map(locate_at(i.into()))
+            }))
+        }
+        Shared::Borrowed(Value::Object(map)) => Box::new(map.iter().flat_map(move |(k, v)| {
+            apply_tokens_with(&scope, Shared::Borrowed(v), next_tokens).map(locate_at(k[..].into()))
+        })),
+        Shared::Borrowed(input) => cannot_iterate(type_name(input), input),
+        // Values the filter built are moved out of rather than copied, unless
+        // something else still refers to them.
+        Shared::Owned(input) => match Rc::unwrap_or_clone(input) {
+            Value::Array(array) => {
+                Box::new(array.into_iter().enumerate().flat_map(move |(i, v)| {
+                    apply_tokens_with(&scope, Shared::from(v), next_tokens).map(locate_at(i.into()))
+                }))
+            }
+            Value::Object(map) => Box::new(map.into_iter().flat_map(move |(k, v)| {
+                apply_tokens_with(&scope, Shared::from(v), next_tokens).map(locate_at(k.into()))
+            })),
+            input => cannot_iterate(type_name(&input), &input),
+        },
+        Shared::NonFinite(_, input) => cannot_iterate("number", &input),
+    }
+}
+
+/// Runs a function called from `caller`, one defined by the filter or its
+/// modules before any builtin.
+fn call_function<'a>(
+    caller: &Scope<'a>,
+    name: &'a str,
+    args: &'a [Vec<Token>],
+    input: Shared<'a>,
+) -> Results<'a> {
+    match caller.function(name, args.len()) {
+        Some(Callable::Defined(definition, scope)) => {
+            // The function can call itself from its body.
+            let callable = Callable::Defined(definition, scope.clone());
+            let scope = scope.with_function(&definition.name, callable);
+            bind_parameters(scope, caller, definition, &definition.params, args, input)
+        }
+        Some(Callable::Argument(body, scope)) => apply_tokens_with(&scope, input, body),
+        Some(Callable::Value(value)) => once(Ok(value)),
+        // Builtins may run their arguments on anything, so where their errors
+        // happen isn't known.
+        None => match call_builtin(caller, name, args, &input) {
+            Ok(results) => locate_all(results, None),
+            Err(e) => once(Err(locate(e, None))),
+        },
+    }
+}
+
+/// Binds each parameter of `definition` to its argument, then runs the body.
+fn bind_parameters<'a>(
+    scope: Scope<'a>,
+    caller: &Scope<'a>,
+    definition: &'a Definition,
+    params: &'a [Parameter],
+    args: &'a [Vec<Token>],
+    input: Shared<'a>,
+) -> Results<'a> {
+    let (Some((param, params)), Some((arg, args))) = (params.split_first(), args.split_first())
+    else {
+        return apply_tokens_with(&scope, input, &definition.body);
+    };
+
+    match param {
+        // Arguments are evaluated in the context of the caller.
+        Parameter::Filter(name) => {
+            let scope = scope.with_function(name, Callable::Argument(arg, caller.clone()));
+            bind_parameters(scope, caller, definition, params, args, input)
+        }
+        Parameter::Value(name) => {
+            let caller = caller.clone();
+            let values = apply_tokens_with(&caller, input.clone(), arg);
+            and_then(values, move |value| {
+                let scope = scope
+                    .bind(name, value.clone())
+                    .with_function(name, Callable::Value(value));
+                bind_parameters(scope, &caller, definition, params, args, input.clone())
+            })
+        }
+    }
+}
+
+/// Evaluates every argument against the input and yields each combination
+/// of their outputs, the last argument changing slowest like in jq.
+fn cartesian_arguments<'a>(
+    scope: &Scope<'a>,
+    input: Shared<'a>,
+    mut args: Vec<&'a [Token]>,
+) -> Results<'a, Vec<Shared<'a>>> {
+    let Some(last) = args.pop() else {
+        return once(Ok(Vec::new()));
+    };
+
+    let scope = scope.clone();
+    let values = apply_tokens_with(&scope, input.clone(), last);
+    and_then(values, move |value| {
+        let combinations = cartesian_arguments(&scope, input.clone(), args.clone());
+        Box::new(combinations.map(move |combination| {
+            let mut combination = combination?;
+            combination.push(value.clone());
+            Ok(combination)
+        }))
+    })
+}
+
+/// Folds every output of `source` into each state produced by `init`,
+/// keeping the last output of `update` as the next state.
+fn reduce<'a>(
+    scope: &Scope<'a>,
+    input: Shared<'a>,
+    source: &'a [Token],
+    name: &'a str,
+    init: &'a [Token],
+    update: &'a [Token],
+) -> Results<'a> {
+    let scope = scope.clone();
+    let initial = apply_tokens_with(&scope, input.clone(), init);
+    and_then(initial, move |initial| {
+        let mut values = apply_tokens_with(&scope, input.clone(), source);
+        once(values.try_fold(initial, |state, value| {
+            let scope = scope.bind(name, value?);
+            let mut updated = Shared::Borrowed(&Value::Null);
+            for result in apply_tokens_with(&scope, state, update) {
+                updated = result.map_err(|e| locate(e, None))?;
+            }
+            anyhow::Ok(updated)
+        }))
+    })
+}
+
+/// Applies the operator to every combination of outputs of both sides, the
+/// right-hand side changing slowest like in jq.
+fn operate<'a>(
+    scope: &Scope<'a>,
+    input: Shared<'a>,
+    operator: Operator,
+    lhs: &'a [Token],
+    rhs: &'a [Token],
+) -> Results<'a> {
+    if let [] | [Token::Identity] = lhs {
+        return operate_on_input(scope, input, operator, rhs);
+    }
+
+    let combinations = cartesian_arguments(scope, input, vec![lhs, rhs]);
+    Box::new(combinations.map(move |values| {
+        let [a, b] = <[Shared; 2]>::try_from(values?).expect("two operands");
+        arithmetic(operator, a, b)
+    }))
+}
+
+/// Like `operate` with `.` on the left-hand side, as in `reduce` updates like
+/// `. + [$x]`. The input is moved into the result for the last output of the
+/// right-hand side rather than copied, which is only known once the next one
+/// has been asked for.
+fn operate_on_input<'a>(
+    scope: &Scope<'a>,
+    input: Shared<'a>,
+    operator: Operator,
+    rhs: &'a [Token],
+) -> Results<'a> {
+    let mut values = Some(apply_tokens_with(scope, input.clone(), rhs).peekable());
+    let mut input = Some(input);
+    Box::new(iter::from_fn(move || {
+        let rhs = values.as_mut()?;
+        let b = rhs.next()?;
+        let a = if rhs.peek().is_some() {
+            input.clone()?
+        } else {
+            // Nothing else refers to the input once the right-hand side is
+            // done with it.
+            values = None;
+            input.take()?
+        };
+        Some(b.and_then(|b| arithmetic(operator, a, b)))
+    }))
+}
+
+/// Applies the operator to both operands, only copying the left-hand side
+/// when it's changed and something else refers to it.
+fn arithmetic<'a>(operator: Operator, a: Shared<'a>, b: Shared<'a>) -> anyhow::Result<Shared<'a>> {
+    // Numbers first, since NaN is written as `null`.
+    if let (Some(x), Some(y)) = (a.as_f64(), b.as_f64()) {
+        let number = match operator {
+            Operator::Add => x + y,
+            Operator::Subtract => x - y,
+            Operator::Multiply => x * y,
+            Operator::Divide if y == 0.0 => bail!(
+                "{} and {} cannot be divided because the divisor is zero",
+                a.describe(),
+                b.describe()
+            ),
+            Operator::Divide => x / y,
+            Operator::Modulo => {
+                let (x, y) = (x as i64, y as i64);
+                if y == 0 {
+                    bail!(
+                        "{} and {} cannot be divided (remainder) because the divisor is zero",
+                        a.describe(),
+                        b.describe()
+                    );
+                }
+                return Ok(Shared::from(Value::from(x.wrapping_rem(y.wrapping_abs()))));
+            }
+        };
+        return Ok(Shared::number(number));
+    }
+
+    if operator == Operator::Add && a.is_null() {
+        return Ok(b);
+    }
+    if operator == Operator::Add && b.is_null() {
+        return Ok(a);
+    }
+    if let (Operator::Multiply, Value::String(string), Some(times)) = (operator, &*a, b.as_f64()) {
+        return repeat(string, times).map(Shared::from);
+    }
+    if let (Operator::Multiply, Some(times), Value::String(string)) = (operator, a.as_f64(), &*b) {
+        return repeat(string, times).map(Shared::from);
+    }
+    // Past this point NaN would be mistaken for `null`, and it can't be
+    // combined with anything that isn't a number anyway.
+    if matches!(a, Shared::NonFinite(..)) || matches!(b, Shared::NonFinite(..)) {
+        return Err(cannot_operate(operator, &a, &b));
+    }
+
+    let value = match (operator, a.into_owned(), &*b) {
+        (Operator::Add, Value::String(a), Value::String(b)) => Value::String(a + b),
+        (Operator::Add, Value::Array(mut a), Value::Array(b)) => {
+            a.extend(b.iter().cloned());
+            Value::Array(a)
+        }
+        (Operator::Add, Value::Object(mut a), Value::Object(b)) => {
+            a.extend(b.iter().map(|(key, value)| (key.clone(), value.clone())));
+            Value::Object(a)
+        }
+        (Operator::Subtract, Value::Array(a), Value::Array(b)) => a
+            .into_iter()
+            .filter(|value| !b.iter().any(|removed| values_equal(value, removed)))
+            .collect(),
+        (Operator::Multiply, a @ Value::Object(_), b @ Value::Object(_)) => deep_merge(a, b),
+        (Operator::Divide, Value::String(a), Value::String(b)) => split(&a, b),
+        (operator, a, _) => return Err(cannot_operate(operator, &Shared::from(a), &b)),
+    };
+
+    Ok(Shared::from(value))
+}
+
+fn as_f64(number: &serde_json::Number) -> anyhow::Result<f64> {
+    number.as_f64().context("Number out of range")
+}
+
+fn cannot_operate(operator: Operator, a: &Shared<'_>, b: &Shared<'_>) -> anyhow::Error {
+    let verb = match operator {
+        Operator::Add => "added",
+        Operator::Subtract => "subtracted",
+        Operator::Multiply => "multiplied",
+        Operator::Divide | Operator::Modulo => "divided",
+    };
+    anyhow!("{} and {} cannot be {verb}", a.describe(), b.describe())
+}
+
+/// Longest string that `*` builds, like jq's limit on the length of strings.
+const MAX_STRING_LENGTH: usize = i32::MAX as usize;
+
+fn repeat(string: &str, times: f64) -> anyhow::Result<Value> {
+    // Like jq, anything between 0 and 2 repeats the string once, and NaN
+    // doesn't repeat it at all.
+    if times.is_nan() || times <= 0.0 {
+        return Ok(Value::Null);
+    }
+    // Casting saturates, so the length check below catches huge counts.
+    let count = ((times - 1.0) as usize).saturating_add(1);
+    match string.len().checked_mul(count) {
+        Some(length) if length <= MAX_STRING_LENGTH => Ok(Value::String(string.repeat(count))),
+        _ => bail!("Repeat string result too long"),
+    }
+}
+
+/// Recursively merges `b` into `a`, like jq's `*` on objects.
+fn deep_merge(a: Value, b: &Value) -> Value {
+    match (a, b) {
+        (Value::Object(mut a), Value::Object(b)) => {
+            for (key, value) in b {
+                let merged = match a.remove(key) {
+                    Some(existing) => deep_merge(existing, value),
+                    None => value.clone(),
+                };
+                a.insert(key.clone(), merged);
+            }
+            Value::Object(a)
+        }
+        (_, b) => b.clone(),
+    }
+}
+
+fn split(string: &str, separator: &str) -> Value {
+    if string.is_empty() {
+        return json!([]);
+    }
+    if separator.is_empty() {
+        return string
+            .chars()
+            .map(|c| Value::String(c.to_string()))
+            .collect();
+    }
+    string.split(separator).map(Value::from).collect()
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// Describes a value in an error message like jq does, by its type and the
+/// start of its JSON text, like `string ("a very lon...)`.
+fn describe(value: &Value) -> String {
+    describe_as(type_name(value), value)
 }
 
-pub fn apply_tokens(input: &Value, tokens: &[Token<'_>]) -> anyhow::Result<Output> {
-    let mut output = input;
+/// Like `describe`, for a value of another type than the one it's written
+/// as, like NaN which is written as `null`.
+fn describe_as(type_name: &str, value: &Value) -> String {
+    const MAX_LENGTH: usize = 14;
+
+    let mut text = format::to_string_compact(value);
+    if text.len() > MAX_LENGTH {
+        let mut end = MAX_LENGTH - 3;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+        text.push_str("...");
+    }
+    format!("{type_name} ({text})")
+}
+
+fn as_number(value: &Value) -> anyhow::Result<f64> {
+    match value {
+        Value::Number(n) => n.as_f64().context("Number out of range"),
+        _ => bail!("{} number required", describe(value)),
+    }
+}
+
+/// Converts the result of a numeric operation back into a JSON value.
+///
+/// `serde_json` can't represent non-finite floats so, like jq does when
+/// printing them, NaN becomes `null` and infinities saturate to the largest
+/// finite float. Integral results are kept as integers so `3.7 | floor` is `3`.
+pub(crate) fn number_to_value(number: f64) -> Value {
+    if number.is_nan() {
+        return Value::Null;
+    }
+
+    let number = number.clamp(f64::MIN, f64::MAX);
+    if number.fract() == 0.0 && number.abs() < 2f64.powi(53) {
+        return Value::from(number as i64);
+    }
+
+    serde_json::Number::from_f64(number).map_or(Value::Null, Value::Number)
+}
+
+const ISO8601_FORMAT: &str = "%Y-%m-%dT%H:%M:%SZ";
+
+fn as_broken_down_time(value: &Value, name: &str) -> anyhow::Result<BrokenDownTime> {
+    let numbers = match value {
+        Value::Number(_) => return from_timestamp(as_number(value)?),
+        Value::Array(values) => values
+            .iter()
+            .map(as_number)
+            .collect::<Result<Vec<_>, _>>()?,
+        _ => bail!("{name} requires parsed datetime inputs"),
+    };
+
+    if numbers.len() < 6 {
+        bail!("{name} requires array of 6 numbers");
+    }
+    BrokenDownTime::from_numbers(&numbers)
+        .with_context(|| format!("{name} requires parsed datetime inputs"))
+}
+
+fn from_timestamp(timestamp: f64) -> anyhow::Result<BrokenDownTime> {
+    BrokenDownTime::from_timestamp(timestamp)
+        .context("error converting number of seconds since epoch to datetime")
+}
+
+fn to_timestamp(time: BrokenDownTime, name: &str) -> anyhow::Result<Value> {
+    let timestamp = time
+        .to_timestamp()
+        .with_context(|| format!("{name} requires parsed datetime inputs"))?;
+    Ok(number_to_value(timestamp))
+}
+
+fn broken_down_time_to_value(time: BrokenDownTime) -> Value {
+    time.to_numbers().into_iter().map(number_to_value).collect()
+}
+
+fn as_date_string(value: &Value) -> anyhow::Result<&str> {
+    match value {
+        Value::String(string) => Ok(string),
+        _ => bail!("strptime/1 requires string inputs and arguments"),
+    }
+}
+
+type Math1 = fn(f64) -> f64;
+type Math2 = fn(f64, f64) -> f64;
+type Math3 = fn(f64, f64, f64) -> f64;
+
+#[derive(Clone, Copy)]
+enum MathFunction {
+    Unary(Math1),
+    Binary(Math2),
+    Ternary(Math3),
+}
+
+impl MathFunction {
+    fn call(self, numbers: &[f64]) -> f64 {
+        match self {
+            MathFunction::Unary(f) => f(numbers[0]),
+            MathFunction::Binary(f) => f(numbers[0], numbers[1]),
+            MathFunction::Ternary(f) => f(numbers[0], numbers[1], numbers[2]),
+        }
+    }
+}
+
+const MATH_FUNCTIONS_1: &[(&str, Math1)] = &[
+    ("floor", f64::floor),
+    ("ceil", f64::ceil),
+    ("round", f64::round),
+    ("rint", f64::round_ties_even),
+    ("nearbyint", f64::round_ties_even),
+    ("trunc", f64::trunc),
+    ("fabs", f64::abs),
+    ("sqrt", f64::sqrt),
+    ("cbrt", f64::cbrt),
+    ("exp", f64::exp),
+    ("exp2", f64::exp2),
+    ("exp10", |n| 10f64.powf(n)),
+    ("expm1", f64::exp_m1),
+    ("log", f64::ln),
+    ("log2", f64::log2),
+    ("log10", f64::log10),
+    ("log1p", f64::ln_1p),
+    ("logb", |n| n.abs().log2().floor()),
+    ("significand", |n| n / 2f64.powf(n.abs().log2().floor())),
+    ("sin", f64::sin),
+    ("cos", f64::cos),
+    ("tan", f64::tan),
+    ("asin", f64::asin),
+    ("acos", f64::acos),
+    ("atan", f64::atan),
+    ("sinh", f64::sinh),
+    ("cosh", f64::cosh),
+    ("tanh", f64::tanh),
+    ("asinh", f64::asinh),
+    ("acosh", f64::acosh),
+    ("atanh", f64::atanh),
+];
+
+const MATH_FUNCTIONS_2: &[(&str, Math2)] = &[
+    ("pow", f64::powf),
+    ("atan2", f64::atan2),
+    ("fmin", f64::min),
+    ("fmax", f64::max),
+    ("fmod", |a, b| a % b),
+    ("fdim", |a, b| if a > b { a - b } else { 0.0 }),
+    ("hypot", f64::hypot),
+    ("copysign", f64::copysign),
+    ("ldexp", |a, b| a * 2f64.powf(b.trunc())),
+    ("scalb", |a, b| a * 2f64.powf(b)),
+    ("scalbln", |a, b| a * 2f64.powf(b.trunc())),
+];
+
+const MATH_FUNCTIONS_3: &[(&str, Math3)] = &[("fma", f64::mul_add)];
+
+fn find_function<F: Copy>(functions: &[(&str, F)], name: &str) -> Option<F> {
+    functions
+        .iter()
+        .find_map(|(function_name, f)| (*function_name == name).then_some(*f))
+}
+
+/// Functions `call_builtin` implements, by name and arity, besides the math
+/// functions.
+const BUILTINS: &[(&str, usize)] = &[
+    ("infinite", 0),
+    ("nan", 0),
+    ("isnan", 0),
+    ("isinfinite", 0),
+    ("isnormal", 0),
+    ("_negate", 0),
+    ("now", 0),
+    ("env", 0),
+    ("modulemeta", 0),
+    ("input_filename", 0),
+    ("halt", 0),
+    ("halt_error", 0),
+    ("halt_error", 1),
+    ("input", 0),
+    ("inputs", 0),
+    ("limit", 2),
+    ("first", 1),
+    ("mktime", 0),
+    ("gmtime", 0),
+    ("todate", 0),
+    ("todateiso8601", 0),
+    ("date", 0),
+    ("fromdate", 0),
+    ("fromdateiso8601", 0),
+    ("strftime", 1),
+    ("strptime", 1),
+    ("dateadd", 2),
+    ("datesub", 2),
+    ("contains", 1),
+    ("inside", 1),
+    ("indices", 1),
+    ("index", 1),
+    ("rindex", 1),
+    ("any", 0),
+    ("any", 1),
+    ("any", 2),
+    ("all", 0),
+    ("all", 1),
+    ("all", 2),
+    ("flatten", 0),
+    ("flatten", 1),
+    ("tostring", 0),
+    ("tojson", 0),
+    ("tonumber", 0),
+    ("fromjson", 0),
+    ("ascii", 0),
+    ("toarray", 0),
+    ("group_by", 1),
+    ("GROUP_BY", 1),
+    ("unique_by", 1),
+    ("UNIQUE_BY", 1),
+    ("INDEX", 1),
+    ("INDEX", 2),
+    ("IN", 1),
+    ("IN", 2),
+    ("JOIN", 2),
+    ("JOIN", 3),
+    ("JOIN", 4),
+];
+
+fn is_builtin(name: &str, arity: usize) -> bool {
+    BUILTINS.contains(&(name, arity))
+        || match arity {
+            0 => find_function(MATH_FUNCTIONS_1, name).is_some(),
+            2 => find_function(MATH_FUNCTIONS_2, name).is_some(),
+            3 => find_function(MATH_FUNCTIONS_3, name).is_some(),
+            _ => false,
+        }
+}
+
+/// Checks that every function, variable and format the tokens refer to is
+/// defined when they run in `context`, so that like in jq referring to an
+/// undefined one fails to compile rather than when it's reached.
+pub(crate) fn check_references(tokens: &[Token], context: &Context) -> anyhow::Result<()> {
+    check_references_in(tokens, context, &mut Defined::default())
+}
+
+/// Checks the body of a function defined in `context`, which can call itself.
+pub(crate) fn check_definition(definition: &Definition, context: &Context) -> anyhow::Result<()> {
+    let mut defined = Defined {
+        functions: vec![(&*definition.name, definition.params.len())],
+        variables: Vec::new(),
+    };
+    check_body(definition, context, &mut defined)
+}
+
+/// The functions and variables the filter has defined around the tokens
+/// being checked, innermost last.
+#[derive(Default)]
+struct Defined<'a> {
+    functions: Vec<(&'a str, usize)>,
+    variables: Vec<&'a str>,
+}
+
+/// Like `check_references`, with `defined` holding what the filter has
+/// defined around the tokens.
+fn check_references_in<'a>(
+    tokens: &'a [Token],
+    context: &Context,
+    defined: &mut Defined<'a>,
+) -> anyhow::Result<()> {
+    for token in tokens {
+        match token {
+            Token::Identity
+            | Token::Index(_)
+            | Token::OptionalIndex(_)
+            | Token::IterateIndex(_)
+            | Token::IterateOptionalIndex(_)
+            | Token::Key(_)
+            | Token::OptionalKey(_)
+            | Token::IterateKey(_)
+            | Token::IterateOptionalKey(_)
+            | Token::Iterate
+            | Token::Literal(_) => {}
+            Token::Format(name) if !FORMATS.contains(&&**name) => {
+                bail!(Undefined::Format(name.to_string()))
+            }
+            Token::Format(_) => {}
+            Token::Variable(name) => {
+                let is_defined = defined.variables.contains(&&**name)
+                    || context.variables.get(name).is_some()
+                    || &**name == "ENV";
+                if !is_defined {
+                    bail!(Undefined::Variable(name.to_string()));
+                }
+            }
+            Token::Array(tokens) => check_references_in(tokens, context, defined)?,
+            Token::Comma(branches) => {
+                for branch in branches {
+                    check_references_in(branch, context, defined)?;
+                }
+            }
+            Token::Bind(source, name, body) => {
+                check_references_in(source, context, defined)?;
+                defined.variables.push(name);
+                check_references_in(body, context, defined)?;
+                defined.variables.pop();
+            }
+            Token::Reduce(source, name, init, update) => {
+                check_references_in(source, context, defined)?;
+                check_references_in(init, context, defined)?;
+                defined.variables.push(name);
+                check_references_in(update, context, defined)?;
+                defined.variables.pop();
+            }
+            Token::Operation(_, lhs, rhs) => {
+                check_references_in(lhs, context, defined)?;
+                check_references_in(rhs, context, defined)?;
+            }
+            Token::Define(definition, rest) => {
+                defined
+                    .functions
+                    .push((&definition.name, definition.params.len()));
+                check_body(definition, context, defined)?;
+                check_references_in(rest, context, defined)?;
+                defined.functions.pop();
+            }
+            Token::Function(name, args) => {
+                for arg in args {
+                    check_references_in(arg, context, defined)?;
+                }
+
+                let arity = args.len();
+                let is_defined = defined.functions.contains(&(&**name, arity))
+                    || context.functions.get(name, arity).is_some()
+                    || is_builtin(name, arity);
+                if !is_defined {
+                    bail!(Undefined::Function {
+                        name: name.to_string(),
+                        arity,
+                    });
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks the body of `definition`, which sees its parameters as functions
+/// and the ones passed by value as variables too.
+fn check_body<'a>(
+    definition: &'a Definition,
+    context: &Context,
+    defined: &mut Defined<'a>,
+) -> anyhow::Result<()> {
+    let (outer_functions, outer_variables) = (defined.functions.len(), defined.variables.len());
+    for param in &definition.params {
+        match param {
+            Parameter::Filter(name) => defined.functions.push((name, 0)),
+            Parameter::Value(name) => {
+                defined.functions.push((name, 0));
+                defined.variables.push(name);
+            }
+        }
+    }
+    check_references_in(&definition.body, context, defined)?;
+    defined.functions.truncate(outer_functions);
+    defined.variables.truncate(outer_variables);
+    Ok(())
+}
+
+fn call_builtin<'a>(
+    scope: &Scope<'a>,
+    name: &'a str,
+    args: &'a [Vec<Token>],
+    shared_input: &Shared<'a>,
+) -> anyhow::Result<Results<'a>> {
+    // Arguments are run on the input as it was given, which doesn't copy it
+    // when it's borrowed.
+    let input: &Value = shared_input;
+    let value = match (name, args.len()) {
+        ("infinite", 0) => return Ok(once(Ok(Shared::number(f64::INFINITY)))),
+        ("nan", 0) => return Ok(once(Ok(Shared::number(f64::NAN)))),
+        ("isnan", 0) => Value::Bool(shared_input.to_number()?.is_nan()),
+        ("isinfinite", 0) => Value::Bool(shared_input.to_number()?.is_infinite()),
+        ("isnormal", 0) => Value::Bool(shared_input.to_number()?.is_normal()),
+        ("_negate", 0) => match shared_input.as_f64() {
+            Some(number) => return Ok(once(Ok(Shared::number(-number)))),
+            None => bail!("{} cannot be negated", describe(input)),
+        },
+        ("now", 0) => number_to_value(scope.context.clock.now()),
+        ("env", 0) => scope.context.environment.to_value(),
+        ("modulemeta", 0) => module::metadata(input, &scope.context.search_path)?,
+        ("input_filename", 0) => scope
+            .inputs
+            .as_ref()
+            .and_then(|inputs| inputs.filename())
+            .map_or(Value::Null, Value::from),
+        ("halt", 0) => bail!(Halt {
+            code: 0,
+            message: None,
+        }),
+        ("halt_error", 0) => bail!(Halt {
+            code: 5,
+            message: Some(input.clone()),
+        }),
+        ("halt_error", 1) => {
+            return Ok(call_with_arguments(
+                scope,
+                shared_input,
+                args,
+                move |input, values| {
+                    let code = match &*values[0] {
+                        Value::Number(code) => as_f64(code)? as i32,
+                        _ => bail!("halt_error/1: number required"),
+                    };
+                    bail!(Halt {
+                        code,
+                        message: Some(input.clone()),
+                    })
+                },
+            ))
+        }
+        ("input", 0) => match scope.next_input() {
+            Some(value) => value?,
+            None => bail!("No more inputs"),
+        },
+        ("inputs", 0) => {
+            // Inputs are only read as they're needed, so `first(inputs)`
+            // leaves the rest for later.
+            let scope = scope.clone();
+            let values = iter::from_fn(move || scope.next_input());
+            return Ok(Box::new(values.map(|value| Ok(Shared::from(value?)))));
+        }
+        ("limit", 2) => {
+            let (scope, input) = (scope.clone(), shared_input.clone());
+            let counts = apply_tokens_with(&scope, input.clone(), &args[0]);
+            return Ok(and_then(counts, move |count| {
+                let results = apply_tokens_with(&scope, input.clone(), &args[1]);
+                match as_number(&count) {
+                    // The generator is dropped once it has produced enough.
+                    Ok(count) if count > 0.0 => Box::new(results.take(count.ceil() as usize)),
+                    // Like in jq, a negative count doesn't limit anything.
+                    Ok(count) if count < 0.0 => results,
+                    Ok(_) => Box::new(iter::empty()),
+                    Err(e) => once(Err(e)),
+                }
+            }));
+        }
+        ("first", 1) => {
+            let results = apply_tokens_with(scope, shared_input.clone(), &args[0]);
+            return Ok(Box::new(results.take(1)));
+        }
+        ("mktime", 0) => {
+            let Value::Array(_) = input else {
+                bail!("mktime requires array of 6 numbers");
+            };
+            to_timestamp(as_broken_down_time(input, "mktime")?, "mktime")?
+        }
+        ("gmtime", 0) => broken_down_time_to_value(from_timestamp(as_number(input)?)?),
+        ("todate" | "todateiso8601" | "date", 0) => {
+            let time = as_broken_down_time(input, "strftime/1")?;
+            Value::String(time.format(ISO8601_FORMAT)?)
+        }
+        ("fromdate" | "fromdateiso8601", 0) => {
+            let time = BrokenDownTime::parse(as_date_string(input)?, ISO8601_FORMAT)?;
+            to_timestamp(time, "fromdate")?
+        }
+        ("strftime", 1) => {
+            return Ok(call_with_arguments(
+                scope,
+                shared_input,
+                args,
+                move |input, values| {
+                    let time = as_broken_down_time(input, "strftime/1")?;
+                    let Value::String(format) = &*values[0] else {
+                        bail!("strftime/1 requires a string format");
+                    };
+                    Ok(Value::String(time.format(format)?))
+                },
+            ))
+        }
+        ("strptime", 1) => {
+            return Ok(call_with_arguments(
+                scope,
+                shared_input,
+                args,
+                move |input, values| {
+                    let Value::String(format) = &*values[0] else {
+                        bail!("strptime/1 requires string inputs and arguments");
+                    };
+                    let time = BrokenDownTime::parse(as_date_string(input)?, format)?;
+                    Ok(broken_down_time_to_value(time))
+                },
+            ))
+        }
+        ("dateadd", 2) => {
+            return Ok(call_with_arguments(
+                scope,
+                shared_input,
+                args,
+                move |input, values| {
+                    Ok(number_to_value(as_number(input)? + as_number(&values[1])?))
+                },
+            ))
+        }
+        ("datesub", 2) => {
+            return Ok(call_with_arguments(
+                scope,
+                shared_input,
+                args,
+                move |input, values| {
+                    Ok(number_to_value(as_number(input)? - as_number(&values[1])?))
+                },
+            ))
+        }
+        ("contains", 1) => {
+            return Ok(call_with_arguments(
+                scope,
+                shared_input,
+                args,
+                move |input, values| check_containment(input, &values[0]).map(Value::Bool),
+            ))
+        }
+        ("inside", 1) => {
+            return Ok(call_with_arguments(
+                scope,
+                shared_input,
+                args,
+                move |input, values| check_containment(&values[0], input).map(Value::Bool),
+            ))
+        }
+        ("indices", 1) => {
+            return Ok(call_with_arguments(
+                scope,
+                shared_input,
+                args,
+                move |input, values| indices(input, &values[0]),
+            ))
+        }
+        ("index" | "rindex", 1) => {
+            return Ok(call_with_arguments(
+                scope,
+                shared_input,
+                args,
+                move |input, values| {
+                    let Value::Array(found) = indices(input, &values[0])? else {
+                        return Ok(Value::Null);
+                    };
+                    let position = if name == "index" {
+                        found.first()
+                    } else {
+                        found.last()
+                    };
+                    Ok(position.cloned().unwrap_or(Value::Null))
+                },
+            ))
+        }
+        ("any" | "all", 0) => any_or_all(scope, input, &[Token::Iterate], &[], name == "all")?,
+        ("any" | "all", 1) => any_or_all(scope, input, &[Token::Iterate], &args[0], name == "all")?,
+        ("any" | "all", 2) => any_or_all(scope, input, &args[0], &args[1], name == "all")?,
+        ("flatten", 0) => flatten(input, f64::INFINITY)?,
+        ("flatten", 1) => {
+            return Ok(call_with_arguments(
+                scope,
+                shared_input,
+                args,
+                move |input, values| flatten(input, as_number(&values[0])?),
+            ))
+        }
+        ("tostring", 0) => Value::String(to_string(input)),
+        ("tojson", 0) => Value::String(format::to_string_compact(input)),
+        ("tonumber", 0) => match input {
+            Value::Number(_) => input.clone(),
+            Value::String(string) => parse_number(string)?,
+            _ => bail!("{} cannot be parsed as a number", describe(input)),
+        },
+        ("fromjson", 0) => match input {
+            Value::String(string) => serde_json::from_str(string)
+                .map_err(|e| anyhow::anyhow!("{e} (while parsing '{string}')"))?,
+            _ => bail!("{} only strings can be parsed", describe(input)),
+        },
+        ("ascii", 0) => match input.as_u64().filter(|&n| n <= 127) {
+            Some(n) => Value::String(char::from(n as u8).to_string()),
+            None => bail!("ascii only takes numbers between 0 and 127"),
+        },
+        ("toarray", 0) => match input {
+            Value::Array(_) => input.clone(),
+            _ => json!([input]),
+        },
+        ("group_by" | "GROUP_BY", 1) => {
+            let groups = group_by(scope, input, &args[0])?;
+            groups.into_iter().map(Value::Array).collect()
+        }
+        ("unique_by" | "UNIQUE_BY", 1) => group_by(scope, input, &args[0])?
+            .into_iter()
+            .filter_map(|group| group.into_iter().next())
+            .collect(),
+        ("INDEX", 1) => index_by(scope, input, &[Token::Iterate], &args[0])?,
+        ("INDEX", 2) => index_by(scope, input, &args[0], &args[1])?,
+        ("IN", 1) => any_equal(scope, input, &[Token::Identity], &args[0])?,
+        ("IN", 2) => any_equal(scope, input, &args[0], &args[1])?,
+        ("JOIN", 2..=4) => {
+            let joined = join(scope, input, args)?;
+            return Ok(Box::new(
+                joined.into_iter().map(|value| Ok(Shared::from(value))),
+            ));
+        }
+        _ => return call_math_function(scope, name, args, shared_input),
+    };
+
+    Ok(once(Ok(Shared::from(value))))
+}
+
+/// Formats that can be applied with `@name`.
+const FORMATS: &[&str] = &["text", "json"];
+
+fn apply_format(name: &str, input: &Value) -> anyhow::Result<Value> {
+    let formatted = match name {
+        "text" => to_string(input),
+        "json" => format::to_string_compact(input),
+        _ => bail!("{name} is not a valid format"),
+    };
+
+    Ok(Value::String(formatted))
+}
+
+fn to_string(value: &Value) -> String {
+    match value {
+        Value::String(string) => string.clone(),
+        _ => format::to_string_compact(value),
+    }
+}
+
+/// Parses a string with jq's number syntax, which is more lenient than JSON's
+/// and also accepts numbers like `.5`, `1.` and `nan`.
+fn parse_number(string: &str) -> anyhow::Result<Value> {
+    let trimmed = string.trim();
+    if let Ok(number) = serde_json::from_str::<serde_json::Number>(trimmed) {
+        return Ok(Value::Number(number));
+    }
+    if matches!(trimmed, "nan" | "-nan") {
+        return Ok(number_to_value(f64::NAN));
+    }
+
+    let is_number_syntax = !trimmed.starts_with('+')
+        && trimmed
+            .chars()
+            .all(|c| c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E'));
+    match trimmed.parse() {
+        Ok(number) if is_number_syntax => Ok(number_to_value(number)),
+        _ => bail!("Cannot parse '{string}' as JSON"),
+    }
+}
+
+/// Compares values the way jq does, where numbers are equal regardless of
+/// whether they were written as integers or floats.
+fn values_equal(a: &Value, b: &Value) -> bool {
+    compare_values(a, b) == Ordering::Equal
+}
+
+/// Orders values following jq's sort order: `null`, `false`, `true`, numbers,
+/// strings, arrays and then objects, which are compared first by their sorted
+/// keys and then by their values.
+fn compare_values(a: &Value, b: &Value) -> Ordering {
+    fn rank(value: &Value) -> u8 {
+        match value {
+            Value::Null => 0,
+            Value::Bool(false) => 1,
+            Value::Bool(true) => 2,
+            Value::Number(_) => 3,
+            Value::String(_) => 4,
+            Value::Array(_) => 5,
+            Value::Object(_) => 6,
+        }
+    }
+
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => match (a.as_i64(), b.as_i64()) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            _ => a
+                .as_f64()
+                .partial_cmp(&b.as_f64())
+                .unwrap_or(Ordering::Equal),
+        },
+        (Value::String(a), Value::String(b)) => a.cmp(b),
+        (Value::Array(a), Value::Array(b)) => a
+            .iter()
+            .zip(b)
+            .map(|(a, b)| compare_values(a, b))
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or_else(|| a.len().cmp(&b.len())),
+        (Value::Object(a), Value::Object(b)) => {
+            let mut a_keys: Vec<_> = a.keys().collect();
+            let mut b_keys: Vec<_> = b.keys().collect();
+            a_keys.sort();
+            b_keys.sort();
+
+            a_keys.cmp(&b_keys).then_with(|| {
+                a_keys
+                    .iter()
+                    .map(|key| compare_values(&a[key.as_str()], &b[key.as_str()]))
+                    .find(|ordering| ordering.is_ne())
+                    .unwrap_or(Ordering::Equal)
+            })
+        }
+        (a, b) => rank(a).cmp(&rank(b)),
+    }
+}
+
+/// Sorts the input array by the outputs of `key` and splits it into groups of
+/// elements sharing the same key.
+fn group_by(scope: &Scope, input: &Value, key: &[Token]) -> anyhow::Result<Vec<Vec<Value>>> {
+    let Value::Array(array) = input else {
+        bail!("Cannot index {} with number", type_name(input));
+    };
+
+    let mut keyed = array
+        .iter()
+        .map(|value| {
+            let key = apply_tokens_with(scope, Shared::Borrowed(value), key)
+                .map(|key| key.map(Shared::into_owned))
+                .collect::<anyhow::Result<_>>()?;
+            let key = Value::Array(key);
+            Ok((key, value.clone()))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    keyed.sort_by(|(a, _), (b, _)| compare_values(a, b));
+
+    let mut groups: Vec<(Value, Vec<Value>)> = Vec::new();
+    for (key, value) in keyed {
+        match groups.last_mut() {
+            Some((last_key, group)) if values_equal(last_key, &key) => group.push(value),
+            _ => groups.push((key, vec![value])),
+        }
+    }
+
+    Ok(groups.into_iter().map(|(_, group)| group).collect())
+}
+
+/// Builds an object out of the outputs of `stream`, keyed by `key` converted to a string.
+fn index_by(
+    scope: &Scope,
+    input: &Value,
+    stream: &[Token],
+    key: &[Token],
+) -> anyhow::Result<Value> {
+    let mut index = serde_json::Map::new();
+    for row in apply_tokens_with(scope, Shared::Borrowed(input), stream) {
+        let row = row?;
+        for key in apply_tokens_with(scope, row.clone(), key) {
+            index.insert(to_string(&*key?), row.clone().into_owned());
+        }
+    }
+
+    Ok(Value::Object(index))
+}
+
+/// Whether any output of `source` is equal to any output of `stream`.
+fn any_equal(
+    scope: &Scope,
+    input: &Value,
+    source: &[Token],
+    stream: &[Token],
+) -> anyhow::Result<Value> {
+    let candidates =
+        apply_tokens_with(scope, Shared::Borrowed(input), stream).collect::<Result<Vec<_>, _>>()?;
+    for value in apply_tokens_with(scope, Shared::Borrowed(input), source) {
+        let value = value?;
+        if candidates.iter().any(|c| values_equal(&value, c)) {
+            return Ok(Value::Bool(true));
+        }
+    }
+
+    Ok(Value::Bool(false))
+}
+
+/// Pairs every row of a stream with the entry of an `INDEX`ed object it refers to.
+///
+/// Takes the index, then optionally the stream of rows (defaulting to the
+/// elements of the input), the expression producing each row's key, and
+/// optionally an expression the `[row, match]` pairs are piped into, in which
+/// case the pairs are emitted one at a time instead of collected in an array.
+fn join(scope: &Scope, input: &Value, args: &[Vec<Token>]) -> anyhow::Result<Vec<Value>> {
+    let (index, stream, key, join) = match args {
+        [index, key] => (index, &[Token::Iterate][..], key, None),
+        [index, stream, key] => (index, &stream[..], key, None),
+        [index, stream, key, join] => (index, &stream[..], key, Some(join)),
+        _ => unreachable!("JOIN takes 2 to 4 arguments"),
+    };
+
+    let mut outputs = Vec::new();
+    for index in apply_tokens_with(scope, Shared::Borrowed(input), index) {
+        let index = index?;
+        let mut pairs = Vec::new();
+        for row in apply_tokens_with(scope, Shared::Borrowed(input), stream) {
+            let row = row?;
+            let mut pair = vec![row.clone().into_owned()];
+            for key in apply_tokens_with(scope, row, key) {
+                let key = key?;
+                let matched = match (&*index, &*key) {
+                    (Value::Object(index), Value::String(key)) => {
+                        index.get(key).cloned().unwrap_or(Value::Null)
+                    }
+                    (Value::Null, _) => Value::Null,
+                    _ => bail!(
+                        "Cannot index {} with {}",
+                        type_name(&index),
+                        type_name(&key)
+                    ),
+                };
+                pair.push(matched);
+            }
+            pairs.push(Value::Array(pair));
+        }
+
+        match join {
+            Some(join) => {
+                for pair in pairs {
+                    for output in apply_tokens_with(scope, Shared::from(pair), join) {
+                        outputs.push(output?.into_owned());
+                    }
+                }
+            }
+            None if args.len() == 2 => outputs.push(Value::Array(pairs)),
+            None => outputs.extend(pairs),
+        }
+    }
+
+    Ok(outputs)
+}
+
+fn is_truthy(value: &Value) -> bool {
+    !matches!(value, Value::Null | Value::Bool(false))
+}
+
+fn check_containment(a: &Value, b: &Value) -> anyhow::Result<bool> {
+    if type_name(a) != type_name(b) {
+        bail!(
+            "{} and {} cannot have their containment checked",
+            describe(a),
+            describe(b)
+        );
+    }
+
+    Ok(contains(a, b))
+}
+
+/// Whether `a` contains `b` following jq's rules: substrings for strings,
+/// every element or key being recursively contained for arrays and objects,
+/// and plain equality for everything else.
+fn contains(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Object(a), Value::Object(b)) => b
+            .iter()
+            .all(|(key, b)| a.get(key).is_some_and(|a| contains(a, b))),
+        (Value::Array(a), Value::Array(b)) => b.iter().all(|b| a.iter().any(|a| contains(a, b))),
+        (Value::String(a), Value::String(b)) => a.contains(b.as_str()),
+        (a, b) => values_equal(a, b),
+    }
+}
+
+fn indices(input: &Value, target: &Value) -> anyhow::Result<Value> {
+    let found: Vec<usize> = match (input, target) {
+        (Value::Null, _) => return Ok(Value::Null),
+        (Value::String(_), Value::String(target)) if target.is_empty() => return Ok(Value::Null),
+        (Value::Array(_), Value::Array(target)) if target.is_empty() => return Ok(Value::Null),
+        (Value::String(string), Value::String(target)) => string
+            .char_indices()
+            .enumerate()
+            .filter(|(_, (byte, _))| string[*byte..].starts_with(target.as_str()))
+            .map(|(char_index, _)| char_index)
+            .collect(),
+        (Value::Array(array), Value::Array(target)) => array
+            .windows(target.len())
+            .enumerate()
+            .filter(|(_, window)| window.iter().zip(target).all(|(a, b)| values_equal(a, b)))
+            .map(|(i, _)| i)
+            .collect(),
+        (Value::Array(array), target) => array
+            .iter()
+            .enumerate()
+            .filter(|(_, value)| values_equal(value, target))
+            .map(|(i, _)| i)
+            .collect(),
+        (input, target) => bail!(
+            "Cannot determine indices of {} in {}",
+            type_name(target),
+            type_name(input)
+        ),
+    };
+
+    Ok(found.into_iter().map(Value::from).collect())
+}
+
+/// Checks the `condition` against every output of `generator`, an empty
+/// condition meaning the outputs themselves are checked.
+fn any_or_all(
+    scope: &Scope,
+    input: &Value,
+    generator: &[Token],
+    condition: &[Token],
+    all: bool,
+) -> anyhow::Result<Value> {
+    for value in apply_tokens_with(scope, Shared::Borrowed(input), generator) {
+        for result in apply_tokens_with(scope, value?, condition) {
+            if is_truthy(&*result?) != all {
+                return Ok(Value::Bool(!all));
+            }
+        }
+    }
+
+    Ok(Value::Bool(all))
+}
+
+fn flatten(input: &Value, depth: f64) -> anyhow::Result<Value> {
+    if depth < 0.0 {
+        bail!("flatten depth must not be negative");
+    }
+
+    let values: Vec<&Value> = match input {
+        Value::Array(array) => array.iter().collect(),
+        Value::Object(map) => map.values().collect(),
+        _ => bail!("Cannot iterate over {}", describe(input)),
+    };
+
+    let mut flattened = Vec::new();
+    flatten_into(values, depth, &mut flattened);
+
+    Ok(Value::Array(flattened))
+}
+
+fn flatten_into<'v>(
+    values: impl IntoIterator<Item = &'v Value>,
+    depth: f64,
+    flattened: &mut Vec<Value>,
+) {
+    for value in values {
+        match value {
+            Value::Array(inner) if depth > 0.0 => flatten_into(inner, depth - 1.0, flattened),
+            value => flattened.push(value.clone()),
+        }
+    }
+}
+
+/// Calls `f` with the input and every combination of the arguments' outputs.
+fn call_with_arguments<'a>(
+    scope: &Scope<'a>,
+    input: &Shared<'a>,
+    args: &'a [Vec<Token>],
+    f: impl Fn(&Value, &[Shared<'_>]) -> anyhow::Result<Value> + 'a,
+) -> Results<'a> {
+    let args = args.iter().map(Vec::as_slice).collect();
+    let combinations = cartesian_arguments(scope, input.clone(), args);
+    let input = input.clone();
+    Box::new(combinations.map(move |values| f(&input, &values?).map(Shared::from)))
+}
+
+fn call_math_function<'a>(
+    scope: &Scope<'a>,
+    name: &str,
+    args: &'a [Vec<Token>],
+    input: &Shared<'a>,
+) -> anyhow::Result<Results<'a>> {
+    let function = match args.len() {
+        0 => find_function(MATH_FUNCTIONS_1, name).map(MathFunction::Unary),
+        2 => find_function(MATH_FUNCTIONS_2, name).map(MathFunction::Binary),
+        3 => find_function(MATH_FUNCTIONS_3, name).map(MathFunction::Ternary),
+        _ => None,
+    }
+    .with_context(|| format!("{name}/{} is not defined", args.len()))?;
+
+    if args.is_empty() {
+        return Ok(once(Ok(Shared::number(
+            function.call(&[input.to_number()?]),
+        ))));
+    }
+
+    // Like `call_with_arguments`, but keeping NaN and infinities apart.
+    let args = args.iter().map(Vec::as_slice).collect();
+    let combinations = cartesian_arguments(scope, input.clone(), args);
+    Ok(Box::new(combinations.map(move |values| {
+        let numbers = values?
+            .iter()
+            .map(Shared::to_number)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Shared::number(function.call(&numbers)))
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn apply_tokens(input: &Value, tokens: &[Token]) -> anyhow::Result<Vec<Value>> {
+        apply_tokens_in(&Context::default(), input, tokens)
+    }
+
+    fn apply_tokens_in(
+        context: &Context,
+        input: &Value,
+        tokens: &[Token],
+    ) -> anyhow::Result<Vec<Value>> {
+        apply_tokens_with(&Scope::new(context), Shared::Borrowed(input), tokens)
+            .map(|value| value.map(Shared::into_owned))
+            .collect()
+    }
+
+    fn apply_operator(operator: Operator, a: Value, b: Value) -> anyhow::Result<Value> {
+        arithmetic(operator, Shared::from(a), Shared::from(b)).map(Shared::into_owned)
+    }
+
+    #[test]
+    fn apply_identity_token() {
+        let input = json!({"quotes": ["a", "b", "c"]});
+        let tokens = vec![Token::Identity];
+
+        assert_eq!(apply_tokens(&input, &tokens).unwrap(), vec![input]);
+    }
+
+    #[test]
+    fn apply_index_to_array() {
+        let input = json!([1, 2, 3]);
+        let tokens = vec![Token::Index(2)];
+
+        assert_eq!(apply_tokens(&input, &tokens).unwrap(), vec![json!(3)]);
+    }
+
+    #[test]
+    fn apply_out_of_bounds_index_to_array() {
+        let input = json!([1, 2, 3]);
+        let tokens = vec![Token::Index(3)];
+
+        assert_eq!(apply_tokens(&input, &tokens).unwrap(), vec![Value::Null]);
+    }
+
+    #[test]
+    fn apply_index_to_non_array() {
+        let tokens = vec![Token::Index(2)];
+
+        let input = json!("1");
+        assert!(apply_tokens(&input, &tokens).is_err());
+        let input = json!(1);
+        assert!(apply_tokens(&input, &tokens).is_err());
+        let input = json!({"hello": "world"});
+        assert!(apply_tokens(&input, &tokens).is_err());
+    }
+
+    #[test]
+    fn apply_errors_are_worded_like_jq() {
+        let error = |input: Value, tokens: Vec<Token>| {
+            apply_tokens(&input, &tokens).unwrap_err().to_string()
+        };
+
+        assert_eq!(
+            error(json!("abc"), vec![Token::Key("foo".into())]),
+            "Cannot index string with \"foo\""
+        );
+        assert_eq!(
+            error(
+                json!({"a": [1]}),
+                vec![Token::Key("a".into()), Token::IterateKey("b".into())]
+            ),
+            "Cannot index array with \"b\""
+        );
+        assert_eq!(
+            error(json!({"a": 1}), vec![Token::Index(0)]),
+            "Cannot index object with number"
+        );
+        assert_eq!(
+            error(json!(5), vec![Token::Iterate]),
+            "Cannot iterate over number (5)"
+        );
+        assert_eq!(
+            error(json!({"a": null}), vec![Token::IterateKey("a".into())]),
+            "Cannot iterate over null (null)"
+        );
+        assert_eq!(
+            error(json!("a very long string value"), vec![Token::Iterate]),
+            "Cannot iterate over string (\"a very lon...)"
+        );
+        assert_eq!(
+            apply_operator(Operator::Add, json!({"aaaaaaaaaaaaa": 1}), json!(1))
+                .unwrap_err()
+                .to_string(),
+            "object ({\"aaaaaaaaa...) and number (1) cannot be added"
+        );
+        assert_eq!(
+            apply_operator(Operator::Modulo, json!(5), json!(0.4))
+                .unwrap_err()
+                .to_string(),
+            "number (5) and number (0.4) cannot be divided (remainder) because the divisor is zero"
+        );
+    }
+
+    #[test]
+    fn describe_cuts_values_on_character_boundaries() {
+        assert_eq!(describe(&json!([1, 2])), "array ([1,2])");
+        assert_eq!(describe(&json!("12345678901")), "string (\"12345678901\")");
+        assert_eq!(
+            describe(&json!("123456789012")),
+            "string (\"123456789012\")"
+        );
+        assert_eq!(
+            describe(&json!("1234567890123")),
+            "string (\"1234567890...)"
+        );
+        assert_eq!(describe(&json!("é€é€é€é€")), "string (\"é€é€...)");
+    }
+
+    #[test]
+    fn apply_index_to_array_chained() {
+        let input = json!([[1, 2, 3], [4, 5, 6]]);
+        let tokens = vec![Token::Index(1), Token::Index(0)];
+
+        assert_eq!(apply_tokens(&input, &tokens).unwrap(), vec![json!(4)]);
+    }
+
+    #[test]
+    fn apply_index_to_array_chained_with_identity() {
+        let input = json!([[1, 2, 3], [4, 5, 6]]);
+        let tokens = vec![Token::Index(1), Token::Identity, Token::Index(0)];
+
+        assert_eq!(apply_tokens(&input, &tokens).unwrap(), vec![json!(4)]);
+    }
+
+    #[test]
+    fn apply_key_to_object() {
+        let input = json!({"hello": "world"});
+        let tokens = vec![Token::Key("hello".into())];
+
+        assert_eq!(apply_tokens(&input, &tokens).unwrap(), vec![json!("world")]);
+    }
+
+    #[test]
+    fn apply_non_existent_key_to_object() {
+        let input = json!({"hello": "world"});
+        let tokens = vec![Token::Key("missing".into())];
+
+        assert_eq!(apply_tokens(&input, &tokens).unwrap(), vec![Value::Null]);
+    }
+
+    #[test]
+    fn apply_key_to_non_object() {
+        let tokens = vec![Token::Key("hello".into())];
+
+        let input = json!("1");
+        assert!(apply_tokens(&input, &tokens).is_err());
+        let input = json!(1);
+        assert!(apply_tokens(&input, &tokens).is_err());
+        let input = json!([1, 2, 3, 4]);
+        assert!(apply_tokens(&input, &tokens).is_err());
+    }
+
+    #[test]
+    fn apply_key_to_object_chained() {
+        let input = json!({"hello": {"world": 42}});
+        let tokens = vec![Token::Key("hello".into()), Token::Key("world".into())];
+
+        assert_eq!(apply_tokens(&input, &tokens).unwrap(), vec![json!(42)]);
+    }
+
+    #[test]
+    fn apply_chain_key_and_index_access() {
+        let input = json!({"key": [1,2,3]});
+        let tokens = vec![Token::Key("key".into()), Token::Index(0)];
+
+        assert_eq!(apply_tokens(&input, &tokens).unwrap(), vec![json!(1)]);
+    }
+
+    #[test]
+    fn apply_optional_index_to_non_array() {
+        let tokens = vec![Token::OptionalIndex(1)];
+
+        let input = json!("1");
+        assert_eq!(apply_tokens(&input, &tokens).unwrap(), vec![Value::Null]);
+        let input = json!(1);
+        assert_eq!(apply_tokens(&input, &tokens).unwrap(), vec![Value::Null]);
+        let input = json!({"hello": "world"});
+        assert_eq!(apply_tokens(&input, &tokens).unwrap(), vec![Value::Null]);
+    }
+
+    #[test]
+    fn apply_optional_key_to_non_object() {
+        let tokens = vec![Token::OptionalKey("hello".into())];
+
+        let input = json!("1");
+        assert_eq!(apply_tokens(&input, &tokens).unwrap(), vec![Value::Null]);
+        let input = json!(1);
+        assert_eq!(apply_tokens(&input, &tokens).unwrap(), vec![Value::Null]);
+        let input = json!([1, 2, 3, 4]);
+        assert_eq!(apply_tokens(&input, &tokens).unwrap(), vec![Value::Null]);
+    }
+
+    #[test]
+    fn apply_iterator() {
+        let tokens = vec![Token::Iterate];
+
+        let input = json!([[1, [2, 3]], [4, [5, 6]], [7, [8, 9]]]);
+        let res = apply_tokens(&input, &tokens).unwrap();
+
+        let expected = vec![json!([1, [2, 3]]), json!([4, [5, 6]]), json!([7, [8, 9]])];
+
+        assert_eq!(res, expected);
+    }
+
+    #[test]
+    fn apply_iterator_chained() {
+        let tokens = vec![Token::Iterate, Token::Iterate];
+
+        let input = json!([[1, [2, 3]], [4, [5, 6]], [7, [8, 9]]]);
+        let res = apply_tokens(&input, &tokens).unwrap();
+
+        let expected = vec![
+            json!(1),
+            json!([2, 3]),
+            json!(4),
+            json!([5, 6]),
+            json!(7),
+            json!([8, 9]),
+        ];
+        assert_eq!(res, expected);
+    }
+
+    #[test]
+    fn apply_iterator_chained_twice() {
+        let tokens = vec![Token::Iterate, Token::Iterate, Token::Iterate];
+
+        let input = json!([[1, [2, 3]], [4, [5, 6]], [7, [8, 9]]]);
+        let res = apply_tokens(&input, &tokens);
+
+        // NOTE: jq doesn't allow iterating over primitive values
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn apply_iterator_on_object() {
+        let tokens = vec![Token::Iterate];
+
+        let input = json!({"hello": "a", "world": "b"});
+        let res = apply_tokens(&input, &tokens).unwrap();
+
+        let expected = vec![json!("a"), json!("b")];
+
+        assert_eq!(res, expected);
+    }
+
+    #[test]
+    fn apply_iterator_on_array_in_object() {
+        let tokens = vec![Token::Iterate, Token::Iterate];
+
+        let input = json!({"hello": ["a", "b"], "world": ["c"]});
+        let res = apply_tokens(&input, &tokens).unwrap();
+
+        let expected = vec![json!("a"), json!("b"), json!("c")];
+
+        assert_eq!(res, expected);
+    }
+
+    #[test]
+    fn apply_wrap_in_array() {
+        let tokens = vec![Token::Array(vec![Token::Key("hello".into())])];
+        let input = json!({"hello": "a"});
+
+        let res = apply_tokens(&input, &tokens);
+        let expected = vec![json!(["a"])];
+
+        assert_eq!(res.unwrap(), expected)
+    }
+
+    #[test]
+    fn apply_wrap_in_array_multiple_values() {
+        let tokens = vec![Token::Array(vec![Token::IterateKey("hello".into())])];
+        let input = json!({"hello": ["a","b", "c"]});
+
+        let res = apply_tokens(&input, &tokens);
+        let expected = vec![json!(["a", "b", "c"])];
+
+        assert_eq!(res.unwrap(), expected)
+    }
+
+    #[test]
+    fn apply_wrap_array_in_array() {
+        let tokens = vec![Token::Array(vec![Token::Key("hello".into())])];
+        let input = json!({"hello": ["a","b", "c"]});
+
+        let res = apply_tokens(&input, &tokens);
+        let expected = vec![json!([["a", "b", "c"]])];
+
+        assert_eq!(res.unwrap(), expected)
+    }
+
+    #[test]
+    fn apply_one_argument_math_functions() {
+        let input = json!(3.7);
+
+        let cases = [
+            ("floor", json!(3)),
+            ("ceil", json!(4)),
+            ("round", json!(4)),
+            ("trunc", json!(3)),
+            ("fabs", json!(3.7)),
+        ];
+        for (name, expected) in cases {
+            let tokens = vec![Token::Function(name.into(), vec![])];
+            assert_eq!(
+                apply_tokens(&input, &tokens).unwrap(),
+                vec![expected],
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn apply_math_function_keeps_integers() {
+        let tokens = vec![Token::Function("sqrt".into(), vec![])];
+        let input = json!(16);
+
+        assert_eq!(apply_tokens(&input, &tokens).unwrap(), vec![json!(4)]);
+    }
+
+    #[test]
+    fn apply_math_function_to_non_number() {
+        let tokens = vec![Token::Function("floor".into(), vec![])];
+        let input = json!("1");
+
+        assert!(apply_tokens(&input, &tokens).is_err());
+    }
+
+    #[test]
+    fn apply_two_argument_math_function() {
+        let tokens = vec![Token::Function(
+            "pow".into(),
+            vec![
+                vec![Token::Key("base".into())],
+                vec![Token::Literal(json!(2))],
+            ],
+        )];
+        let input = json!({"base": 3});
+
+        assert_eq!(apply_tokens(&input, &tokens).unwrap(), vec![json!(9)]);
+    }
+
+    #[test]
+    fn apply_three_argument_math_function() {
+        let args = [2, 3, 4].map(|n| vec![Token::Literal(json!(n))]).to_vec();
+        let tokens = vec![Token::Function("fma".into(), args)];
+
+        assert_eq!(
+            apply_tokens(&Value::Null, &tokens).unwrap(),
+            vec![json!(10)]
+        );
+    }
+
+    #[test]
+    fn apply_math_function_with_wrong_arity() {
+        let tokens = vec![Token::Function("floor".into(), vec![vec![Token::Identity]])];
+
+        assert!(apply_tokens(&json!(1), &tokens).is_err());
+    }
+
+    #[test]
+    fn apply_non_finite_numbers() {
+        let nan = vec![Token::Function("nan".into(), vec![])];
+        let infinite = vec![Token::Function("infinite".into(), vec![])];
+
+        assert_eq!(apply_tokens(&Value::Null, &nan).unwrap(), vec![Value::Null]);
+        assert_eq!(
+            apply_tokens(&Value::Null, &infinite).unwrap(),
+            vec![json!(f64::MAX)]
+        );
+    }
+
+    #[test]
+    fn apply_number_predicates() {
+        let cases = [
+            ("nan", "isnan", true),
+            ("infinite", "isnan", false),
+            ("infinite", "isinfinite", true),
+            ("nan", "isinfinite", false),
+            ("infinite", "isnormal", false),
+            ("nan", "isnormal", false),
+        ];
+        for (value, predicate, expected) in cases {
+            let tokens = vec![
+                Token::Function(value.into(), vec![]),
+                Token::Function(predicate.into(), vec![]),
+            ];
+            assert_eq!(
+                apply_tokens(&Value::Null, &tokens).unwrap(),
+                vec![json!(expected)],
+                "{value} | {predicate}"
+            );
+        }
+
+        let tokens = vec![Token::Function("isnormal".into(), vec![])];
+        assert_eq!(
+            apply_tokens(&json!(1.5), &tokens).unwrap(),
+            vec![json!(true)]
+        );
+        assert_eq!(
+            apply_tokens(&json!(0), &tokens).unwrap(),
+            vec![json!(false)]
+        );
+    }
+
+    #[test]
+    fn number_predicates_tell_non_finite_numbers_apart() {
+        let predicate = |name: &str| vec![Token::Function(name.into(), vec![])];
+
+        // The values NaN and infinities are written as aren't them.
+        assert_eq!(
+            apply_tokens(&json!(f64::MAX), &predicate("isinfinite")).unwrap(),
+            vec![json!(false)]
+        );
+        assert_eq!(
+            apply_tokens(&json!(f64::MAX), &predicate("isnormal")).unwrap(),
+            vec![json!(true)]
+        );
+        for name in ["isnan", "isinfinite", "isnormal"] {
+            let error = apply_tokens(&Value::Null, &predicate(name)).unwrap_err();
+            assert_eq!(error.to_string(), "null (null) number required", "{name}");
+            assert!(apply_tokens(&json!("a"), &predicate(name)).is_err());
+        }
+
+        // They stay non-finite through variables and arithmetic.
+        let tokens = vec![Token::Bind(
+            predicate("infinite"),
+            "x".into(),
+            vec![
+                Token::Operation(
+                    Operator::Subtract,
+                    vec![Token::Variable("x".into())],
+                    vec![Token::Literal(json!(1))],
+                ),
+                Token::Function("isinfinite".into(), vec![]),
+            ],
+        )];
+        assert_eq!(
+            apply_tokens(&Value::Null, &tokens).unwrap(),
+            vec![json!(true)]
+        );
+        let tokens = vec![
+            Token::Operation(
+                Operator::Add,
+                predicate("nan"),
+                vec![Token::Literal(json!(1))],
+            ),
+            Token::Function("isnan".into(), vec![]),
+        ];
+        assert_eq!(
+            apply_tokens(&Value::Null, &tokens).unwrap(),
+            vec![json!(true)]
+        );
+    }
+
+    #[test]
+    fn nan_is_a_number_rather_than_null() {
+        let nan = || Token::Function("nan".into(), vec![]);
+        let literal = |value: Value| vec![Token::Literal(value)];
+        let error = |tokens: &[Token]| apply_tokens(&Value::Null, tokens).unwrap_err().to_string();
+
+        assert_eq!(
+            error(&[nan(), Token::Key("a".into())]),
+            "Cannot index number with \"a\""
+        );
+        assert_eq!(
+            error(&[nan(), Token::Index(0)]),
+            "Cannot index number with number"
+        );
+        assert_eq!(
+            error(&[Token::Operation(
+                Operator::Add,
+                vec![nan()],
+                literal(json!("a"))
+            )]),
+            "number (null) and string (\"a\") cannot be added"
+        );
+        assert_eq!(
+            error(&[Token::Operation(
+                Operator::Add,
+                literal(json!("a")),
+                vec![nan()]
+            )]),
+            "string (\"a\") and number (null) cannot be added"
+        );
+
+        // Like in jq, `null` is still the identity of addition and NaN
+        // doesn't repeat strings.
+        let tokens = vec![
+            Token::Operation(Operator::Add, literal(Value::Null), vec![nan()]),
+            Token::Function("isnan".into(), vec![]),
+        ];
+        assert_eq!(
+            apply_tokens(&Value::Null, &tokens).unwrap(),
+            vec![json!(true)]
+        );
+        let tokens = vec![Token::Operation(
+            Operator::Multiply,
+            literal(json!("ab")),
+            vec![nan()],
+        )];
+        assert_eq!(
+            apply_tokens(&Value::Null, &tokens).unwrap(),
+            vec![Value::Null]
+        );
+
+        // NaN stays a number in variables, but arrays hold the `null` it's
+        // written as, so unlike in jq it can't be told apart once put in one.
+        let tokens = vec![Token::Bind(
+            vec![nan()],
+            "x".into(),
+            vec![
+                Token::Variable("x".into()),
+                Token::Function("isnan".into(), vec![]),
+            ],
+        )];
+        assert_eq!(
+            apply_tokens(&Value::Null, &tokens).unwrap(),
+            vec![json!(true)]
+        );
+        let tokens = vec![
+            Token::Array(vec![nan()]),
+            Token::Index(0),
+            Token::Function("isnan".into(), vec![]),
+        ];
+        assert_eq!(error(&tokens), "null (null) number required");
+    }
 
-    for (i, token) in tokens.iter().enumerate() {
-        match token {
-            Token::Identity => {}
-            Token::Index(_) | Token::IterateIndex(_) if !output.is_array() => {
-                bail!("Can't index into non array value");
-            }
-            Token::Index(index) | Token::OptionalIndex(index) => {
-                output = output.get(index).unwrap_or(&Value::Null);
-            }
-            Token::Key(_) | Token::IterateKey(_) if !output.is_object() => {
-                bail!("Can't access key of non object value");
-            }
-            Token::Key(key) | Token::OptionalKey(key) => {
-                output = output.get(key).unwrap_or(&Value::Null);
-            }
-            Token::IterateIndex(index) | Token::IterateOptionalIndex(index) => {
-                output = output.get(index).unwrap_or(&Value::Null);
-                return iterate(output, &tokens[i + 1..]);
-            }
-            Token::IterateKey(key) | Token::IterateOptionalKey(key) => {
-                output = output.get(key).unwrap_or(&Value::Null);
-                return iterate(output, &tokens[i + 1..]);
-            }
-            Token::Iterate => return iterate(output, &tokens[i + 1..]),
-            Token::Array(array) => {
-                let applied = apply_tokens(output, array)?;
-                let wrapped = match applied {
-                    Output::Single(value) => json!([value]),
-                    Output::Multiple(values) => {
-                        values.into_iter().map(unwrap_output).collect::<Value>()
-                    }
-                };
+    #[test]
+    fn apply_negation() {
+        let negate = || Token::Function("_negate".into(), vec![]);
 
-                return apply_tokens(&wrapped, &tokens[i + 1..]);
-            }
-        }
+        assert_eq!(
+            apply_tokens(&json!(2.5), &[negate()]).unwrap(),
+            vec![json!(-2.5)]
+        );
+        let tokens = vec![
+            Token::Function("infinite".into(), vec![]),
+            negate(),
+            Token::Function("isinfinite".into(), vec![]),
+        ];
+        assert_eq!(
+            apply_tokens(&Value::Null, &tokens).unwrap(),
+            vec![json!(true)]
+        );
+        assert_eq!(
+            apply_tokens(&json!("a"), &[negate()])
+                .unwrap_err()
+                .to_string(),
+            "string (\"a\") cannot be negated"
+        );
     }
 
-    Ok(Output::Single(output.to_owned()))
-}
+    #[test]
+    fn infinite_output_to_string() {
+        let output = vec![json!(f64::MAX)];
 
-fn unwrap_output(output: Output) -> Value {
-    match output {
-        Output::Single(value) => value,
-        Output::Multiple(values) => values.into_iter().map(unwrap_output).collect::<Value>(),
+        assert_eq!(
+            format::format_values(&output, &format::OutputOptions::default()),
+            "1.7976931348623157e+308"
+        );
     }
-}
-
-fn iterate(input: &Value, next_tokens: &[Token<'_>]) -> anyhow::Result<Output> {
-    match input {
-        Value::Null | Value::Bool(_) | Value::Number(_) | Value::String(_) => {
-            bail!("Cannot iterate on primitive values")
-        }
-        Value::Array(array) => {
-            let transformed = array
-                .iter()
-                .map(|v| apply_tokens(v, next_tokens))
-                .collect::<Result<Vec<_>, _>>()?;
-            Ok(Output::Multiple(transformed))
-        }
-        Value::Object(map) => {
-            let transformed = map
-                .into_iter()
-                .map(|(_, v)| apply_tokens(v, next_tokens))
-                .collect::<Result<Vec<Output>, _>>()?;
 
-            Ok(Output::Multiple(transformed))
+    fn fixed_clock() -> Context {
+        Context {
+            clock: Clock::Fixed(1425599621.0),
+            ..Default::default()
         }
     }
-}
-
-pub fn token_output_to_string(output: Output) -> anyhow::Result<String> {
-    let string = match output {
-        Output::Single(value) => serde_json::to_string_pretty(&value)?,
-        Output::Multiple(values) => values
-            .into_iter()
-            .map(token_output_to_string)
-            .collect::<Result<Vec<_>, _>>()?
-            .join("\n"),
-    };
 
-    Ok(string)
-}
+    #[test]
+    fn apply_now_with_fixed_clock() {
+        let tokens = vec![Token::Function("now".into(), vec![])];
 
-#[allow(dead_code)]
-pub fn print_output(output: Output) {
-    match output {
-        Output::Single(v) => println!("{v}"),
-        Output::Multiple(values) => values.into_iter().for_each(print_output),
+        assert_eq!(
+            apply_tokens_in(&fixed_clock(), &Value::Null, &tokens).unwrap(),
+            vec![json!(1425599621)]
+        );
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use serde_json::json;
 
-    use super::*;
+    #[test]
+    fn apply_date_functions() {
+        let cases = [
+            ("todate", json!(1425599621), json!("2015-03-05T23:53:41Z")),
+            (
+                "todateiso8601",
+                json!(1425599621.9),
+                json!("2015-03-05T23:53:41Z"),
+            ),
+            ("fromdate", json!("2015-03-05T23:53:41Z"), json!(1425599621)),
+            (
+                "fromdateiso8601",
+                json!("1970-01-02T00:00:00Z"),
+                json!(86400),
+            ),
+            (
+                "gmtime",
+                json!(1425599621),
+                json!([2015, 2, 5, 23, 53, 41, 4, 63]),
+            ),
+            ("gmtime", json!(0.5), json!([1970, 0, 1, 0, 0, 0.5, 4, 0])),
+            (
+                "mktime",
+                json!([2015, 2, 5, 23, 53, 41, 4, 63]),
+                json!(1425599621),
+            ),
+            (
+                "mktime",
+                json!([2015, 2, 5, 23, 53, 41.7]),
+                json!(1425599621),
+            ),
+            // Days past the end of the month roll over like in `timegm`.
+            (
+                "mktime",
+                json!([2024, 1, 30, 0, 0, 0, 0, 0]),
+                json!(1709251200),
+            ),
+            (
+                "todate",
+                json!([2024, 1, 30, 0, 0, 0, 0, 0]),
+                json!("2024-02-30T00:00:00Z"),
+            ),
+        ];
+        for (name, input, expected) in cases {
+            let tokens = vec![Token::Function(name.into(), vec![])];
+            assert_eq!(
+                apply_tokens(&input, &tokens).unwrap(),
+                vec![expected],
+                "{name}"
+            );
+        }
+    }
 
     #[test]
-    fn apply_identity_token() {
-        let input = json!({"quotes": ["a", "b", "c"]});
-        let tokens = vec![Token::Identity];
+    fn apply_date_functions_to_invalid_input() {
+        let cases = [
+            ("mktime", json!(1425599621)),
+            ("mktime", json!([1, 2, 3])),
+            ("mktime", json!([1e18, 0, 1, 0, 0, 0])),
+            ("mktime", json!([2000, 0, 1, 0, 0, 1e300])),
+            ("gmtime", json!(1e300)),
+            ("gmtime", json!(-1e18)),
+            ("todate", json!("2015")),
+            ("fromdate", json!(1425599621)),
+            ("fromdate", json!("2015-03-05")),
+        ];
+        for (name, input) in cases {
+            let tokens = vec![Token::Function(name.into(), vec![])];
+            assert!(apply_tokens(&input, &tokens).is_err(), "{name}");
+        }
+
+        let tokens = vec![Token::Function("mktime".into(), vec![])];
+        let error = apply_tokens(&json!([1e18, 0, 1, 0, 0, 0]), &tokens).unwrap_err();
+        assert_eq!(error.to_string(), "mktime requires parsed datetime inputs");
 
+        let tokens = vec![Token::Function("gmtime".into(), vec![])];
+        let error = apply_tokens(&json!(1e300), &tokens).unwrap_err();
         assert_eq!(
-            apply_tokens(&input, &tokens).unwrap(),
-            Output::Single(input)
+            error.to_string(),
+            "error converting number of seconds since epoch to datetime"
         );
     }
 
     #[test]
-    fn apply_index_to_array() {
-        let input = json!([1, 2, 3]);
-        let tokens = vec![Token::Index(2)];
+    fn apply_strftime_and_strptime() {
+        let format = vec![Token::Literal(json!("%d/%m/%Y %H:%M"))];
 
+        let tokens = vec![Token::Function("strftime".into(), vec![format.clone()])];
         assert_eq!(
-            apply_tokens(&input, &tokens).unwrap(),
-            Output::Single(json!(3))
+            apply_tokens(&json!(1425599621), &tokens).unwrap(),
+            vec![json!("05/03/2015 23:53")]
+        );
+
+        let tokens = vec![
+            Token::Function("strptime".into(), vec![format]),
+            Token::Function("mktime".into(), vec![]),
+        ];
+        assert_eq!(
+            apply_tokens(&json!("05/03/2015 23:53"), &tokens).unwrap(),
+            vec![json!(1425599580)]
         );
     }
 
     #[test]
-    fn apply_out_of_bounds_index_to_array() {
-        let input = json!([1, 2, 3]);
-        let tokens = vec![Token::Index(3)];
+    fn apply_dateadd_and_datesub() {
+        let args = vec![
+            vec![Token::Literal(json!("seconds"))],
+            vec![Token::Literal(json!(60))],
+        ];
 
+        let tokens = vec![Token::Function("dateadd".into(), args.clone())];
         assert_eq!(
-            apply_tokens(&input, &tokens).unwrap(),
-            Output::Single(Value::Null)
+            apply_tokens(&json!(1425599621), &tokens).unwrap(),
+            vec![json!(1425599681)]
+        );
+
+        let tokens = vec![Token::Function("datesub".into(), args)];
+        assert_eq!(
+            apply_tokens(&json!(1425599621), &tokens).unwrap(),
+            vec![json!(1425599561)]
         );
     }
 
+    fn call(name: &'static str, args: impl IntoIterator<Item = Value>) -> Token {
+        let args = args
+            .into_iter()
+            .map(|arg| vec![Token::Literal(arg)])
+            .collect();
+        Token::Function(name.into(), args)
+    }
+
+    fn single_output(values: Vec<Value>) -> Value {
+        assert_eq!(values.len(), 1, "expected a single output");
+        values.into_iter().next().unwrap()
+    }
+
     #[test]
-    fn apply_index_to_non_array() {
-        let tokens = vec![Token::Index(2)];
+    fn apply_containment_functions() {
+        let cases = [
+            (json!("foobar"), "contains", json!("bar"), json!(true)),
+            (json!("foobar"), "contains", json!("baz"), json!(false)),
+            (
+                json!(["foobar", "baz"]),
+                "contains",
+                json!(["baz", "bar"]),
+                json!(true),
+            ),
+            (
+                json!(["foobar", "baz"]),
+                "contains",
+                json!(["qux"]),
+                json!(false),
+            ),
+            (
+                json!({"foo": 12, "bar": [1, 2, {"barp": 12, "blip": 13}]}),
+                "contains",
+                json!({"bar": [{"barp": 12}]}),
+                json!(true),
+            ),
+            (
+                json!({"foo": 12, "bar": [1, 2, {"barp": 12, "blip": 13}]}),
+                "contains",
+                json!({"bar": [{"barp": 15}]}),
+                json!(false),
+            ),
+            (json!([1, "a"]), "contains", json!(["a"]), json!(true)),
+            (json!(1), "contains", json!(1), json!(true)),
+            (json!("bar"), "inside", json!("foobar"), json!(true)),
+            (
+                json!(["baz", "bar"]),
+                "inside",
+                json!(["foobar", "foo", "baz"]),
+                json!(true),
+            ),
+            (
+                json!({"foo": 12}),
+                "inside",
+                json!({"foo": 12, "bar": 1}),
+                json!(true),
+            ),
+        ];
+        for (input, name, arg, expected) in cases {
+            let tokens = vec![call(name, [arg.clone()])];
+            assert_eq!(
+                single_output(apply_tokens(&input, &tokens).unwrap()),
+                expected,
+                "{input} | {name}({arg})"
+            );
+        }
+    }
 
-        let input = json!("1");
-        assert!(apply_tokens(&input, &tokens).is_err());
-        let input = json!(1);
-        assert!(apply_tokens(&input, &tokens).is_err());
-        let input = json!({"hello": "world"});
-        assert!(apply_tokens(&input, &tokens).is_err());
+    #[test]
+    fn apply_containment_to_different_types() {
+        let tokens = vec![call("contains", [json!(1)])];
+        assert!(apply_tokens(&json!("1"), &tokens).is_err());
+
+        let tokens = vec![call("inside", [json!([1])])];
+        assert!(apply_tokens(&json!({"a": 1}), &tokens).is_err());
     }
 
     #[test]
-    fn apply_index_to_array_chained() {
-        let input = json!([[1, 2, 3], [4, 5, 6]]);
-        let tokens = vec![Token::Index(1), Token::Index(0)];
+    fn apply_index_functions() {
+        let cases = [
+            (json!("a,b, cd, efg"), "indices", json!(", "), json!([3, 7])),
+            (json!("aaa"), "indices", json!("aa"), json!([0, 1])),
+            (json!("éé,é"), "indices", json!(","), json!([2])),
+            (
+                json!([0, 1, 2, 1, 3, 1, 2]),
+                "indices",
+                json!(1),
+                json!([1, 3, 5]),
+            ),
+            (
+                json!([0, 1, 2, 1, 3, 1, 2]),
+                "indices",
+                json!([1, 2]),
+                json!([1, 5]),
+            ),
+            (json!([1, 2]), "indices", json!([]), Value::Null),
+            (Value::Null, "indices", json!(1), Value::Null),
+            (json!("a,b, cd, efg"), "index", json!(", "), json!(3)),
+            (json!("a,b, cd, efg"), "rindex", json!(", "), json!(7)),
+            (json!("abc"), "index", json!("z"), Value::Null),
+            (json!([0, 1, 2, 1]), "rindex", json!(1), json!(3)),
+            (json!([0, 1, 2, 1]), "index", json!(5), Value::Null),
+        ];
+        for (input, name, arg, expected) in cases {
+            let tokens = vec![call(name, [arg.clone()])];
+            assert_eq!(
+                single_output(apply_tokens(&input, &tokens).unwrap()),
+                expected,
+                "{input} | {name}({arg})"
+            );
+        }
+    }
 
-        assert_eq!(
-            apply_tokens(&input, &tokens).unwrap(),
-            Output::Single(json!(4))
-        );
+    #[test]
+    fn apply_index_to_mismatched_types() {
+        let tokens = vec![call("indices", [json!(1)])];
+        assert!(apply_tokens(&json!("abc"), &tokens).is_err());
     }
 
     #[test]
-    fn apply_index_to_array_chained_with_identity() {
-        let input = json!([[1, 2, 3], [4, 5, 6]]);
-        let tokens = vec![Token::Index(1), Token::Identity, Token::Index(0)];
+    fn apply_any_and_all() {
+        let active = vec![Token::Key("active".into())];
+        let items = vec![Token::Key("items".into()), Token::Iterate];
+
+        let cases = [
+            (json!([true, false]), "any", vec![], json!(true)),
+            (json!([true, false]), "all", vec![], json!(false)),
+            (json!([]), "any", vec![], json!(false)),
+            (json!([]), "all", vec![], json!(true)),
+            (json!({"a": 1, "b": null}), "all", vec![], json!(false)),
+            (
+                json!([{"active": false}, {"active": true}]),
+                "any",
+                vec![active.clone()],
+                json!(true),
+            ),
+            (
+                json!([{"active": false}, {"active": true}]),
+                "all",
+                vec![active.clone()],
+                json!(false),
+            ),
+            (
+                json!({"items": [{"active": 1}, {"active": "yes"}]}),
+                "all",
+                vec![items.clone(), active.clone()],
+                json!(true),
+            ),
+            (
+                json!({"items": [{"active": null}]}),
+                "any",
+                vec![items.clone(), active.clone()],
+                json!(false),
+            ),
+        ];
+        for (input, name, args, expected) in cases {
+            let tokens = vec![Token::Function(name.into(), args)];
+            assert_eq!(
+                apply_tokens(&input, &tokens).unwrap(),
+                vec![expected],
+                "{input} | {name}"
+            );
+        }
+    }
 
-        assert_eq!(
-            apply_tokens(&input, &tokens).unwrap(),
-            Output::Single(json!(4))
+    #[test]
+    fn every_listed_builtin_is_implemented() {
+        let names = BUILTINS.iter().copied().chain(
+            MATH_FUNCTIONS_1
+                .iter()
+                .map(|(name, _)| (*name, 0))
+                .chain(MATH_FUNCTIONS_2.iter().map(|(name, _)| (*name, 2)))
+                .chain(MATH_FUNCTIONS_3.iter().map(|(name, _)| (*name, 3))),
         );
+
+        for (name, arity) in names {
+            let args = vec![vec![Token::Literal(json!(1))]; arity];
+            let tokens = vec![Token::Function(name.into(), args)];
+            if let Err(e) = apply_tokens(&json!(1), &tokens) {
+                assert!(!e.to_string().contains("is not defined"), "{name}/{arity}");
+            }
+        }
     }
 
     #[test]
-    fn apply_key_to_object() {
-        let input = json!({"hello": "world"});
-        let tokens = vec![Token::Key("hello")];
+    fn apply_limit_and_first() {
+        let input = json!([{"a": 1}, {"a": 2}, {"a": 3}, 4]);
+        // `.[] | .a` fails on the last element, which is never reached when
+        // fewer results are asked for.
+        let generator = vec![Token::Iterate, Token::Key("a".into())];
+        let limit = |count: Value| {
+            let args = vec![vec![Token::Literal(count)], generator.clone()];
+            vec![Token::Function("limit".into(), args)]
+        };
 
         assert_eq!(
-            apply_tokens(&input, &tokens).unwrap(),
-            Output::Single(json!("world"))
+            apply_tokens(&input, &limit(json!(2))).unwrap(),
+            vec![json!(1), json!(2)]
         );
+        assert!(apply_tokens(&input, &limit(json!(0))).unwrap().is_empty());
+        assert!(apply_tokens(&input, &limit(json!(4))).is_err());
+        assert!(apply_tokens(&input, &limit(json!(-1))).is_err());
+
+        let tokens = vec![Token::Function("first".into(), vec![generator])];
+        assert_eq!(apply_tokens(&input, &tokens).unwrap(), vec![json!(1)]);
+        let tokens = vec![Token::Function("first".into(), vec![vec![Token::Iterate]])];
+        assert!(apply_tokens(&json!([]), &tokens).unwrap().is_empty());
     }
 
     #[test]
-    fn apply_non_existent_key_to_object() {
-        let input = json!({"hello": "world"});
-        let tokens = vec![Token::Key("missing")];
+    fn apply_any_to_non_iterable() {
+        let tokens = vec![Token::Function("any".into(), vec![])];
+        assert!(apply_tokens(&json!(1), &tokens).is_err());
+    }
 
-        assert_eq!(
-            apply_tokens(&input, &tokens).unwrap(),
-            Output::Single(Value::Null)
-        );
+    #[test]
+    fn apply_flatten() {
+        let input = json!([1, [2], [[3]], [[[4]]]]);
+
+        let cases = [
+            (None, json!([1, 2, 3, 4])),
+            (Some(0), json!([1, [2], [[3]], [[[4]]]])),
+            (Some(1), json!([1, 2, [3], [[4]]])),
+            (Some(2), json!([1, 2, 3, [4]])),
+        ];
+        for (depth, expected) in cases {
+            let args: Vec<_> = depth.into_iter().map(Value::from).collect();
+            let tokens = vec![call("flatten", args)];
+            assert_eq!(
+                single_output(apply_tokens(&input, &tokens).unwrap()),
+                expected,
+                "flatten({depth:?})"
+            );
+        }
     }
 
     #[test]
-    fn apply_key_to_non_object() {
-        let tokens = vec![Token::Key("hello")];
+    fn apply_flatten_with_negative_depth() {
+        let tokens = vec![call("flatten", [json!(-1)])];
+        assert!(apply_tokens(&json!([[1]]), &tokens).is_err());
+    }
 
-        let input = json!("1");
-        assert!(apply_tokens(&input, &tokens).is_err());
-        let input = json!(1);
-        assert!(apply_tokens(&input, &tokens).is_err());
-        let input = json!([1, 2, 3, 4]);
-        assert!(apply_tokens(&input, &tokens).is_err());
+    #[test]
+    fn apply_conversion_functions() {
+        let cases = [
+            (json!("abc"), "tostring", json!("abc")),
+            (
+                json!([1, "a", {"b": null}]),
+                "tostring",
+                json!(r#"[1,"a",{"b":null}]"#),
+            ),
+            (json!(1.5), "tostring", json!("1.5")),
+            (json!("abc"), "tojson", json!(r#""abc""#)),
+            (json!({"a": [1, 2]}), "tojson", json!(r#"{"a":[1,2]}"#)),
+            (json!("\u{7f}"), "tojson", json!(r#""\u007f""#)),
+            (json!(f64::MAX), "tojson", json!("1.7976931348623157e+308")),
+            (json!("42"), "tonumber", json!(42)),
+            (json!(" -1.5e3 "), "tonumber", json!(-1500.0)),
+            (json!(".5"), "tonumber", json!(0.5)),
+            (json!("1."), "tonumber", json!(1)),
+            (json!("nan"), "tonumber", Value::Null),
+            (json!(7), "tonumber", json!(7)),
+            (
+                json!(r#"{"a":[1,"b"]}"#),
+                "fromjson",
+                json!({"a": [1, "b"]}),
+            ),
+            (json!("null"), "fromjson", Value::Null),
+            (json!(65), "ascii", json!("A")),
+            (json!(1), "toarray", json!([1])),
+            (json!([1]), "toarray", json!([1])),
+        ];
+        for (input, name, expected) in cases {
+            let tokens = vec![Token::Function(name.into(), vec![])];
+            assert_eq!(
+                apply_tokens(&input, &tokens).unwrap(),
+                vec![expected],
+                "{input} | {name}"
+            );
+        }
     }
 
     #[test]
-    fn apply_key_to_object_chained() {
-        let input = json!({"hello": {"world": 42}});
-        let tokens = vec![Token::Key("hello"), Token::Key("world")];
+    fn apply_conversion_functions_to_invalid_input() {
+        let cases = [
+            (json!("abc"), "tonumber"),
+            (json!("+1"), "tonumber"),
+            (json!("infinity"), "tonumber"),
+            (json!([1]), "tonumber"),
+            (json!("{"), "fromjson"),
+            (json!(1), "fromjson"),
+            (json!(128), "ascii"),
+            (json!("a"), "ascii"),
+        ];
+        for (input, name) in cases {
+            let tokens = vec![Token::Function(name.into(), vec![])];
+            assert!(apply_tokens(&input, &tokens).is_err(), "{input} | {name}");
+        }
+    }
 
-        assert_eq!(
-            apply_tokens(&input, &tokens).unwrap(),
-            Output::Single(json!(42))
-        );
+    #[test]
+    fn apply_fromjson_error_mentions_input() {
+        let tokens = vec![Token::Function("fromjson".into(), vec![])];
+        let error = apply_tokens(&json!("[1,"), &tokens).unwrap_err();
+
+        assert!(error.to_string().ends_with("(while parsing '[1,')"));
     }
 
     #[test]
-    fn apply_chain_key_and_index_access() {
-        let input = json!({"key": [1,2,3]});
-        let tokens = vec![Token::Key("key"), Token::Index(0)];
+    fn apply_formats() {
+        let input = json!({"a": "b"});
 
+        let tokens = vec![Token::Format("text".into())];
         assert_eq!(
             apply_tokens(&input, &tokens).unwrap(),
-            Output::Single(json!(1))
+            vec![json!(r#"{"a":"b"}"#)]
         );
-    }
 
-    #[test]
-    fn apply_optional_index_to_non_array() {
-        let tokens = vec![Token::OptionalIndex(1)];
+        let tokens = vec![Token::Key("a".into()), Token::Format("text".into())];
+        assert_eq!(apply_tokens(&input, &tokens).unwrap(), vec![json!("b")]);
 
-        let input = json!("1");
+        let tokens = vec![Token::Key("a".into()), Token::Format("json".into())];
         assert_eq!(
             apply_tokens(&input, &tokens).unwrap(),
-            Output::Single(Value::Null)
+            vec![json!(r#""b""#)]
         );
-        let input = json!(1);
+
+        let tokens = vec![Token::Format("nope".into())];
+        assert!(apply_tokens(&input, &tokens).is_err());
+    }
+
+    #[test]
+    fn apply_index_and_key_to_null() {
+        let tokens = vec![Token::Index(0), Token::Key("hello".into())];
+
         assert_eq!(
-            apply_tokens(&input, &tokens).unwrap(),
-            Output::Single(Value::Null)
+            apply_tokens(&Value::Null, &tokens).unwrap(),
+            vec![Value::Null]
         );
-        let input = json!({"hello": "world"});
+    }
+
+    #[test]
+    fn apply_comma() {
+        let tokens = vec![Token::Comma(vec![
+            vec![Token::Key("a".into())],
+            vec![Token::Key("b".into()), Token::Iterate],
+        ])];
+        let input = json!({"a": 1, "b": [2, 3]});
+
         assert_eq!(
             apply_tokens(&input, &tokens).unwrap(),
-            Output::Single(Value::Null)
+            vec![json!(1), json!(2), json!(3)]
         );
     }
 
     #[test]
-    fn apply_optional_key_to_non_object() {
-        let tokens = vec![Token::OptionalKey("hello")];
+    fn apply_wrap_nested_iterators_in_array() {
+        let tokens = vec![Token::Array(vec![Token::Iterate, Token::Iterate])];
+        let input = json!([[1, 2], [3]]);
 
-        let input = json!("1");
         assert_eq!(
             apply_tokens(&input, &tokens).unwrap(),
-            Output::Single(Value::Null)
+            vec![json!([1, 2, 3])]
         );
-        let input = json!(1);
+    }
+
+    #[test]
+    fn compare_values_in_jq_order() {
+        let ordered = [
+            json!(null),
+            json!(false),
+            json!(true),
+            json!(-1),
+            json!(0.5),
+            json!(1),
+            json!(""),
+            json!("a"),
+            json!([]),
+            json!([1]),
+            json!([1, 2]),
+            json!({}),
+            json!({"a": 2}),
+            json!({"a": 1, "b": 0}),
+            json!({"b": 0}),
+        ];
+
+        for pair in ordered.windows(2) {
+            assert_eq!(
+                compare_values(&pair[0], &pair[1]),
+                Ordering::Less,
+                "{} < {}",
+                pair[0],
+                pair[1]
+            );
+        }
+        assert!(values_equal(&json!(1), &json!(1.0)));
+    }
+
+    fn users_and_orders() -> Value {
+        json!({
+            "users": [{"id": 1, "name": "ann"}, {"id": 2, "name": "bob"}],
+            "orders": [{"user": 2, "total": 5}, {"user": 1, "total": 3}, {"user": 3, "total": 1}]
+        })
+    }
+
+    #[test]
+    fn apply_sql_functions() {
+        let user_id = vec![
+            Token::Key("user".into()),
+            Token::Function("tostring".into(), vec![]),
+        ];
+        let user_index = vec![Token::Function(
+            "INDEX".into(),
+            vec![
+                vec![Token::IterateKey("users".into())],
+                vec![Token::Key("id".into())],
+            ],
+        )];
+
+        let cases = [
+            (
+                user_index.clone(),
+                vec![json!({"1": {"id": 1, "name": "ann"}, "2": {"id": 2, "name": "bob"}})],
+            ),
+            (
+                vec![
+                    Token::Key("users".into()),
+                    Token::Function("INDEX".into(), vec![vec![Token::Key("name".into())]]),
+                ],
+                vec![json!({"ann": {"id": 1, "name": "ann"}, "bob": {"id": 2, "name": "bob"}})],
+            ),
+            (
+                vec![
+                    Token::Key("orders".into()),
+                    Token::Function(
+                        "JOIN".into(),
+                        vec![vec![Token::Literal(json!({"1": "ann"}))], user_id.clone()],
+                    ),
+                ],
+                vec![json!([
+                    [{"user": 2, "total": 5}, null],
+                    [{"user": 1, "total": 3}, "ann"],
+                    [{"user": 3, "total": 1}, null]
+                ])],
+            ),
+            (
+                vec![Token::Function(
+                    "JOIN".into(),
+                    vec![
+                        user_index.clone(),
+                        vec![Token::IterateKey("orders".into())],
+                        user_id.clone(),
+                    ],
+                )],
+                vec![
+                    json!([{"user": 2, "total": 5}, {"id": 2, "name": "bob"}]),
+                    json!([{"user": 1, "total": 3}, {"id": 1, "name": "ann"}]),
+                    json!([{"user": 3, "total": 1}, null]),
+                ],
+            ),
+            (
+                vec![Token::Function(
+                    "JOIN".into(),
+                    vec![
+                        user_index,
+                        vec![Token::IterateKey("orders".into())],
+                        user_id,
+                        vec![Token::Index(1), Token::Key("name".into())],
+                    ],
+                )],
+                vec![json!("bob"), json!("ann"), Value::Null],
+            ),
+        ];
+        for (tokens, expected) in cases {
+            let output = apply_tokens(&users_and_orders(), &tokens).unwrap();
+            assert_eq!(output, expected, "{tokens:?}");
+        }
+    }
+
+    #[test]
+    fn apply_join_with_several_keys_per_row() {
+        // Like jq's `[., $idx[idx_expr]]`, every match of a row goes in the
+        // same pair rather than in one pair each.
+        let index = vec![Token::Literal(json!({"a": 1, "b": 2}))];
+        let input = json!([["a", "b"], ["x"], []]);
+
+        let tokens = vec![Token::Function(
+            "JOIN".into(),
+            vec![index.clone(), vec![Token::Iterate], vec![Token::Iterate]],
+        )];
         assert_eq!(
             apply_tokens(&input, &tokens).unwrap(),
-            Output::Single(Value::Null)
+            vec![json!([["a", "b"], 1, 2]), json!([["x"], null]), json!([[]])]
         );
-        let input = json!([1, 2, 3, 4]);
+
+        let tokens = vec![Token::Function(
+            "JOIN".into(),
+            vec![index, vec![Token::Iterate]],
+        )];
         assert_eq!(
             apply_tokens(&input, &tokens).unwrap(),
-            Output::Single(Value::Null)
+            vec![json!([[["a", "b"], 1, 2], [["x"], null], [[]]])]
         );
     }
 
     #[test]
-    fn apply_iterator() {
-        let tokens = vec![Token::Iterate];
-
-        let input = json!([[1, [2, 3]], [4, [5, 6]], [7, [8, 9]]]);
-        let res = apply_tokens(&input, &tokens).unwrap();
-
-        let expected = Output::Multiple(vec![
-            Output::Single(json!([1, [2, 3]])),
-            Output::Single(json!([4, [5, 6]])),
-            Output::Single(json!([7, [8, 9]])),
-        ]);
-
-        assert_eq!(res, expected);
+    fn apply_join_with_non_string_key() {
+        let tokens = vec![Token::Function(
+            "JOIN".into(),
+            vec![
+                vec![Token::Literal(json!({}))],
+                vec![Token::Key("user".into())],
+            ],
+        )];
+
+        assert!(apply_tokens(&json!([{"user": 1}]), &tokens).is_err());
     }
 
     #[test]
-    fn apply_iterator_chained() {
-        let tokens = vec![Token::Iterate, Token::Iterate];
-
-        let input = json!([[1, [2, 3]], [4, [5, 6]], [7, [8, 9]]]);
-        let res = apply_tokens(&input, &tokens).unwrap();
-
-        let expected = Output::Multiple(vec![
-            Output::Multiple(vec![
-                Output::Single(json!(1)),
-                Output::Single(json!([2, 3])),
-            ]),
-            Output::Multiple(vec![
-                Output::Single(json!(4)),
-                Output::Single(json!([5, 6])),
-            ]),
-            Output::Multiple(vec![
-                Output::Single(json!(7)),
-                Output::Single(json!([8, 9])),
-            ]),
-        ]);
-        assert_eq!(res, expected);
+    fn apply_in() {
+        let one_or_two = vec![Token::Comma(vec![
+            vec![Token::Literal(json!(1))],
+            vec![Token::Literal(json!(2.0))],
+        ])];
+
+        let cases = [
+            (json!(1), vec![one_or_two.clone()], true),
+            (json!(2), vec![one_or_two.clone()], true),
+            (json!(3), vec![one_or_two.clone()], false),
+            (
+                json!([3, 2]),
+                vec![vec![Token::Iterate], one_or_two.clone()],
+                true,
+            ),
+            (
+                json!([3, 4]),
+                vec![vec![Token::Iterate], one_or_two.clone()],
+                false,
+            ),
+        ];
+        for (input, args, expected) in cases {
+            let tokens = vec![Token::Function("IN".into(), args)];
+            assert_eq!(
+                apply_tokens(&input, &tokens).unwrap(),
+                vec![json!(expected)],
+                "{input}"
+            );
+        }
     }
 
     #[test]
-    fn apply_iterator_chained_twice() {
-        let tokens = vec![Token::Iterate, Token::Iterate, Token::Iterate];
+    fn apply_group_by_and_unique_by() {
+        let input = json!([{"k": 2, "v": "a"}, {"k": 1, "v": "b"}, {"k": 2.0, "v": "c"}]);
+        let key = vec![Token::Key("k".into())];
 
-        let input = json!([[1, [2, 3]], [4, [5, 6]], [7, [8, 9]]]);
-        let res = apply_tokens(&input, &tokens);
+        let tokens = vec![Token::Function("group_by".into(), vec![key.clone()])];
+        assert_eq!(
+            apply_tokens(&input, &tokens).unwrap(),
+            vec![json!([
+                [{"k": 1, "v": "b"}],
+                [{"k": 2, "v": "a"}, {"k": 2.0, "v": "c"}]
+            ])]
+        );
 
-        // NOTE: jq doesn't allow iterating over primitive values
-        assert!(res.is_err());
+        let tokens = vec![Token::Function("UNIQUE_BY".into(), vec![key])];
+        assert_eq!(
+            apply_tokens(&input, &tokens).unwrap(),
+            vec![json!([{"k": 1, "v": "b"}, {"k": 2, "v": "a"}])]
+        );
     }
 
     #[test]
-    fn apply_iterator_on_object() {
-        let tokens = vec![Token::Iterate];
-
-        let input = json!({"hello": "a", "world": "b"});
-        let res = apply_tokens(&input, &tokens).unwrap();
-
-        let expected =
-            Output::Multiple(vec![Output::Single(json!("a")), Output::Single(json!("b"))]);
+    fn apply_arithmetic_operators() {
+        let cases = [
+            (json!(1), Operator::Add, json!(2.5), json!(3.5)),
+            (json!(null), Operator::Add, json!("a"), json!("a")),
+            (json!("ab"), Operator::Add, json!("cd"), json!("abcd")),
+            (json!([1]), Operator::Add, json!([2]), json!([1, 2])),
+            (
+                json!({"a": 1}),
+                Operator::Add,
+                json!({"a": 2, "b": 3}),
+                json!({"a": 2, "b": 3}),
+            ),
+            (json!(5), Operator::Subtract, json!(7), json!(-2)),
+            (
+                json!([1, 2, 1, 3]),
+                Operator::Subtract,
+                json!([1]),
+                json!([2, 3]),
+            ),
+            (json!(4), Operator::Multiply, json!(0.5), json!(2)),
+            (json!("ab"), Operator::Multiply, json!(3), json!("ababab")),
+            (json!("ab"), Operator::Multiply, json!(0.5), json!("ab")),
+            (json!("ab"), Operator::Multiply, json!(0), json!(null)),
+            (
+                json!({"a": {"b": 1}}),
+                Operator::Multiply,
+                json!({"a": {"c": 2}}),
+                json!({"a": {"b": 1, "c": 2}}),
+            ),
+            (json!(7), Operator::Divide, json!(2), json!(3.5)),
+            (
+                json!("a,b,c"),
+                Operator::Divide,
+                json!(","),
+                json!(["a", "b", "c"]),
+            ),
+            (json!(-7), Operator::Modulo, json!(2), json!(-1)),
+            (json!(7.9), Operator::Modulo, json!(-2), json!(1)),
+        ];
+
+        for (a, operator, b, expected) in cases {
+            assert_eq!(
+                apply_operator(operator, a.clone(), b.clone()).unwrap(),
+                expected,
+                "{a} {operator:?} {b}"
+            );
+        }
 
-        assert_eq!(res, expected);
+        assert!(apply_operator(Operator::Add, json!(1), json!("a")).is_err());
+        assert!(apply_operator(Operator::Subtract, json!("a"), json!("a")).is_err());
+        assert!(apply_operator(Operator::Divide, json!(1), json!(0)).is_err());
+        assert!(apply_operator(Operator::Modulo, json!(1), json!(0.5)).is_err());
     }
 
     #[test]
-    fn apply_iterator_on_array_in_object() {
-        let tokens = vec![Token::Iterate, Token::Iterate];
+    fn apply_huge_string_repeat() {
+        for times in [json!(1e20), json!(u64::MAX), json!(i32::MAX)] {
+            let error = apply_operator(Operator::Multiply, json!("ab"), times).unwrap_err();
+            assert_eq!(error.to_string(), "Repeat string result too long");
+        }
 
-        let input = json!({"hello": ["a", "b"], "world": ["c"]});
-        let res = apply_tokens(&input, &tokens).unwrap();
+        assert_eq!(
+            apply_operator(Operator::Multiply, json!(""), json!(1e20)).unwrap(),
+            json!("")
+        );
+    }
 
-        let expected = Output::Multiple(vec![
-            Output::Multiple(vec![Output::Single(json!("a")), Output::Single(json!("b"))]),
-            Output::Multiple(vec![Output::Single(json!("c"))]),
-        ]);
+    #[test]
+    fn apply_operation_to_every_combination() {
+        let tokens = vec![Token::Operation(
+            Operator::Add,
+            vec![Token::Comma(vec![
+                vec![Token::Literal(json!(1))],
+                vec![Token::Literal(json!(2))],
+            ])],
+            vec![Token::Comma(vec![
+                vec![Token::Literal(json!(10))],
+                vec![Token::Literal(json!(20))],
+            ])],
+        )];
+
+        let output = apply_tokens(&Value::Null, &tokens).unwrap();
+        assert_eq!(output, vec![json!(11), json!(12), json!(21), json!(22)]);
+    }
 
-        assert_eq!(res, expected);
+    #[test]
+    fn apply_variable_bindings() {
+        let input = json!({"items": [1, 2, 3], "offset": 10});
+
+        let tokens = vec![Token::Bind(
+            vec![Token::Key("offset".into())],
+            "offset".into(),
+            vec![Token::Reduce(
+                vec![Token::IterateKey("items".into())],
+                "x".into(),
+                vec![Token::Variable("offset".into())],
+                vec![Token::Operation(
+                    Operator::Add,
+                    vec![Token::Identity],
+                    vec![Token::Variable("x".into())],
+                )],
+            )],
+        )];
+        let output = apply_tokens(&input, &tokens).unwrap();
+        assert_eq!(output, vec![json!(16)]);
+
+        let tokens = vec![Token::Variable("missing".into())];
+        assert!(apply_tokens(&input, &tokens).is_err());
     }
 
     #[test]
-    fn apply_wrap_in_array() {
-        let tokens = vec![Token::Array(vec![Token::Key("hello")])];
-        let input = json!({"hello": "a"});
+    fn variables_share_the_values_bound_to_them() {
+        let context = Context::default();
+        let scope = Scope::new(&context).bind("x", Shared::from(json!([1, 2, 3])));
+
+        let (Some(Shared::Owned(a)), Some(Shared::Owned(b))) =
+            (scope.variable("x"), scope.variable("x"))
+        else {
+            panic!("$x should be owned by the scope");
+        };
+        assert!(Rc::ptr_eq(&a, &b));
+    }
 
-        let res = apply_tokens(&input, &tokens);
-        let expected = Output::Single(json!(["a"]));
+    #[test]
+    fn operations_on_the_input_update_it_in_place() {
+        let context = Context::default();
+        let mut array = Vec::with_capacity(4);
+        array.push(json!(1));
+        let buffer = array.as_ptr();
+
+        // Like the update of `reduce .[] as $x ([]; . + [$x])`.
+        let tokens = vec![Token::Operation(
+            Operator::Add,
+            vec![Token::Identity],
+            vec![Token::Array(vec![Token::Literal(json!(2))])],
+        )];
+        let input = Shared::from(Value::Array(array));
+        let mut results = apply_tokens_with(&Scope::new(&context), input, &tokens);
+
+        let output = results.next().unwrap().unwrap();
+        assert_eq!(*output, json!([1, 2]));
+        assert!(matches!(&*output, Value::Array(array) if array.as_ptr() == buffer));
+        assert!(results.next().is_none());
+    }
 
-        assert_eq!(res.unwrap(), expected)
+    fn define(
+        name: &'static str,
+        params: Vec<Parameter>,
+        body: Vec<Token>,
+        rest: Vec<Token>,
+    ) -> Token {
+        Token::Define(
+            Arc::new(Definition {
+                name: name.into(),
+                params,
+                body,
+            }),
+            rest,
+        )
     }
 
     #[test]
-    fn apply_wrap_in_array_multiple_values() {
-        let tokens = vec![Token::Array(vec![Token::IterateKey("hello")])];
-        let input = json!({"hello": ["a","b", "c"]});
-
-        let res = apply_tokens(&input, &tokens);
-        let expected = Output::Single(json!(["a", "b", "c"]));
+    fn apply_definitions() {
+        let add = |lhs, rhs| Token::Operation(Operator::Add, lhs, rhs);
+
+        // def twice(f): f | f; def add($n): . + $n; 1 | twice(add(10, 20))
+        let tokens = vec![define(
+            "twice",
+            vec![Parameter::Filter("f".into())],
+            vec![call("f", []), call("f", [])],
+            vec![define(
+                "add",
+                vec![Parameter::Value("n".into())],
+                vec![add(
+                    vec![Token::Identity],
+                    vec![Token::Variable("n".into())],
+                )],
+                vec![Token::Function(
+                    "twice".into(),
+                    vec![vec![Token::Function(
+                        "add".into(),
+                        vec![vec![Token::Comma(vec![
+                            vec![Token::Literal(json!(10))],
+                            vec![Token::Literal(json!(20))],
+                        ])]],
+                    )]],
+                )],
+            )],
+        )];
+        let output = apply_tokens(&json!(1), &tokens).unwrap();
+        assert_eq!(output, vec![json!(21), json!(31), json!(31), json!(41)]);
+
+        // Value parameters are also filters returning the value.
+        let tokens = vec![define(
+            "f",
+            vec![Parameter::Value("a".into())],
+            vec![call("a", [])],
+            vec![call("f", [json!(7)])],
+        )];
+        let output = apply_tokens(&Value::Null, &tokens).unwrap();
+        assert_eq!(output, vec![json!(7)]);
+    }
 
-        assert_eq!(res.unwrap(), expected)
+    #[test]
+    fn apply_definitions_with_lexical_scope() {
+        // def x: 1; def f: x; def x: 2; f, x
+        let tokens = vec![define(
+            "x",
+            vec![],
+            vec![Token::Literal(json!(1))],
+            vec![define(
+                "f",
+                vec![],
+                vec![call("x", [])],
+                vec![define(
+                    "x",
+                    vec![],
+                    vec![Token::Literal(json!(2))],
+                    vec![Token::Comma(vec![vec![call("f", [])], vec![call("x", [])]])],
+                )],
+            )],
+        )];
+        let output = apply_tokens(&Value::Null, &tokens).unwrap();
+        assert_eq!(output, vec![json!(1), json!(2)]);
+
+        // Definitions shadow builtins, but only with the same arity.
+        let tokens = vec![define(
+            "floor",
+            vec![Parameter::Filter("f".into())],
+            vec![call("f", [])],
+            vec![call("floor", []), call("floor", [json!(3)])],
+        )];
+        let output = apply_tokens(&json!(1.5), &tokens).unwrap();
+        assert_eq!(output, vec![json!(3)]);
+
+        let tokens = vec![define(
+            "floor",
+            vec![Parameter::Filter("f".into())],
+            vec![call("f", [])],
+            vec![call("floor", [])],
+        )];
+        let output = apply_tokens(&json!(1.5), &tokens).unwrap();
+        assert_eq!(output, vec![json!(1)]);
     }
 
     #[test]
-    fn apply_wrap_array_in_array() {
-        let tokens = vec![Token::Array(vec![Token::Key("hello")])];
-        let input = json!({"hello": ["a","b", "c"]});
+    fn apply_global_variables() {
+        let tokens = vec![Token::Variable("ARGS".into())];
+        let output = apply_tokens(&Value::Null, &tokens).unwrap();
+        assert_eq!(output, vec![json!({"positional": [], "named": {}})]);
+
+        let mut named = serde_json::Map::new();
+        named.insert("name".to_owned(), json!("value"));
+        let context = Context::default()
+            .with_arguments(named, vec![json!(1)])
+            .with_variable("extra", json!(true));
+
+        let tokens = vec![Token::Array(vec![Token::Comma(vec![
+            vec![Token::Variable("name".into())],
+            vec![Token::Variable("extra".into())],
+            vec![Token::Variable("ARGS".into())],
+        ])])];
+        let output = apply_tokens_in(&context, &Value::Null, &tokens).unwrap();
+        assert_eq!(
+            output,
+            vec![json!([
+                "value",
+                true,
+                {"positional": [1], "named": {"name": "value"}}
+            ])]
+        );
+    }
 
-        let res = apply_tokens(&input, &tokens);
-        let expected = Output::Single(json!([["a", "b", "c"]]));
+    #[test]
+    fn apply_custom_environment() {
+        let mut variables = serde_json::Map::new();
+        variables.insert("PAGER".to_owned(), json!("less"));
+        let context = Context {
+            environment: Environment::Custom(variables),
+            ..Default::default()
+        };
+
+        let tokens = vec![Token::Variable("ENV".into()), Token::Key("PAGER".into())];
+        let output = apply_tokens_in(&context, &Value::Null, &tokens).unwrap();
+        assert_eq!(output, vec![json!("less")]);
+
+        let tokens = vec![
+            Token::Function("env".into(), vec![]),
+            Token::Key("HOME".into()),
+        ];
+        let output = apply_tokens_in(&context, &Value::Null, &tokens).unwrap();
+        assert_eq!(output, vec![Value::Null]);
+    }
 
-        assert_eq!(res.unwrap(), expected)
+    #[test]
+    fn apply_process_environment() {
+        let tokens = vec![Token::Function("env".into(), vec![])];
+        let [Value::Object(variables)] = &apply_tokens(&Value::Null, &tokens).unwrap()[..] else {
+            panic!("env should be an object");
+        };
+
+        let path = std::env::var("PATH").ok().map(Value::String);
+        assert_eq!(variables.get("PATH").cloned(), path);
     }
 }